@@ -0,0 +1,1525 @@
+//! Application state.
+//!
+//! What the front end knows about conversations, kept apart from any
+//! widgets. The state only changes through `AppState::apply_reply` and
+//! `AppState::apply_action`, which return the effects the front end has to
+//! carry out: requests to Keybase and views to update.
+//!
+//! Every conversation keeps its messages, including the ones that arrive
+//! while another conversation is open. Opening a conversation shows them
+//! right away and asks Keybase for the latest history in the background.
+//!
+//! Messages sent from the drafts go through an outbox: they are shown below
+//! the conversation as soon as they are sent, marked while they are on the
+//! way or if sending failed, until Keybase sends them back as messages.
+
+use super::commands::{self, Command};
+use super::completion::{self, Cycle, Words};
+use super::compose;
+use super::keybase::{Channel, ChatMsg, KeybaseReply};
+use std::collections::HashMap;
+
+/// Messages kept per conversation.
+pub const HISTORY_LENGTH: usize = 1000;
+
+/// Sent messages kept per conversation for recalling them.
+pub const SENT_LENGTH: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionStatus {
+    /// Nothing heard from Keybase yet.
+    Connecting,
+    Connected,
+    /// The last request failed.
+    Error(String),
+    /// Lost the connection to Keybase, waiting for it to come back.
+    Reconnecting(String),
+}
+
+impl Default for ConnectionStatus {
+    fn default() -> Self {
+        return ConnectionStatus::Connecting;
+    }
+}
+
+/// How far an outgoing message got.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Delivery {
+    /// Waiting for Keybase to answer at all.
+    Queued,
+    /// Handed to the API, no answer yet.
+    Pending,
+    /// Accepted by the API as message `id`, not back from the listener yet.
+    Sent {
+        id: u64,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// What an outgoing message does.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutgoingKind {
+    /// A message to the conversation.
+    Message,
+    /// A message to the conversation given by name, e.g. a username.
+    To {
+        name: String,
+    },
+    Exploding {
+        lifetime: String,
+    },
+    /// Replaces the text of message `message_id`.
+    Edit {
+        message_id: u64,
+    },
+    /// The text is a reaction to message `message_id`.
+    Reaction {
+        message_id: u64,
+    },
+}
+
+impl OutgoingKind {
+    /// Returns true if the message comes back to the conversation it was
+    /// sent from once it is delivered.
+    fn comes_back(&self) -> bool {
+        if let OutgoingKind::Exploding { .. } = self {
+            return true;
+        }
+        return *self == OutgoingKind::Message;
+    }
+}
+
+/// A message in the outbox.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Outgoing {
+    /// Local id that answers from the API are matched with, see
+    /// `AppState::apply_delivery`.
+    pub id: u64,
+    /// The conversation it was sent from.
+    pub conversation_id: String,
+    pub text: String,
+    pub kind: OutgoingKind,
+    /// Time the message was sent, in UTC.
+    pub utc_timestamp: chrono::NaiveDateTime,
+    pub delivery: Delivery,
+}
+
+impl Outgoing {
+    pub fn failed(&self) -> bool {
+        return matches!(self.delivery, Delivery::Failed { .. });
+    }
+}
+
+/// Returns true if `a` was sent before `b`, both in the same conversation.
+/// Message ids grow within a conversation, times only tell seconds apart.
+fn is_before(a: &ChatMsg, b: &ChatMsg) -> bool {
+    if a.id != 0 && b.id != 0 {
+        return a.id < b.id;
+    }
+    return a.utc_timestamp < b.utc_timestamp;
+}
+
+/// Something the user did.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UserAction {
+    OpenConversation {
+        conversation_id: String,
+    },
+    /// The message entry of the open conversation changed.
+    EditDraft {
+        text: String,
+    },
+    /// Sends the draft of the open conversation, or runs it if it is a
+    /// command.
+    SendDraft,
+    /// Completes what is being typed in the draft of the open conversation.
+    Complete,
+    /// Puts the sent message before the recalled one in the draft, like
+    /// Up in a shell.
+    RecallOlder,
+    /// Puts the sent message after the recalled one in the draft, or
+    /// empties it after the last one.
+    RecallNewer,
+    /// Recalls the next older sent message containing the draft.
+    SearchSent,
+    /// Sends `text` without touching drafts, e.g. a reply from a
+    /// notification.
+    Send {
+        conversation_id: String,
+        text: String,
+    },
+}
+
+/// What the front end has to do after `apply_reply` or `apply_action`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Effect {
+    /// Ask Keybase for the history of a conversation.
+    Fetch {
+        conversation_id: String,
+    },
+    /// Send a message to a conversation and tell `apply_delivery` how it
+    /// went.
+    Send {
+        outgoing_id: u64,
+        conversation_id: String,
+        text: String,
+        kind: OutgoingKind,
+    },
+    Join {
+        channel: String,
+    },
+    Leave {
+        channel: String,
+    },
+    /// Turn off notifications for a conversation.
+    Mute {
+        conversation_id: String,
+    },
+    /// Look for messages containing `query` and list them.
+    Search {
+        conversation_id: String,
+        query: String,
+    },
+    /// Set the headline of a team channel, `team#channel`.
+    SetTopic {
+        channel: String,
+        text: String,
+    },
+    /// Tell the user something in the chat view, e.g. help or an error.
+    ShowNotice {
+        text: String,
+    },
+    /// Show all messages of the open conversation.
+    ShowConversation,
+    /// A message was added to the open conversation.
+    ShowMessage {
+        msg: ChatMsg,
+    },
+    /// The draft of the open conversation changed, e.g. it was sent.
+    ShowDraft,
+    /// The conversation list changed.
+    ShowChannels,
+}
+
+#[derive(Default)]
+pub struct AppState {
+    /// Our username.
+    me: String,
+    channels: Vec<Channel>,
+    current_conversation_id: String,
+    /// Messages by conversation id, oldest first.
+    messages: HashMap<String, Vec<ChatMsg>>,
+    /// Messages received since each conversation was last opened.
+    unread: HashMap<String, u32>,
+    drafts: HashMap<String, String>,
+    status: ConnectionStatus,
+    /// Pastes of this many lines are put in a code fence, 0 turns it off.
+    fence_paste_lines: usize,
+    /// Matches being stepped through with Tab.
+    completion: Option<Cycle>,
+    /// Messages sent from the drafts by conversation id, oldest first.
+    sent: HashMap<String, Vec<String>>,
+    sent_length: usize,
+    recall: Option<Recall>,
+    /// Messages sent but not back from Keybase yet, oldest first.
+    outbox: Vec<Outgoing>,
+    next_outgoing_id: u64,
+}
+
+/// The sent message shown in the draft.
+#[derive(Debug)]
+struct Recall {
+    /// Index into the sent messages of the open conversation.
+    index: usize,
+    /// What `SearchSent` looks for, `None` when recalled with Up.
+    query: Option<String>,
+}
+
+impl AppState {
+    pub fn new() -> Self {
+        return AppState {
+            sent_length: SENT_LENGTH,
+            ..AppState::default()
+        };
+    }
+
+    /// Sets our username, needed to find our own messages.
+    pub fn set_me(&mut self, me: &str) {
+        self.me = me.to_string();
+    }
+
+    /// All conversations, sorted by name.
+    pub fn channels(&self) -> &[Channel] {
+        return &self.channels;
+    }
+
+    /// Id of the open conversation, or "" if none is open.
+    pub fn current_conversation_id(&self) -> &str {
+        return &self.current_conversation_id;
+    }
+
+    /// Returns the name of a conversation, or "" if it is not known.
+    pub fn conversation_name(&self, conversation_id: &str) -> &str {
+        match self.channels.iter().find(|c| c.id == conversation_id) {
+            Some(chan) => &chan.name,
+            None => "",
+        }
+    }
+
+    /// Known messages of a conversation, oldest first.
+    pub fn messages(&self, conversation_id: &str) -> &[ChatMsg] {
+        match self.messages.get(conversation_id) {
+            Some(msgs) => msgs,
+            None => &[],
+        }
+    }
+
+    /// Unread counts by conversation id. Read conversations are left out.
+    pub fn unread(&self) -> &HashMap<String, u32> {
+        return &self.unread;
+    }
+
+    pub fn unread_count(&self, conversation_id: &str) -> u32 {
+        return self.unread.get(conversation_id).cloned().unwrap_or(0);
+    }
+
+    /// The unsent text of a conversation.
+    pub fn draft(&self, conversation_id: &str) -> &str {
+        match self.drafts.get(conversation_id) {
+            Some(draft) => draft,
+            None => "",
+        }
+    }
+
+    /// Unsent drafts by conversation id.
+    pub fn drafts(&self) -> &HashMap<String, String> {
+        return &self.drafts;
+    }
+
+    /// Brings back drafts, e.g. from disk after a restart.
+    pub fn restore_drafts(&mut self, drafts: HashMap<String, String>) {
+        self.drafts = drafts;
+        self.drafts.retain(|_, text| !text.is_empty());
+    }
+
+    /// Sets how long a paste into a draft has to be to get fenced as code.
+    /// 0 turns fencing off.
+    pub fn set_fence_paste_lines(&mut self, lines: usize) {
+        self.fence_paste_lines = lines;
+    }
+
+    /// What can be completed in the open conversation: the people in it,
+    /// the other channels of its team and the emoji used in it.
+    pub fn completion_words(&self) -> Words {
+        let mut words = Words::new();
+        let name = self.conversation_name(&self.current_conversation_id);
+        match name.find('#') {
+            Some(pos) => {
+                let team = &name[..pos + 1];
+                for chan in &self.channels {
+                    if chan.name.starts_with(team) {
+                        words.channels.insert(chan.name[team.len()..].to_string());
+                    }
+                }
+            }
+            // Private conversations are named after the people in them.
+            None => words
+                .users
+                .extend(name.split(',').filter(|u| !u.is_empty()).map(String::from)),
+        }
+        for msg in self.messages(&self.current_conversation_id) {
+            words.users.insert(msg.sender.clone());
+            words.users.extend(msg.at_mentions.iter().cloned());
+            words.add_emoji_from(&msg.text);
+        }
+        words.users.remove(&self.me);
+        return words;
+    }
+
+    /// Sent messages by conversation id, oldest first.
+    pub fn sent(&self) -> &HashMap<String, Vec<String>> {
+        return &self.sent;
+    }
+
+    /// Brings back sent messages, e.g. from disk after a restart.
+    pub fn restore_sent(&mut self, sent: HashMap<String, Vec<String>>) {
+        self.sent = sent;
+        self.set_sent_length(self.sent_length);
+    }
+
+    /// Sets how many sent messages are kept per conversation. 0 keeps none.
+    pub fn set_sent_length(&mut self, length: usize) {
+        self.sent_length = length;
+        self.recall = None;
+        for texts in self.sent.values_mut() {
+            if texts.len() > length {
+                let extra = texts.len() - length;
+                texts.drain(..extra);
+            }
+        }
+        self.sent.retain(|_, texts| !texts.is_empty());
+    }
+
+    /// True if Up and Down in the message entry recall sent messages:
+    /// the draft is empty or a recalled message.
+    pub fn recalling(&self) -> bool {
+        return self.recall.is_some() || self.draft(&self.current_conversation_id).is_empty();
+    }
+
+    fn push_sent(&mut self, text: &str) {
+        if self.sent_length == 0 {
+            return;
+        }
+        let texts = self
+            .sent
+            .entry(self.current_conversation_id.clone())
+            .or_default();
+        // Sending the same thing again doesn't need another entry.
+        if texts.last().map(String::as_str) != Some(text) {
+            texts.push(text.to_string());
+        }
+        if texts.len() > self.sent_length {
+            texts.remove(0);
+        }
+    }
+
+    /// Recalls sent message `index` of the open conversation.
+    fn recall_sent(&mut self, index: usize, query: Option<String>) -> Vec<Effect> {
+        let conversation_id = self.current_conversation_id.clone();
+        let text = self.sent[&conversation_id][index].clone();
+        self.drafts.insert(conversation_id, text);
+        self.recall = Some(Recall {
+            index: index,
+            query: query,
+        });
+        return vec![Effect::ShowDraft];
+    }
+
+    /// Messages of a conversation in the outbox, oldest first.
+    pub fn outbox(&self, conversation_id: &str) -> Vec<&Outgoing> {
+        return self
+            .outbox
+            .iter()
+            .filter(|o| o.conversation_id == conversation_id)
+            .collect();
+    }
+
+    /// Returns true while messages can't be sent.
+    fn is_offline(&self) -> bool {
+        if let ConnectionStatus::Reconnecting(_) = self.status {
+            return true;
+        }
+        return self.status == ConnectionStatus::Connecting;
+    }
+
+    /// Puts `text` in the outbox and sends it, or holds it back until
+    /// Keybase answers.
+    fn send(&mut self, conversation_id: &str, text: &str, kind: OutgoingKind) -> Vec<Effect> {
+        let outgoing = Outgoing {
+            id: self.next_outgoing_id,
+            conversation_id: conversation_id.to_string(),
+            text: text.to_string(),
+            kind: kind,
+            utc_timestamp: chrono::Utc::now().naive_utc(),
+            delivery: if self.is_offline() {
+                Delivery::Queued
+            } else {
+                Delivery::Pending
+            },
+        };
+        self.next_outgoing_id += 1;
+
+        let mut effects = Vec::new();
+        if outgoing.delivery == Delivery::Pending {
+            effects.push(Effect::Send {
+                outgoing_id: outgoing.id,
+                conversation_id: outgoing.conversation_id.clone(),
+                text: outgoing.text.clone(),
+                kind: outgoing.kind.clone(),
+            });
+        }
+        if conversation_id == self.current_conversation_id {
+            effects.push(Effect::ShowConversation);
+        }
+        self.outbox.push(outgoing);
+        return effects;
+    }
+
+    /// Sends the outbox messages that `resend` picks again.
+    fn resend<F: Fn(&Outgoing) -> bool>(&mut self, resend: F) -> Vec<Effect> {
+        let mut effects = Vec::new();
+        for outgoing in self.outbox.iter_mut().filter(|o| resend(o)) {
+            outgoing.delivery = Delivery::Pending;
+            effects.push(Effect::Send {
+                outgoing_id: outgoing.id,
+                conversation_id: outgoing.conversation_id.clone(),
+                text: outgoing.text.clone(),
+                kind: outgoing.kind.clone(),
+            });
+        }
+        return effects;
+    }
+
+    /// Drops the sent outbox messages that are among the messages of
+    /// `conversation_id`. Returns true if there were any.
+    fn remove_delivered(&mut self, conversation_id: &str) -> bool {
+        let msgs = match self.messages.get(conversation_id) {
+            Some(msgs) => msgs,
+            None => return false,
+        };
+        let count = self.outbox.len();
+        self.outbox.retain(|o| match o.delivery {
+            Delivery::Sent { id } if o.conversation_id == conversation_id => {
+                !msgs.iter().any(|m| m.id == id)
+            }
+            _ => true,
+        });
+        return self.outbox.len() != count;
+    }
+
+    /// Takes in the answer to sending the outbox message `outgoing_id`.
+    /// Messages that were cut off by a lost connection go again once it is
+    /// back.
+    pub fn apply_delivery(&mut self, outgoing_id: u64, reply: KeybaseReply) -> Vec<Effect> {
+        if let KeybaseReply::Disconnected { ref error } = reply {
+            self.status = ConnectionStatus::Reconnecting(error.clone());
+        }
+        let index = match self.outbox.iter().position(|o| o.id == outgoing_id) {
+            Some(index) => index,
+            None => return vec![],
+        };
+        let outgoing = &mut self.outbox[index];
+        outgoing.delivery = match reply {
+            KeybaseReply::SendReply { id } => Delivery::Sent { id: id },
+            KeybaseReply::ErrorReply { error } => Delivery::Failed { error: error },
+            KeybaseReply::Disconnected { .. } => Delivery::Queued,
+            _ => Delivery::Failed {
+                error: "Unexpected reply from Keybase".to_string(),
+            },
+        };
+        let conversation_id = outgoing.conversation_id.clone();
+        if let Delivery::Sent { .. } = outgoing.delivery {
+            if !outgoing.kind.comes_back() {
+                let outgoing = self.outbox.remove(index);
+                // Edits don't come back as messages, so they are applied to
+                // the cached message here.
+                if let OutgoingKind::Edit { message_id } = outgoing.kind {
+                    let msgs = self.messages.entry(conversation_id.clone()).or_default();
+                    if let Some(msg) = msgs.iter_mut().find(|m| m.id == message_id) {
+                        msg.text = outgoing.text;
+                    }
+                }
+            }
+        }
+        // The message may have come back before the answer.
+        self.remove_delivered(&conversation_id);
+        if conversation_id == self.current_conversation_id {
+            return vec![Effect::ShowConversation];
+        }
+        return vec![];
+    }
+
+    pub fn status(&self) -> &ConnectionStatus {
+        return &self.status;
+    }
+
+    fn push_message(&mut self, msg: ChatMsg) {
+        let msgs = self
+            .messages
+            .entry(msg.conversation_id.clone())
+            .or_default();
+        msgs.push(msg);
+        if msgs.len() > HISTORY_LENGTH {
+            let extra = msgs.len() - HISTORY_LENGTH;
+            msgs.drain(..extra);
+        }
+    }
+
+    /// Replaces the known messages of a conversation with `history`, newest
+    /// first. Known messages older or newer than all of `history` are kept,
+    /// e.g. ones that arrived while it was fetched. Returns true if the
+    /// messages changed.
+    fn merge_history(
+        &mut self,
+        conversation_id: &str,
+        old: Vec<ChatMsg>,
+        history: Vec<ChatMsg>,
+    ) -> bool {
+        let (newest, oldest) = match (history.first(), history.last()) {
+            (Some(newest), Some(oldest)) => (newest.clone(), oldest.clone()),
+            // Nothing to replace anything with.
+            _ => {
+                self.messages.insert(conversation_id.to_string(), old);
+                return false;
+            }
+        };
+        let mut merged: Vec<ChatMsg> = old
+            .iter()
+            .filter(|m| is_before(m, &oldest))
+            .cloned()
+            .collect();
+        merged.extend(history.into_iter().rev());
+        merged.extend(old.iter().filter(|m| is_before(&newest, m)).cloned());
+        if merged.len() > HISTORY_LENGTH {
+            let extra = merged.len() - HISTORY_LENGTH;
+            merged.drain(..extra);
+        }
+        let changed = merged != old;
+        self.messages.insert(conversation_id.to_string(), merged);
+        return changed;
+    }
+
+    /// Takes in the history of `conversation_id`, newest first.
+    fn take_history(&mut self, conversation_id: &str, msgs: Vec<ChatMsg>) -> Vec<Effect> {
+        let old = self.messages.remove(conversation_id).unwrap_or_default();
+        let mut changed = self.merge_history(conversation_id, old, msgs);
+        changed |= self.remove_delivered(conversation_id);
+        if changed && conversation_id == self.current_conversation_id {
+            return vec![Effect::ShowConversation];
+        }
+        return vec![];
+    }
+
+    /// Takes in the reply to `Effect::Fetch` for `conversation_id`.
+    pub fn apply_history(&mut self, conversation_id: &str, reply: KeybaseReply) -> Vec<Effect> {
+        let mut effects = self.update_status(&reply);
+        match reply {
+            KeybaseReply::ChatMsgListReply { msgs } => {
+                effects.extend(self.take_history(conversation_id, msgs));
+            }
+            reply => effects.extend(self.apply_reply_content(reply)),
+        }
+        return effects;
+    }
+
+    /// Takes in a reply or an incoming message from Keybase.
+    pub fn apply_reply(&mut self, reply: KeybaseReply) -> Vec<Effect> {
+        let mut effects = self.update_status(&reply);
+        effects.extend(self.apply_reply_content(reply));
+        return effects;
+    }
+
+    /// Notes that Keybase answered with `reply`.
+    fn update_status(&mut self, reply: &KeybaseReply) -> Vec<Effect> {
+        let was_offline = self.is_offline();
+        self.status = match reply {
+            KeybaseReply::ErrorReply { ref error }
+            | KeybaseReply::ListenerStopped { ref error } => ConnectionStatus::Error(error.clone()),
+            KeybaseReply::Disconnected { ref error } => {
+                ConnectionStatus::Reconnecting(error.clone())
+            }
+            _ => ConnectionStatus::Connected,
+        };
+        // Keybase is there, so what was held back can go.
+        let mut effects = Vec::new();
+        if was_offline && !self.is_offline() {
+            effects = self.resend(|o| o.delivery == Delivery::Queued);
+            if !effects.is_empty() && !self.outbox(&self.current_conversation_id).is_empty() {
+                effects.push(Effect::ShowConversation);
+            }
+        }
+        return effects;
+    }
+
+    fn apply_reply_content(&mut self, reply: KeybaseReply) -> Vec<Effect> {
+        match reply {
+            KeybaseReply::ChatMsgReply { msg } => {
+                self.push_message(msg.clone());
+                let delivered = self.remove_delivered(&msg.conversation_id);
+                if msg.conversation_id == self.current_conversation_id {
+                    // The outbox stays below the messages.
+                    if delivered || !self.outbox(&msg.conversation_id).is_empty() {
+                        return vec![Effect::ShowConversation];
+                    }
+                    return vec![Effect::ShowMessage { msg: msg }];
+                }
+                *self.unread.entry(msg.conversation_id).or_insert(0) += 1;
+                return vec![];
+            }
+            KeybaseReply::ChatMsgListReply { msgs } => {
+                // Whose empty history this is only the one who asked knows,
+                // see `apply_history`.
+                let conversation_id = match msgs.first() {
+                    Some(msg) => msg.conversation_id.clone(),
+                    None => return vec![],
+                };
+                return self.take_history(&conversation_id, msgs);
+            }
+            KeybaseReply::ChannelListReply { channels } => {
+                for chan in channels.iter().filter(|c| c.unread_msgs) {
+                    // The API only tells whether there is something unread.
+                    if chan.id != self.current_conversation_id {
+                        self.unread.entry(chan.id.clone()).or_insert(1);
+                    }
+                }
+                self.channels = channels;
+                return vec![Effect::ShowChannels];
+            }
+            KeybaseReply::Reconnected => {
+                // A fetch may have been cut off.
+                if self.current_conversation_id.is_empty() {
+                    return vec![];
+                }
+                return vec![Effect::Fetch {
+                    conversation_id: self.current_conversation_id.clone(),
+                }];
+            }
+            KeybaseReply::SearchReply { .. }
+            | KeybaseReply::SendReply { .. }
+            | KeybaseReply::Done
+            | KeybaseReply::ErrorReply { .. }
+            | KeybaseReply::Disconnected { .. }
+            | KeybaseReply::ListenerStopped { .. } => {
+                return vec![];
+            }
+        }
+    }
+
+    /// Returns what to do for `command` typed in the open conversation.
+    fn run_command(&mut self, command: Command) -> Result<Vec<Effect>, String> {
+        let conversation_id = self.current_conversation_id.clone();
+        let effect = match command {
+            Command::Send { text } => {
+                return Ok(self.send(&conversation_id, &text, OutgoingKind::Message));
+            }
+            Command::Msg { user, text } => {
+                let kind = OutgoingKind::To { name: user };
+                return Ok(self.send(&conversation_id, &text, kind));
+            }
+            Command::Explode { lifetime, text } => {
+                let kind = OutgoingKind::Exploding { lifetime: lifetime };
+                return Ok(self.send(&conversation_id, &text, kind));
+            }
+            Command::Edit { text } => {
+                let me = self.me.clone();
+                let msgs = self.messages.entry(conversation_id.clone()).or_default();
+                let msg = match msgs.iter().rev().find(|m| m.sender == me) {
+                    Some(msg) => msg,
+                    None => return Err("No message of yours to edit here.".to_string()),
+                };
+                // The cached message keeps its text until Keybase accepts the
+                // edit, see `apply_delivery`.
+                let kind = OutgoingKind::Edit { message_id: msg.id };
+                return Ok(self.send(&conversation_id, &text, kind));
+            }
+            Command::React { reaction } => {
+                let message_id = match self.messages(&conversation_id).last() {
+                    Some(msg) => msg.id,
+                    None => return Err("No message to react to.".to_string()),
+                };
+                let kind = OutgoingKind::Reaction {
+                    message_id: message_id,
+                };
+                return Ok(self.send(&conversation_id, &reaction, kind));
+            }
+            Command::Join { channel } => Effect::Join { channel: channel },
+            Command::Leave { channel } => {
+                let channel = match channel {
+                    Some(channel) => channel,
+                    None => self.conversation_name(&conversation_id).to_string(),
+                };
+                if !channel.contains('#') {
+                    return Err("Only team channels can be left.".to_string());
+                }
+                Effect::Leave { channel: channel }
+            }
+            Command::Retry => {
+                let mut effects =
+                    self.resend(|o| o.conversation_id == conversation_id && o.failed());
+                if effects.is_empty() {
+                    return Err("No failed messages here.".to_string());
+                }
+                effects.push(Effect::ShowConversation);
+                return Ok(effects);
+            }
+            Command::Discard => {
+                let count = self.outbox.len();
+                self.outbox
+                    .retain(|o| o.conversation_id != conversation_id || !o.failed());
+                if self.outbox.len() == count {
+                    return Err("No failed messages here.".to_string());
+                }
+                return Ok(vec![Effect::ShowConversation]);
+            }
+            Command::Search { query } => Effect::Search {
+                conversation_id: conversation_id,
+                query: query,
+            },
+            Command::Topic { text } => {
+                let channel = self.conversation_name(&conversation_id).to_string();
+                if !channel.contains('#') {
+                    return Err("Only team channels have a topic.".to_string());
+                }
+                Effect::SetTopic {
+                    channel: channel,
+                    text: text,
+                }
+            }
+            Command::Mute => Effect::Mute {
+                conversation_id: conversation_id,
+            },
+            Command::Help { command } => Effect::ShowNotice {
+                text: commands::help(command.as_deref())?,
+            },
+        };
+        return Ok(vec![effect]);
+    }
+
+    /// Takes in something the user did.
+    pub fn apply_action(&mut self, action: UserAction) -> Vec<Effect> {
+        match action {
+            UserAction::OpenConversation { conversation_id } => {
+                self.unread.remove(&conversation_id);
+                self.current_conversation_id = conversation_id.clone();
+                self.recall = None;
+                // What is known is shown until the history arrives.
+                return vec![
+                    Effect::ShowConversation,
+                    Effect::Fetch {
+                        conversation_id: conversation_id,
+                    },
+                    Effect::ShowDraft,
+                ];
+            }
+            UserAction::EditDraft { text } => {
+                if self.current_conversation_id.is_empty() {
+                    return vec![];
+                }
+                let conversation_id = self.current_conversation_id.clone();
+                // Editing a recalled message makes it a draft of its own.
+                self.recall = None;
+                let old = self.draft(&conversation_id);
+                let (text, effects) = match compose::fence_paste(old, &text, self.fence_paste_lines)
+                {
+                    Some(fenced) => (fenced, vec![Effect::ShowDraft]),
+                    None => (text, vec![]),
+                };
+                if text.is_empty() {
+                    self.drafts.remove(&conversation_id);
+                } else {
+                    self.drafts.insert(conversation_id, text);
+                }
+                return effects;
+            }
+            UserAction::SendDraft => {
+                let text = match self.drafts.get(&self.current_conversation_id) {
+                    Some(text) => text.trim().to_string(),
+                    None => return vec![],
+                };
+                let effects = match commands::parse(&text) {
+                    Some(Ok(command)) => self.run_command(command),
+                    Some(Err(error)) => Err(error),
+                    None if text.is_empty() => Ok(vec![]),
+                    None => {
+                        let conversation_id = self.current_conversation_id.clone();
+                        Ok(self.send(
+                            &conversation_id,
+                            commands::unescape(&text),
+                            OutgoingKind::Message,
+                        ))
+                    }
+                };
+                match effects {
+                    Ok(mut effects) => {
+                        self.push_sent(&text);
+                        self.recall = None;
+                        self.drafts.remove(&self.current_conversation_id);
+                        effects.insert(0, Effect::ShowDraft);
+                        return effects;
+                    }
+                    // The draft is kept for fixing.
+                    Err(error) => return vec![Effect::ShowNotice { text: error }],
+                }
+            }
+            UserAction::Complete => {
+                let draft = self.draft(&self.current_conversation_id).to_string();
+                let words = self.completion_words();
+                let (text, matches) = completion::complete(&draft, &words, &mut self.completion);
+                let mut effects = Vec::new();
+                if text != draft {
+                    self.drafts
+                        .insert(self.current_conversation_id.clone(), text);
+                    effects.push(Effect::ShowDraft);
+                }
+                if !matches.is_empty() {
+                    effects.push(Effect::ShowNotice {
+                        text: matches.join(" "),
+                    });
+                }
+                return effects;
+            }
+            UserAction::RecallOlder => {
+                let count = self
+                    .sent
+                    .get(&self.current_conversation_id)
+                    .map_or(0, Vec::len);
+                let index = match self.recall {
+                    Some(Recall { index, .. }) => index.saturating_sub(1),
+                    None if self.recalling() && count > 0 => count - 1,
+                    None => return vec![],
+                };
+                return self.recall_sent(index, None);
+            }
+            UserAction::RecallNewer => {
+                let count = self
+                    .sent
+                    .get(&self.current_conversation_id)
+                    .map_or(0, Vec::len);
+                match self.recall {
+                    Some(Recall { index, .. }) if index + 1 < count => {
+                        return self.recall_sent(index + 1, None);
+                    }
+                    Some(_) => {
+                        self.recall = None;
+                        self.drafts.remove(&self.current_conversation_id);
+                        return vec![Effect::ShowDraft];
+                    }
+                    None => return vec![],
+                }
+            }
+            UserAction::SearchSent => {
+                let draft = self.draft(&self.current_conversation_id).to_string();
+                let count = self
+                    .sent
+                    .get(&self.current_conversation_id)
+                    .map_or(0, Vec::len);
+                // Searching again goes on from the message found last.
+                let (query, before) = match self.recall {
+                    Some(Recall {
+                        index,
+                        query: Some(ref query),
+                    }) => (query.clone(), index),
+                    Some(Recall { index, query: None }) => (draft, index),
+                    None => (draft, count),
+                };
+                if query.is_empty() {
+                    return vec![Effect::ShowNotice {
+                        text: "Type part of a sent message to search for.".to_string(),
+                    }];
+                }
+                let texts = match self.sent.get(&self.current_conversation_id) {
+                    Some(texts) => &texts[..before],
+                    None => &[],
+                };
+                let lowercase = query.to_lowercase();
+                match texts
+                    .iter()
+                    .rposition(|t| t.to_lowercase().contains(&lowercase))
+                {
+                    Some(index) => return self.recall_sent(index, Some(query)),
+                    None => {
+                        return vec![Effect::ShowNotice {
+                            text: format!("No earlier sent message contains \"{}\".", query),
+                        }]
+                    }
+                }
+            }
+            UserAction::Send {
+                conversation_id,
+                text,
+            } => {
+                let text = text.trim();
+                if text.is_empty() {
+                    return vec![];
+                }
+                return self.send(&conversation_id, text, OutgoingKind::Message);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(conversation_id: &str, text: &str) -> ChatMsg {
+        ChatMsg {
+            sender: "alice".to_string(),
+            conversation_id: conversation_id.to_string(),
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn channel(id: &str, unread_msgs: bool) -> Channel {
+        Channel {
+            name: format!("team#{}", id),
+            id: id.to_string(),
+            unread_msgs: unread_msgs,
+        }
+    }
+
+    fn open(state: &mut AppState, conversation_id: &str) -> Vec<Effect> {
+        return state.apply_action(UserAction::OpenConversation {
+            conversation_id: conversation_id.to_string(),
+        });
+    }
+
+    fn texts(state: &AppState, conversation_id: &str) -> Vec<String> {
+        return state
+            .messages(conversation_id)
+            .iter()
+            .map(|m| m.text.clone())
+            .collect();
+    }
+
+    #[test]
+    fn test_channels_and_status() {
+        let mut state = AppState::new();
+        assert_eq!(*state.status(), ConnectionStatus::Connecting);
+
+        let channels = vec![channel("a", true), channel("b", false)];
+        let effects = state.apply_reply(KeybaseReply::ChannelListReply { channels: channels });
+        assert_eq!(effects, vec![Effect::ShowChannels]);
+        assert_eq!(*state.status(), ConnectionStatus::Connected);
+        assert_eq!(state.conversation_name("b"), "team#b");
+        assert_eq!(state.unread_count("a"), 1);
+        assert_eq!(state.unread_count("b"), 0);
+
+        let error = "no such conversation".to_string();
+        state.apply_reply(KeybaseReply::ErrorReply {
+            error: error.clone(),
+        });
+        assert_eq!(*state.status(), ConnectionStatus::Error(error));
+        state.apply_reply(KeybaseReply::SendReply { id: 1 });
+        assert_eq!(*state.status(), ConnectionStatus::Connected);
+    }
+
+    #[test]
+    fn test_open_and_receive() {
+        let mut state = AppState::new();
+        state.apply_reply(KeybaseReply::ChatMsgReply { msg: msg("a", "1") });
+        state.apply_reply(KeybaseReply::ChatMsgReply { msg: msg("a", "2") });
+        assert_eq!(state.unread_count("a"), 2);
+
+        assert_eq!(
+            open(&mut state, "a"),
+            vec![
+                Effect::ShowConversation,
+                Effect::Fetch {
+                    conversation_id: "a".to_string()
+                },
+                Effect::ShowDraft,
+            ]
+        );
+        assert_eq!(state.unread_count("a"), 0);
+        assert_eq!(state.current_conversation_id(), "a");
+
+        // History comes newest first and replaces what was known.
+        let msgs = vec![msg("a", "3"), msg("a", "2"), msg("a", "1")];
+        assert_eq!(texts(&state, "a"), vec!["1", "2"]);
+        let effects = state.apply_reply(KeybaseReply::ChatMsgListReply { msgs: msgs });
+        assert_eq!(effects, vec![Effect::ShowConversation]);
+        assert_eq!(texts(&state, "a"), vec!["1", "2", "3"]);
+
+        let effects = state.apply_reply(KeybaseReply::ChatMsgReply { msg: msg("a", "4") });
+        assert_eq!(effects, vec![Effect::ShowMessage { msg: msg("a", "4") }]);
+        assert_eq!(state.unread_count("a"), 0);
+        let effects = state.apply_reply(KeybaseReply::ChatMsgReply { msg: msg("b", "x") });
+        assert!(effects.is_empty());
+        assert_eq!(state.unread_count("b"), 1);
+
+        // Late history of another conversation is kept but not shown.
+        let msgs = vec![msg("b", "y")];
+        let effects = state.apply_history("b", KeybaseReply::ChatMsgListReply { msgs: msgs });
+        assert!(effects.is_empty());
+        assert_eq!(texts(&state, "b"), vec!["y"]);
+
+        // An empty history doesn't replace what is known, whoever it is for.
+        let empty = || KeybaseReply::ChatMsgListReply { msgs: vec![] };
+        assert!(state.apply_history("b", empty()).is_empty());
+        assert!(state.apply_reply(empty()).is_empty());
+        assert_eq!(texts(&state, "a"), vec!["1", "2", "3", "4"]);
+        assert_eq!(texts(&state, "b"), vec!["y"]);
+    }
+
+    fn at(conversation_id: &str, text: &str, secs: i64) -> ChatMsg {
+        let mut msg = msg(conversation_id, text);
+        msg.utc_timestamp = chrono::DateTime::from_timestamp(secs, 0)
+            .unwrap()
+            .naive_utc();
+        return msg;
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut state = AppState::new();
+        open(&mut state, "a");
+        let msgs = vec![at("a", "3", 3), at("a", "2", 2), at("a", "1", 1)];
+        state.apply_reply(KeybaseReply::ChatMsgListReply { msgs: msgs });
+        // Messages to other conversations are kept too.
+        state.apply_reply(KeybaseReply::ChatMsgReply {
+            msg: at("b", "x", 4),
+        });
+
+        open(&mut state, "b");
+        assert_eq!(texts(&state, "b"), vec!["x"]);
+        let effects = open(&mut state, "a");
+        assert_eq!(effects[0], Effect::ShowConversation);
+        assert_eq!(texts(&state, "a"), vec!["1", "2", "3"]);
+
+        // The same history again doesn't need to be shown again.
+        let msgs = vec![at("a", "3", 3), at("a", "2", 2)];
+        let effects = state.apply_reply(KeybaseReply::ChatMsgListReply { msgs: msgs });
+        assert!(effects.is_empty());
+        assert_eq!(texts(&state, "a"), vec!["1", "2", "3"]);
+
+        // Newer history is merged after the older messages.
+        let msgs = vec![at("a", "5", 5), at("a", "4", 4), at("a", "3", 3)];
+        let effects = state.apply_reply(KeybaseReply::ChatMsgListReply { msgs: msgs });
+        assert_eq!(effects, vec![Effect::ShowConversation]);
+        assert_eq!(texts(&state, "a"), vec!["1", "2", "3", "4", "5"]);
+
+        // Messages that arrived while the history was on the way stay.
+        state.apply_reply(KeybaseReply::ChatMsgReply {
+            msg: at("a", "6", 6),
+        });
+        let msgs = vec![at("a", "5", 5), at("a", "4", 4)];
+        state.apply_history("a", KeybaseReply::ChatMsgListReply { msgs: msgs });
+        assert_eq!(texts(&state, "a"), vec!["1", "2", "3", "4", "5", "6"]);
+
+        // Ids tell apart messages sent within the same second.
+        let mut late = at("a", "7", 6);
+        late.id = 7;
+        state.apply_reply(KeybaseReply::ChatMsgReply { msg: late });
+        let mut newest = at("a", "6", 6);
+        newest.id = 6;
+        state.apply_history("a", KeybaseReply::ChatMsgListReply { msgs: vec![newest] });
+        assert_eq!(texts(&state, "a"), vec!["1", "2", "3", "4", "5", "6", "7"]);
+    }
+
+    #[test]
+    fn test_history_length() {
+        let mut state = AppState::new();
+        for i in 0..HISTORY_LENGTH + 5 {
+            let msg = msg("a", &i.to_string());
+            state.apply_reply(KeybaseReply::ChatMsgReply { msg: msg });
+        }
+        assert_eq!(state.messages("a").len(), HISTORY_LENGTH);
+        assert_eq!(state.messages("a")[0].text, "5");
+    }
+
+    #[test]
+    fn test_drafts() {
+        let mut state = AppState::new();
+        state.apply_reply(KeybaseReply::ChannelListReply { channels: vec![] });
+        // Nowhere to keep a draft yet.
+        let edit = |text: &str| UserAction::EditDraft {
+            text: text.to_string(),
+        };
+        state.apply_action(edit("lost"));
+        assert_eq!(state.draft(""), "");
+
+        open(&mut state, "a");
+        state.apply_action(edit("hello "));
+        open(&mut state, "b");
+        assert_eq!(state.draft("b"), "");
+        state.apply_action(edit("  "));
+        open(&mut state, "a");
+        assert_eq!(state.draft("a"), "hello ");
+
+        assert_eq!(
+            state.apply_action(UserAction::SendDraft),
+            vec![
+                Effect::ShowDraft,
+                Effect::Send {
+                    outgoing_id: 0,
+                    conversation_id: "a".to_string(),
+                    text: "hello".to_string(),
+                    kind: OutgoingKind::Message,
+                },
+                Effect::ShowConversation,
+            ]
+        );
+        assert_eq!(state.draft("a"), "");
+        assert!(state.apply_action(UserAction::SendDraft).is_empty());
+
+        // Blank drafts are cleared but not sent.
+        open(&mut state, "b");
+        assert_eq!(
+            state.apply_action(UserAction::SendDraft),
+            vec![Effect::ShowDraft]
+        );
+        assert_eq!(state.draft("b"), "");
+
+        let mut drafts = HashMap::new();
+        drafts.insert("c".to_string(), "saved".to_string());
+        drafts.insert("d".to_string(), "".to_string());
+        state.apply_action(edit("gone"));
+        state.restore_drafts(drafts);
+        assert_eq!(state.drafts().len(), 1);
+        assert_eq!(state.draft("b"), "");
+        assert_eq!(state.draft("c"), "saved");
+    }
+
+    #[test]
+    fn test_fence_paste() {
+        let mut state = AppState::new();
+        state.set_fence_paste_lines(3);
+        open(&mut state, "a");
+        let edit = |text: &str| UserAction::EditDraft {
+            text: text.to_string(),
+        };
+        assert!(state.apply_action(edit("see:")).is_empty());
+        assert!(state.apply_action(edit("see:\na\nb")).is_empty());
+        assert_eq!(
+            state.apply_action(edit("see:\na\nb\nc\nd\ne")),
+            vec![Effect::ShowDraft]
+        );
+        assert_eq!(state.draft("a"), "see:\na\nb\n```\nc\nd\ne\n```");
+    }
+
+    fn send_draft(state: &mut AppState, text: &str) -> Vec<Effect> {
+        state.apply_action(UserAction::EditDraft {
+            text: text.to_string(),
+        });
+        return state.apply_action(UserAction::SendDraft);
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut state = AppState::new();
+        state.set_me("me");
+        let channels = vec![channel("a", false)];
+        state.apply_reply(KeybaseReply::ChannelListReply { channels: channels });
+        open(&mut state, "a");
+
+        assert_eq!(
+            send_draft(&mut state, "//usr/bin"),
+            vec![
+                Effect::ShowDraft,
+                Effect::Send {
+                    outgoing_id: 0,
+                    conversation_id: "a".to_string(),
+                    text: "/usr/bin".to_string(),
+                    kind: OutgoingKind::Message,
+                },
+                Effect::ShowConversation,
+            ]
+        );
+        assert_eq!(
+            send_draft(&mut state, "/topic Deploys only"),
+            vec![
+                Effect::ShowDraft,
+                Effect::SetTopic {
+                    channel: "team#a".to_string(),
+                    text: "Deploys only".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            send_draft(&mut state, "/search error 500"),
+            vec![
+                Effect::ShowDraft,
+                Effect::Search {
+                    conversation_id: "a".to_string(),
+                    query: "error 500".to_string()
+                },
+            ]
+        );
+        assert_eq!(
+            send_draft(&mut state, "/leave"),
+            vec![
+                Effect::ShowDraft,
+                Effect::Leave {
+                    channel: "team#a".to_string()
+                },
+            ]
+        );
+
+        // Errors keep the draft.
+        let effects = send_draft(&mut state, "/bogus");
+        assert_eq!(effects.len(), 1);
+        assert_eq!(state.draft("a"), "/bogus");
+        assert_eq!(
+            send_draft(&mut state, "/react :+1:"),
+            vec![Effect::ShowNotice {
+                text: "No message to react to.".to_string()
+            }]
+        );
+
+        let mut mine = msg("a", "tpyo");
+        mine.sender = "me".to_string();
+        mine.id = 7;
+        state.apply_reply(KeybaseReply::ChatMsgReply { msg: mine });
+        let mut theirs = msg("a", "lol");
+        theirs.id = 8;
+        state.apply_reply(KeybaseReply::ChatMsgReply { msg: theirs });
+        assert_eq!(
+            send_draft(&mut state, "/edit typo"),
+            vec![
+                Effect::ShowDraft,
+                Effect::Send {
+                    outgoing_id: 1,
+                    conversation_id: "a".to_string(),
+                    text: "typo".to_string(),
+                    kind: OutgoingKind::Edit { message_id: 7 },
+                },
+                Effect::ShowConversation,
+            ]
+        );
+        // The old text stays until Keybase accepts the edit.
+        assert_eq!(texts(&state, "a"), vec!["tpyo", "lol"]);
+        state.apply_delivery(1, KeybaseReply::SendReply { id: 0 });
+        assert_eq!(texts(&state, "a"), vec!["typo", "lol"]);
+        assert_eq!(state.outbox("a").len(), 1);
+        // A failed edit leaves the message alone.
+        assert_eq!(
+            send_draft(&mut state, "/edit oops")[1],
+            Effect::Send {
+                outgoing_id: 2,
+                conversation_id: "a".to_string(),
+                text: "oops".to_string(),
+                kind: OutgoingKind::Edit { message_id: 7 },
+            }
+        );
+        state.apply_delivery(
+            2,
+            KeybaseReply::ErrorReply {
+                error: "too late".to_string(),
+            },
+        );
+        assert_eq!(texts(&state, "a"), vec!["typo", "lol"]);
+        assert!(state.outbox("a")[1].failed());
+        assert_eq!(
+            send_draft(&mut state, "/react :+1:")[1],
+            Effect::Send {
+                outgoing_id: 3,
+                conversation_id: "a".to_string(),
+                text: ":+1:".to_string(),
+                kind: OutgoingKind::Reaction { message_id: 8 },
+            }
+        );
+        // Reactions don't come back as messages either.
+        state.apply_delivery(3, KeybaseReply::SendReply { id: 0 });
+        assert_eq!(state.outbox("a").len(), 2);
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut state = AppState::new();
+        open(&mut state, "a");
+        state.apply_action(UserAction::EditDraft {
+            text: "/ex".to_string(),
+        });
+        assert_eq!(
+            state.apply_action(UserAction::Complete),
+            vec![Effect::ShowDraft]
+        );
+        assert_eq!(state.draft("a"), "/explode ");
+
+        state.apply_action(UserAction::EditDraft {
+            text: "/m".to_string(),
+        });
+        assert_eq!(
+            state.apply_action(UserAction::Complete),
+            vec![Effect::ShowNotice {
+                text: "/me /msg /mute".to_string()
+            }]
+        );
+        assert_eq!(
+            state.apply_action(UserAction::Complete),
+            vec![Effect::ShowDraft]
+        );
+        assert_eq!(state.draft("a"), "/me");
+    }
+
+    #[test]
+    fn test_complete_words() {
+        let mut state = AppState::new();
+        state.set_me("me");
+        state.apply_reply(KeybaseReply::ChannelListReply {
+            channels: vec![channel("a", false), channel("b", false)],
+        });
+        open(&mut state, "a");
+        let mut from_me = msg("a", "deploy :shipit:");
+        from_me.sender = "me".to_string();
+        state.apply_reply(KeybaseReply::ChatMsgReply { msg: from_me });
+        state.apply_reply(KeybaseReply::ChatMsgReply {
+            msg: msg("a", "hi"),
+        });
+
+        let words = state.completion_words();
+        let users: Vec<&str> = words.users.iter().map(String::as_str).collect();
+        assert_eq!(users, vec!["alice"]);
+        let channels: Vec<&str> = words.channels.iter().map(String::as_str).collect();
+        assert_eq!(channels, vec!["a", "b"]);
+        assert!(words.emoji.contains("shipit"));
+
+        state.apply_action(UserAction::EditDraft {
+            text: "thanks @al".to_string(),
+        });
+        state.apply_action(UserAction::Complete);
+        assert_eq!(state.draft("a"), "thanks @alice ");
+    }
+
+    #[test]
+    fn test_recall() {
+        let mut state = AppState::new();
+        open(&mut state, "a");
+        for text in &["!deploy staging", "thanks", "!deploy prod", "thanks"] {
+            send_draft(&mut state, text);
+        }
+        assert_eq!(
+            state.sent()["a"],
+            vec!["!deploy staging", "thanks", "!deploy prod", "thanks"]
+        );
+        assert!(state.recalling());
+
+        let mut act = |action| {
+            state.apply_action(action);
+            return state.draft("a").to_string();
+        };
+        assert_eq!(act(UserAction::RecallOlder), "thanks");
+        assert_eq!(act(UserAction::RecallOlder), "!deploy prod");
+        assert_eq!(act(UserAction::RecallNewer), "thanks");
+        assert_eq!(act(UserAction::RecallNewer), "");
+
+        act(UserAction::EditDraft {
+            text: "DEPLOY".to_string(),
+        });
+        assert_eq!(act(UserAction::SearchSent), "!deploy prod");
+        assert_eq!(act(UserAction::SearchSent), "!deploy staging");
+        assert_eq!(
+            state.apply_action(UserAction::SearchSent),
+            vec![Effect::ShowNotice {
+                text: "No earlier sent message contains \"DEPLOY\".".to_string()
+            }]
+        );
+        assert_eq!(state.draft("a"), "!deploy staging");
+
+        // Typing leaves the history.
+        state.apply_action(UserAction::EditDraft {
+            text: "!deploy staging now".to_string(),
+        });
+        assert!(!state.recalling());
+        assert!(state.apply_action(UserAction::RecallOlder).is_empty());
+
+        state.set_sent_length(2);
+        assert_eq!(state.sent()["a"], vec!["!deploy prod", "thanks"]);
+    }
+
+    #[test]
+    fn test_send() {
+        let mut state = AppState::new();
+        let send = |text: &str| UserAction::Send {
+            conversation_id: "a".to_string(),
+            text: text.to_string(),
+        };
+        assert!(state.apply_action(send(" \n")).is_empty());
+        // Held back until Keybase answers.
+        assert!(state.apply_action(send("hi\n")).is_empty());
+        assert_eq!(state.outbox("a")[0].delivery, Delivery::Queued);
+        let send_hi = Effect::Send {
+            outgoing_id: 0,
+            conversation_id: "a".to_string(),
+            text: "hi".to_string(),
+            kind: OutgoingKind::Message,
+        };
+        assert_eq!(
+            state.apply_reply(KeybaseReply::ChannelListReply { channels: vec![] }),
+            vec![send_hi.clone(), Effect::ShowChannels]
+        );
+        assert_eq!(state.outbox("a")[0].delivery, Delivery::Pending);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let mut state = AppState::new();
+        state.apply_reply(KeybaseReply::ChannelListReply {
+            channels: vec![channel("a", false)],
+        });
+        open(&mut state, "a");
+        send_draft(&mut state, "one");
+        let lost = KeybaseReply::Disconnected {
+            error: "gone".to_string(),
+        };
+
+        // Cut off while sending, so it waits with the ones sent meanwhile.
+        state.apply_delivery(0, lost.clone());
+        assert_eq!(
+            *state.status(),
+            ConnectionStatus::Reconnecting("gone".to_string())
+        );
+        state.apply_reply(lost);
+        send_draft(&mut state, "two");
+        let delivery: Vec<&Delivery> = state.outbox("a").iter().map(|o| &o.delivery).collect();
+        assert_eq!(delivery, vec![&Delivery::Queued, &Delivery::Queued]);
+
+        let effects = state.apply_reply(KeybaseReply::Reconnected);
+        assert_eq!(*state.status(), ConnectionStatus::Connected);
+        let sent: Vec<u64> = effects
+            .iter()
+            .filter_map(|e| match e {
+                Effect::Send { outgoing_id, .. } => Some(*outgoing_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, vec![0, 1]);
+        assert!(effects.contains(&Effect::Fetch {
+            conversation_id: "a".to_string()
+        }));
+    }
+
+    #[test]
+    fn test_outbox() {
+        let mut state = AppState::new();
+        state.set_me("me");
+        state.apply_reply(KeybaseReply::ChannelListReply {
+            channels: vec![channel("a", false)],
+        });
+        open(&mut state, "a");
+        send_draft(&mut state, "one");
+        send_draft(&mut state, "two");
+        assert_eq!(state.outbox("a").len(), 2);
+
+        assert_eq!(
+            state.apply_delivery(0, KeybaseReply::SendReply { id: 41 }),
+            vec![Effect::ShowConversation]
+        );
+        assert_eq!(state.outbox("a")[0].delivery, Delivery::Sent { id: 41 });
+        let error = |error: &str| KeybaseReply::ErrorReply {
+            error: error.to_string(),
+        };
+        state.apply_delivery(1, error("no network"));
+        assert!(state.outbox("a")[1].failed());
+
+        // The message coming back from Keybase takes its place.
+        let mut echo = msg("a", "one");
+        echo.id = 41;
+        echo.sender = "me".to_string();
+        assert_eq!(
+            state.apply_reply(KeybaseReply::ChatMsgReply { msg: echo }),
+            vec![Effect::ShowConversation]
+        );
+        assert_eq!(state.outbox("a").len(), 1);
+
+        assert_eq!(
+            send_draft(&mut state, "/retry"),
+            vec![
+                Effect::ShowDraft,
+                Effect::Send {
+                    outgoing_id: 1,
+                    conversation_id: "a".to_string(),
+                    text: "two".to_string(),
+                    kind: OutgoingKind::Message,
+                },
+                Effect::ShowConversation,
+            ]
+        );
+        assert_eq!(
+            send_draft(&mut state, "/discard"),
+            vec![Effect::ShowNotice {
+                text: "No failed messages here.".to_string()
+            }]
+        );
+        state.apply_delivery(1, error("still no network"));
+        assert_eq!(
+            send_draft(&mut state, "/discard"),
+            vec![Effect::ShowDraft, Effect::ShowConversation]
+        );
+        assert!(state.outbox("a").is_empty());
+    }
+}
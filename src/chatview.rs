@@ -0,0 +1,431 @@
+//! The chat view: the viewport of a `TextBuffer` drawn into an area, with
+//! scrolling, selection and copying.
+
+use super::libui::{
+    set_clipboard_text, Area, AreaHandler, Attribute, AttributedString, Color, DrawContext, Font,
+    KeyEvent, MouseEvent, TextLayout, EXT_KEY_END, EXT_KEY_HOME, EXT_KEY_PAGE_DOWN,
+    EXT_KEY_PAGE_UP, MODIFIER_CTRL,
+};
+use kbchatbox::markdown::Span;
+use kbchatbox::textbuffer::{
+    display_width, slice_columns, FoundLink, RowId, StyledLine, TextBuffer,
+};
+use std::cell::RefCell;
+use std::cmp;
+use std::process::Command;
+use std::rc::Rc;
+
+const FONT_FAMILY: &str = "Monospace";
+const FONT_SIZE: f64 = 10.0;
+const MARGIN: f64 = 4.0;
+
+const BACKGROUND: Color = Color::rgb(1.0, 1.0, 1.0);
+const HIGHLIGHT_BACKGROUND: Color = Color::rgb(1.0, 0.97, 0.8);
+const SELECTION_BACKGROUND: Color = Color::rgb(0.7, 0.8, 1.0);
+const CODE_BACKGROUND: Color = Color::rgb(0.92, 0.92, 0.92);
+const TEXT_COLOR: Color = Color::rgb(0.0, 0.0, 0.0);
+const QUOTE_COLOR: Color = Color::rgb(0.3, 0.5, 0.3);
+const DIM_COLOR: Color = Color::rgb(0.5, 0.5, 0.5);
+const LINK_COLOR: Color = Color::rgb(0.1, 0.3, 0.8);
+const INDICATOR_BACKGROUND: Color = Color::rgb(0.25, 0.45, 0.75);
+const INDICATOR_COLOR: Color = Color::rgb(1.0, 1.0, 1.0);
+
+fn font() -> Font {
+    Font {
+        family: FONT_FAMILY.to_string(),
+        size: FONT_SIZE,
+    }
+}
+
+/// Returns the attributes that show `span`.
+fn attributes(span: &Span) -> Vec<Attribute> {
+    let style = &span.style;
+    let mut attrs = Vec::new();
+    if span.link.is_some() {
+        attrs.push(Attribute::Color(LINK_COLOR));
+        attrs.push(Attribute::Underline);
+    }
+    if style.code || style.code_block {
+        attrs.push(Attribute::Family(FONT_FAMILY.to_string()));
+        attrs.push(Attribute::Background(CODE_BACKGROUND));
+    }
+    if style.bold {
+        attrs.push(Attribute::Bold);
+    }
+    if style.italic {
+        attrs.push(Attribute::Italic);
+    }
+    if span.link.is_some() {
+        // Already colored.
+    } else if style.quote {
+        attrs.push(Attribute::Color(QUOTE_COLOR));
+    } else if style.dim {
+        attrs.push(Attribute::Color(DIM_COLOR));
+    }
+    return attrs;
+}
+
+/// Schemes `open_link` hands to `xdg-open`. Others could run anything a
+/// desktop file handles.
+const LINK_SCHEMES: &[&str] = &["http://", "https://", "mailto:"];
+
+/// Opens `target` in the default browser or mail client.
+fn open_link(target: &str) {
+    let lower = target.to_ascii_lowercase();
+    if !LINK_SCHEMES.iter().any(|scheme| lower.starts_with(scheme)) {
+        eprintln!("Not opening {}", target);
+        return;
+    }
+    eprintln!("Opening {}", target);
+    match Command::new("xdg-open").arg(target).spawn() {
+        Ok(_) => {}
+        Err(err) => eprintln!("Couldn't run xdg-open: {}", err),
+    }
+}
+
+/// A position between two characters: row and column. Wide characters
+/// take two columns.
+type Cell = (RowId, usize);
+
+/// Number of rows one step of the mouse wheel scrolls.
+const SCROLL_ROWS: f64 = 3.0;
+
+struct ViewState {
+    buf: TextBuffer,
+    /// Scrolled rows not yet whole, from smooth scrolling.
+    scroll_rest: f64,
+    /// What the last draw showed, for mapping the mouse to cells.
+    rows: Vec<(RowId, StyledLine)>,
+    cell_width: f64,
+    line_height: f64,
+    /// Top of the "new messages" bar, if it is shown.
+    indicator_y: Option<f64>,
+    /// Where the selection started and where it ends now.
+    selection: Option<(Cell, Cell)>,
+    selecting: bool,
+}
+
+impl ViewState {
+    fn cell_at(&self, x: f64, y: f64) -> Option<Cell> {
+        if self.rows.is_empty() || self.line_height <= 0.0 {
+            return None;
+        }
+        let row = ((y - MARGIN) / self.line_height).floor().max(0.0) as usize;
+        let (id, ref line) = self.rows[cmp::min(row, self.rows.len() - 1)];
+        let column = ((x - MARGIN) / self.cell_width).round().max(0.0) as usize;
+        return Some((id, cmp::min(column, display_width(&line.text()))));
+    }
+
+    /// Returns the target of the link under the mouse.
+    fn link_at(&self, x: f64, y: f64) -> Option<String> {
+        if self.line_height <= 0.0 || y < MARGIN || x < MARGIN {
+            return None;
+        }
+        let row = ((y - MARGIN) / self.line_height) as usize;
+        let column = ((x - MARGIN) / self.cell_width) as usize;
+        let (_, ref line) = self.rows.get(row)?;
+        return line.link_at(column).map(String::from);
+    }
+
+    /// Returns the selection with the start first.
+    fn ordered_selection(&self) -> Option<(Cell, Cell)> {
+        match self.selection {
+            Some((a, b)) if a != b => Some((cmp::min(a, b), cmp::max(a, b))),
+            _ => None,
+        }
+    }
+
+    /// Returns the selected columns of row `id` with `text`, if any.
+    fn selected_columns(&self, id: RowId, text: &str) -> Option<(usize, usize)> {
+        let (start, end) = self.ordered_selection()?;
+        if id < start.0 || id > end.0 {
+            return None;
+        }
+        let first = if id == start.0 { start.1 } else { 0 };
+        let last = if id == end.0 {
+            end.1
+        } else {
+            display_width(text)
+        };
+        return Some((first, last));
+    }
+
+    /// Returns the selected text. Rows scrolled out of view are included.
+    fn selected_text(&self) -> Option<String> {
+        let (start, end) = self.ordered_selection()?;
+        let mut lines = Vec::new();
+        for (id, row) in self.buf.rows_between(start.0, end.0) {
+            let text = row.text();
+            let (first, last) = self.selected_columns(id, &text)?;
+            lines.push(slice_columns(&text, first, last));
+        }
+        return Some(lines.join("\n"));
+    }
+}
+
+struct Drawer {
+    state: Rc<RefCell<ViewState>>,
+}
+
+impl AreaHandler for Drawer {
+    fn draw(&mut self, ctx: &DrawContext, width: f64, height: f64) {
+        ctx.fill_rect(0.0, 0.0, width, height, BACKGROUND);
+
+        // The size of one character cell.
+        let font = font();
+        let mut cell = AttributedString::new();
+        cell.append("M");
+        let (cell_width, line_height) = TextLayout::new(&cell, &font, -1.0).extents();
+
+        let mut state = self.state.borrow_mut();
+        state.cell_width = cell_width;
+        state.line_height = line_height;
+        // Re-wrap when the window is resized.
+        let columns = cmp::max(((width - 2.0 * MARGIN) / cell_width) as usize, 1);
+        if state.buf.width() != columns {
+            state.buf.set_width(columns);
+            // The selected cells are not the same after wrapping.
+            state.selection = None;
+            state.selecting = false;
+        }
+        let visible = cmp::max(((height - 2.0 * MARGIN) / line_height) as usize, 1);
+        if state.buf.height() != visible {
+            state.buf.set_height(visible);
+        }
+        state.rows = state.buf.get_visible_rows();
+
+        let mut y = MARGIN;
+        for (id, row) in &state.rows {
+            if row.highlight {
+                ctx.fill_rect(0.0, y, width, line_height, HIGHLIGHT_BACKGROUND);
+            }
+            if let Some((first, last)) = state.selected_columns(*id, &row.text()) {
+                ctx.fill_rect(
+                    MARGIN + first as f64 * cell_width,
+                    y,
+                    (last - first) as f64 * cell_width,
+                    line_height,
+                    SELECTION_BACKGROUND,
+                );
+            }
+
+            let mut text = AttributedString::new();
+            // libui has no strikethrough, so those spans get a line drawn
+            // over them. Columns are cells of the monospace font.
+            let mut strikes = Vec::new();
+            let mut column = 0;
+            for span in &row.spans {
+                let range = text.append(&span.text);
+                for attr in attributes(span) {
+                    text.set_attribute(&attr, range);
+                }
+                let len = display_width(&span.text);
+                if span.style.strike {
+                    strikes.push((column, len));
+                }
+                column += len;
+            }
+
+            let layout = TextLayout::new(&text, &font, -1.0);
+            ctx.draw_text(&layout, MARGIN, y);
+            for (start, len) in strikes {
+                ctx.fill_rect(
+                    MARGIN + start as f64 * cell_width,
+                    y + (line_height / 2.0).round(),
+                    len as f64 * cell_width,
+                    1.0,
+                    TEXT_COLOR,
+                );
+            }
+
+            y += line_height;
+        }
+
+        // Tell about messages below the viewport.
+        state.indicator_y = None;
+        let unseen = state.buf.unseen();
+        if unseen > 0 {
+            let bar_y = height - line_height - MARGIN;
+            ctx.fill_rect(
+                0.0,
+                bar_y,
+                width,
+                line_height + MARGIN,
+                INDICATOR_BACKGROUND,
+            );
+            let mut text = AttributedString::new();
+            let label = match unseen {
+                1 => "▼ 1 new message below".to_string(),
+                n => format!("▼ {} new messages below", n),
+            };
+            let range = text.append(&label);
+            text.set_attribute(&Attribute::Color(INDICATOR_COLOR), range);
+            text.set_attribute(&Attribute::Bold, range);
+            ctx.draw_text(
+                &TextLayout::new(&text, &font, -1.0),
+                MARGIN,
+                bar_y + MARGIN / 2.0,
+            );
+            state.indicator_y = Some(bar_y);
+        }
+    }
+
+    fn mouse_event(&mut self, event: &MouseEvent) -> bool {
+        let mut state = self.state.borrow_mut();
+        if event.down == 1 {
+            // Clicking the "new messages" bar jumps to them.
+            if let Some(bar_y) = state.indicator_y {
+                if event.y >= bar_y {
+                    state.buf.scroll_to_bottom();
+                    return true;
+                }
+            }
+            state.selection = state.cell_at(event.x, event.y).map(|cell| (cell, cell));
+            state.selecting = state.selection.is_some();
+            return true;
+        }
+        if event.up == 1 {
+            state.selecting = false;
+            // A click without dragging opens links.
+            if state.ordered_selection().is_none() {
+                if let Some(target) = state.link_at(event.x, event.y) {
+                    open_link(&target);
+                }
+            }
+            return false;
+        }
+        // Dragging with the first button held.
+        if state.selecting && event.held & 1 != 0 {
+            let cell = match state.cell_at(event.x, event.y) {
+                Some(cell) => cell,
+                None => return false,
+            };
+            if let Some((anchor, end)) = state.selection {
+                if end != cell {
+                    state.selection = Some((anchor, cell));
+                    return true;
+                }
+            }
+        }
+        return false;
+    }
+
+    fn scroll(&mut self, dy: f64) -> bool {
+        let mut state = self.state.borrow_mut();
+        let rows = state.scroll_rest + dy * SCROLL_ROWS;
+        let whole = rows.trunc();
+        state.scroll_rest = rows - whole;
+
+        if whole < 0.0 {
+            state.buf.scroll_up(-whole as usize);
+        } else if whole > 0.0 {
+            state.buf.scroll_down(whole as usize);
+        }
+        return whole != 0.0;
+    }
+
+    fn key_event(&mut self, event: &KeyEvent) -> bool {
+        if event.up {
+            return false;
+        }
+        let mut state = self.state.borrow_mut();
+        match event.ext_key {
+            EXT_KEY_PAGE_UP => {
+                state.buf.page_up();
+                return true;
+            }
+            EXT_KEY_PAGE_DOWN => {
+                state.buf.page_down();
+                return true;
+            }
+            EXT_KEY_HOME => {
+                state.buf.scroll_up(usize::MAX);
+                return true;
+            }
+            EXT_KEY_END => {
+                state.buf.scroll_to_bottom();
+                return true;
+            }
+            _ => {}
+        }
+
+        if event.modifiers & MODIFIER_CTRL == 0 {
+            return false;
+        }
+        match event.key {
+            'c' => {
+                if let Some(text) = state.selected_text() {
+                    set_clipboard_text(&text);
+                }
+                return true;
+            }
+            'a' => {
+                if let Some((first, last)) = state.buf.row_range() {
+                    let rows = state.buf.rows_between(last, last);
+                    let width = display_width(&rows[0].1.text());
+                    state.selection = Some(((first, 0), (last, width)));
+                }
+                return true;
+            }
+            _ => return false,
+        }
+    }
+}
+
+/// Shows a `TextBuffer`. It scrolls with the mouse wheel and Page Up, Page
+/// Down, Home and End, text can be selected with the mouse and copied with
+/// Ctrl+C, and clicking a link opens it.
+pub struct ChatView {
+    area: Area,
+    state: Rc<RefCell<ViewState>>,
+}
+
+impl ChatView {
+    pub fn new(text_buf: TextBuffer) -> Self {
+        let state = Rc::new(RefCell::new(ViewState {
+            buf: text_buf,
+            scroll_rest: 0.0,
+            rows: Vec::new(),
+            cell_width: 0.0,
+            line_height: 0.0,
+            indicator_y: None,
+            selection: None,
+            selecting: false,
+        }));
+        let drawer = Drawer {
+            state: Rc::clone(&state),
+        };
+        ChatView {
+            area: Area::new(Box::new(drawer)),
+            state: state,
+        }
+    }
+
+    /// The control to put into a layout.
+    pub fn area(&self) -> Area {
+        return self.area.clone();
+    }
+
+    /// Changes the text buffer and redraws.
+    pub fn update<F: FnOnce(&mut TextBuffer)>(&self, f: F) {
+        f(&mut self.state.borrow_mut().buf);
+        self.area.queue_redraw_all();
+    }
+
+    /// Returns the links in the text buffer.
+    pub fn links(&self) -> Vec<FoundLink> {
+        return self.state.borrow().buf.links().to_vec();
+    }
+
+    /// Returns the number of rows that fit into the view.
+    pub fn visible_rows(&self) -> usize {
+        return self.state.borrow().buf.height();
+    }
+
+    /// Clears the selection, e.g. when another conversation is opened.
+    pub fn clear_selection(&self) {
+        let mut state = self.state.borrow_mut();
+        state.selection = None;
+        state.selecting = false;
+    }
+}
@@ -0,0 +1,315 @@
+//! Command line subcommands for scripts and cron jobs.
+
+use kbchatbox::{Channel, Keybase, KeybaseReply};
+use std::io::{self, Read, Write};
+use std::sync::mpsc::Receiver;
+
+pub const EXIT_OK: i32 = 0;
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+
+const DEFAULT_READ_COUNT: usize = 25;
+
+const USAGE: &str = "Usage:
+    kbchatbox                          Start the GUI.
+    kbchatbox list                     List conversations (name, id, read/unread).
+    kbchatbox read <conv> [-n N]       Print the newest N messages (default 25).
+    kbchatbox send <conv> <text|->     Send text, or read it from stdin with -.
+    kbchatbox tail [conv] [--json]     Print new messages as they arrive.
+    kbchatbox irc [--port N] [--token-file PATH]
+                                       Run an IRC gateway on localhost (default 6667).
+    kbchatbox daemon [--port N] [--token-file PATH]
+                                       Run the HTTP/WebSocket API on localhost (default 8675).
+    kbchatbox help                     Show this help.
+
+<conv> is a conversation name (e.g. alice,bob or team#general) or id.";
+
+enum CliError {
+    /// Bad arguments, exits with `EXIT_USAGE`.
+    Usage(String),
+    /// Keybase or IO failure, exits with `EXIT_FAILURE`.
+    Failed(String),
+}
+
+/// Runs the subcommand in `args` and returns the process exit status.
+pub fn run(args: &[String]) -> i32 {
+    let result = match args[0].as_str() {
+        "list" => cmd_list(&args[1..]),
+        "read" => cmd_read(&args[1..]),
+        "send" => cmd_send(&args[1..]),
+        "tail" => cmd_tail(&args[1..]),
+        "irc" => cmd_irc(&args[1..]),
+        "daemon" => cmd_daemon(&args[1..]),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            return EXIT_OK;
+        }
+        other => Err(CliError::Usage(format!("Unknown command: {}", other))),
+    };
+
+    match result {
+        Ok(()) => EXIT_OK,
+        Err(CliError::Usage(msg)) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            EXIT_USAGE
+        }
+        Err(CliError::Failed(msg)) => {
+            eprintln!("Error: {}", msg);
+            EXIT_FAILURE
+        }
+    }
+}
+
+fn list_channels(kb: &Keybase) -> Result<Vec<Channel>, CliError> {
+    match kb.call(Keybase::create_list_channels_req()) {
+        Ok(KeybaseReply::ChannelListReply { channels }) => Ok(channels),
+        Ok(_) => Err(CliError::Failed("Unexpected reply to list".to_string())),
+        Err(err) => Err(CliError::Failed(err)),
+    }
+}
+
+/// Parses the `--port N` and `--token-file PATH` options of the servers.
+#[cfg(any(feature = "irc", feature = "http-api"))]
+fn parse_server_args(
+    args: &[String],
+    default_port: u16,
+) -> Result<(u16, std::path::PathBuf), CliError> {
+    let mut port = default_port;
+    let mut token_file = super::token::default_token_file();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match (arg.as_str(), it.next()) {
+            ("--port", Some(value)) => {
+                port = match value.parse() {
+                    Ok(port) => port,
+                    Err(_) => return Err(CliError::Usage(format!("Invalid port: {}", value))),
+                };
+            }
+            ("--token-file", Some(value)) => token_file = value.into(),
+            _ => return Err(CliError::Usage(format!("Unexpected argument: {}", arg))),
+        }
+    }
+    return Ok((port, token_file));
+}
+
+/// Maps a conversation name or id to an id.
+fn resolve_conversation(kb: &Keybase, name_or_id: &str) -> Result<String, CliError> {
+    for chan in list_channels(kb)? {
+        if chan.id == name_or_id || chan.name == name_or_id {
+            return Ok(chan.id);
+        }
+    }
+    return Err(CliError::Failed(format!(
+        "No such conversation: {}",
+        name_or_id
+    )));
+}
+
+fn cmd_list(args: &[String]) -> Result<(), CliError> {
+    if !args.is_empty() {
+        return Err(CliError::Usage("list takes no arguments".to_string()));
+    }
+
+    let kb = Keybase::new_api_only();
+    for chan in list_channels(&kb)? {
+        let status = if chan.unread_msgs { "unread" } else { "read" };
+        println!("{}\t{}\t{}", chan.name, chan.id, status);
+    }
+    Ok(())
+}
+
+fn cmd_read(args: &[String]) -> Result<(), CliError> {
+    let mut conversation = None;
+    let mut count = DEFAULT_READ_COUNT;
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        if arg == "-n" {
+            count = match it.next().map(|n| n.parse()) {
+                Some(Ok(n)) if n > 0 => n,
+                _ => return Err(CliError::Usage("-n needs a positive number".to_string())),
+            };
+        } else if conversation.is_none() {
+            conversation = Some(arg);
+        } else {
+            return Err(CliError::Usage(format!("Unexpected argument: {}", arg)));
+        }
+    }
+    let conversation = match conversation {
+        Some(conversation) => conversation,
+        None => return Err(CliError::Usage("read needs a conversation".to_string())),
+    };
+
+    let kb = Keybase::new_api_only();
+    let conversation_id = resolve_conversation(&kb, conversation)?;
+    let req = Keybase::create_read_conversation_req(&conversation_id, count);
+    match kb.call(req) {
+        Ok(KeybaseReply::ChatMsgListReply { msgs }) => {
+            // Newest first from the API, print in reading order.
+            for msg in msgs.iter().rev() {
+                println!("{}", msg.format());
+            }
+            Ok(())
+        }
+        Ok(_) => Err(CliError::Failed("Unexpected reply to read".to_string())),
+        Err(err) => Err(CliError::Failed(err)),
+    }
+}
+
+fn cmd_send(args: &[String]) -> Result<(), CliError> {
+    if args.len() < 2 {
+        return Err(CliError::Usage(
+            "send needs a conversation and text".to_string(),
+        ));
+    }
+
+    let text = if args.len() == 2 && args[1] == "-" {
+        let mut text = String::new();
+        if let Err(err) = io::stdin().read_to_string(&mut text) {
+            return Err(CliError::Failed(format!("Reading stdin failed: {}", err)));
+        }
+        text
+    } else {
+        args[1..].join(" ")
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(CliError::Usage(
+            "Refusing to send an empty message".to_string(),
+        ));
+    }
+
+    let kb = Keybase::new_api_only();
+    let conversation_id = resolve_conversation(&kb, &args[0])?;
+    match kb.call(Keybase::create_msg_req(&conversation_id, text)) {
+        Ok(KeybaseReply::SendReply { .. }) => Ok(()),
+        Ok(_) => Err(CliError::Failed("Unexpected reply to send".to_string())),
+        Err(err) => Err(CliError::Failed(err)),
+    }
+}
+
+fn cmd_tail(args: &[String]) -> Result<(), CliError> {
+    let mut conversation = None;
+    let mut json = false;
+    for arg in args {
+        if arg == "--json" {
+            json = true;
+        } else if conversation.is_none() {
+            conversation = Some(arg);
+        } else {
+            return Err(CliError::Usage(format!("Unexpected argument: {}", arg)));
+        }
+    }
+
+    let kb = Keybase::new();
+    let conversation_id = match conversation {
+        Some(conversation) => Some(resolve_conversation(&kb, conversation)?),
+        None => None,
+    };
+
+    let stdout = io::stdout();
+    return tail(
+        kb.get_message_receiver(),
+        conversation_id.as_deref(),
+        json,
+        &mut stdout.lock(),
+    );
+}
+
+/// Writes the messages from `rx` to `out` until the listener stops.
+fn tail(
+    rx: &Receiver<KeybaseReply>,
+    conversation_id: Option<&str>,
+    json: bool,
+    out: &mut dyn Write,
+) -> Result<(), CliError> {
+    loop {
+        let msg = match rx.recv() {
+            Ok(KeybaseReply::ChatMsgReply { msg }) => msg,
+            Ok(KeybaseReply::ListenerStopped { error }) => {
+                return Err(CliError::Failed(format!(
+                    "Keybase listener stopped: {}",
+                    error
+                )));
+            }
+            Ok(_) => continue,
+            Err(_) => return Err(CliError::Failed("Keybase listener stopped".to_string())),
+        };
+
+        if let Some(id) = conversation_id {
+            if msg.conversation_id != id {
+                continue;
+            }
+        }
+
+        let line = if json {
+            msg.to_json().to_string()
+        } else {
+            msg.format()
+        };
+
+        // Stop quietly when the reading end of a pipe goes away.
+        if writeln!(out, "{}", line).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(feature = "irc")]
+fn cmd_irc(args: &[String]) -> Result<(), CliError> {
+    let (port, token_file) = parse_server_args(args, super::irc::DEFAULT_PORT)?;
+    return super::irc::run(port, &token_file).map_err(CliError::Failed);
+}
+
+#[cfg(not(feature = "irc"))]
+fn cmd_irc(_args: &[String]) -> Result<(), CliError> {
+    return Err(CliError::Failed(
+        "kbchatbox was built without the irc feature".to_string(),
+    ));
+}
+
+#[cfg(feature = "http-api")]
+fn cmd_daemon(args: &[String]) -> Result<(), CliError> {
+    let (port, token_file) = parse_server_args(args, super::daemon::DEFAULT_PORT)?;
+    return super::daemon::run(port, &token_file).map_err(CliError::Failed);
+}
+
+#[cfg(not(feature = "http-api"))]
+fn cmd_daemon(_args: &[String]) -> Result<(), CliError> {
+    return Err(CliError::Failed(
+        "kbchatbox was built without the http-api feature".to_string(),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kbchatbox::ChatMsg;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_tail_stops_with_listener() {
+        let (tx, rx) = mpsc::channel();
+        let msg = |conversation_id: &str, text: &str| KeybaseReply::ChatMsgReply {
+            msg: ChatMsg {
+                conversation_id: conversation_id.to_string(),
+                text: text.to_string(),
+                ..ChatMsg::default()
+            },
+        };
+        tx.send(msg("a", "hi")).unwrap();
+        tx.send(msg("b", "elsewhere")).unwrap();
+        tx.send(KeybaseReply::ListenerStopped {
+            error: "gone".to_string(),
+        })
+        .unwrap();
+
+        let mut out = Vec::new();
+        match tail(&rx, Some("a"), true, &mut out) {
+            Err(CliError::Failed(error)) => assert_eq!(error, "Keybase listener stopped: gone"),
+            _ => panic!("tail didn't fail"),
+        }
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains("\"hi\""));
+    }
+}
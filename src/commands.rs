@@ -0,0 +1,352 @@
+//! Slash commands typed into the message entry.
+//!
+//! A message starting with `/` is a command, e.g. `/msg alice hi`. Start a
+//! message with `//` to send it as text with one `/` less. Every command is
+//! listed in `COMMANDS`, which is also what `/help` and completion use.
+
+/// A command the user can type.
+pub struct CommandInfo {
+    pub name: &'static str,
+    /// The arguments, e.g. `<user> <text>`. Optional ones are in brackets.
+    pub args: &'static str,
+    pub help: &'static str,
+}
+
+/// All commands, sorted by name.
+pub const COMMANDS: &[CommandInfo] = &[
+    CommandInfo {
+        name: "discard",
+        args: "",
+        help: "Drops the messages here that couldn't be sent.",
+    },
+    CommandInfo {
+        name: "edit",
+        args: "<text>",
+        help: "Replaces the text of your last message here.",
+    },
+    CommandInfo {
+        name: "explode",
+        args: "<time> <text>",
+        help: "Sends a message that explodes after <time>, e.g. 30s, 10m, 2h or 7d.",
+    },
+    CommandInfo {
+        name: "help",
+        args: "[command]",
+        help: "Lists the commands, or tells what one does.",
+    },
+    CommandInfo {
+        name: "join",
+        args: "<team#channel>",
+        help: "Joins a team channel.",
+    },
+    CommandInfo {
+        name: "leave",
+        args: "[team#channel]",
+        help: "Leaves a team channel, this one if none is given.",
+    },
+    CommandInfo {
+        name: "me",
+        args: "<action>",
+        help: "Tells what you are doing, in italics.",
+    },
+    CommandInfo {
+        name: "msg",
+        args: "<user> <text>",
+        help: "Sends a private message.",
+    },
+    CommandInfo {
+        name: "mute",
+        args: "",
+        help: "Turns off notifications for this conversation.",
+    },
+    CommandInfo {
+        name: "react",
+        args: "<:emoji:>",
+        help: "Reacts to the last message here.",
+    },
+    CommandInfo {
+        name: "retry",
+        args: "",
+        help: "Sends the messages here that couldn't be sent again.",
+    },
+    CommandInfo {
+        name: "search",
+        args: "<text>",
+        help: "Lists the messages here that contain the text.",
+    },
+    CommandInfo {
+        name: "shrug",
+        args: "[text]",
+        help: "Sends the text followed by ¯\\_(ツ)_/¯.",
+    },
+    CommandInfo {
+        name: "topic",
+        args: "<text>",
+        help: "Sets the headline of this team channel.",
+    },
+];
+
+const SHRUG: &str = "¯\\_(ツ)_/¯";
+
+/// A parsed command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Send text to the open conversation.
+    Send {
+        text: String,
+    },
+    /// Send text to a user.
+    Msg {
+        user: String,
+        text: String,
+    },
+    Explode {
+        lifetime: String,
+        text: String,
+    },
+    Edit {
+        text: String,
+    },
+    React {
+        reaction: String,
+    },
+    Join {
+        channel: String,
+    },
+    /// Leave a channel, or the open conversation if `None`.
+    Leave {
+        channel: Option<String>,
+    },
+    Mute,
+    Search {
+        query: String,
+    },
+    /// Set the headline of the open channel.
+    Topic {
+        text: String,
+    },
+    /// Send the failed messages of the open conversation again.
+    Retry,
+    /// Drop the failed messages of the open conversation.
+    Discard,
+    Help {
+        command: Option<String>,
+    },
+}
+
+fn find(name: &str) -> Option<&'static CommandInfo> {
+    return COMMANDS.iter().find(|c| c.name == name);
+}
+
+/// Splits off the first word of `text`.
+fn first_word(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(pos) => (&text[..pos], text[pos..].trim()),
+        None => (text, ""),
+    }
+}
+
+/// Returns true for lifetimes like `30s`, `10m`, `2h` or `7d`.
+fn is_lifetime(s: &str) -> bool {
+    let unit = match s.chars().last() {
+        Some(unit) => unit,
+        None => return false,
+    };
+    let number = &s[..s.len() - unit.len_utf8()];
+    return !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit())
+        && "smhd".contains(unit);
+}
+
+/// Parses `text` as a command. Returns `None` if it isn't one, and an error
+/// for unknown commands or bad arguments.
+pub fn parse(text: &str) -> Option<Result<Command, String>> {
+    if !text.starts_with('/') || text.starts_with("//") {
+        return None;
+    }
+
+    let (name, args) = first_word(&text[1..]);
+    let info = match find(name) {
+        Some(info) => info,
+        None => {
+            return Some(Err(format!(
+                "Unknown command /{}. Type /help for a list.",
+                name
+            )))
+        }
+    };
+    let (first, rest) = first_word(args);
+    let command = match info.name {
+        "edit" if !args.is_empty() => Command::Edit {
+            text: args.to_string(),
+        },
+        "explode" if is_lifetime(first) && !rest.is_empty() => Command::Explode {
+            lifetime: first.to_string(),
+            text: rest.to_string(),
+        },
+        "help" if rest.is_empty() => Command::Help {
+            command: match first {
+                "" => None,
+                command => Some(command.trim_start_matches('/').to_string()),
+            },
+        },
+        "join" if first.contains('#') && rest.is_empty() => Command::Join {
+            channel: first.to_string(),
+        },
+        "leave" if rest.is_empty() => Command::Leave {
+            channel: match first {
+                "" => None,
+                channel => Some(channel.to_string()),
+            },
+        },
+        "me" if !args.is_empty() => Command::Send {
+            text: format!("_{}_", args),
+        },
+        "msg" if !first.is_empty() && !rest.is_empty() => Command::Msg {
+            user: first.trim_start_matches('@').to_string(),
+            text: rest.to_string(),
+        },
+        "mute" if args.is_empty() => Command::Mute,
+        "retry" if args.is_empty() => Command::Retry,
+        "search" if !args.is_empty() => Command::Search {
+            query: args.to_string(),
+        },
+        "topic" if !args.is_empty() => Command::Topic {
+            text: args.to_string(),
+        },
+        "discard" if args.is_empty() => Command::Discard,
+        "react" if !first.is_empty() && rest.is_empty() => Command::React {
+            reaction: first.to_string(),
+        },
+        "shrug" if args.is_empty() => Command::Send {
+            text: SHRUG.to_string(),
+        },
+        "shrug" => Command::Send {
+            text: format!("{} {}", args, SHRUG),
+        },
+        _ => return Some(Err(format!("Usage: /{} {}", info.name, info.args))),
+    };
+    return Some(Ok(command));
+}
+
+/// Returns the text to send for a message that isn't a command, i.e. with
+/// a leading `//` turned into `/`.
+pub fn unescape(text: &str) -> &str {
+    if text.starts_with("//") {
+        return &text[1..];
+    }
+    return text;
+}
+
+/// Returns help for one command, or a list of all of them.
+pub fn help(command: Option<&str>) -> Result<String, String> {
+    let usage = |c: &CommandInfo| format!("/{} {}", c.name, c.args).trim().to_string();
+    match command {
+        Some(name) => match find(name) {
+            Some(info) => Ok(format!("{} - {}", usage(info), info.help)),
+            None => Err(format!("Unknown command /{}.", name)),
+        },
+        None => {
+            let lines: Vec<String> = COMMANDS
+                .iter()
+                .map(|c| format!("{} - {}", usage(c), c.help))
+                .collect();
+            Ok(lines.join("\n"))
+        }
+    }
+}
+
+/// Names of the commands starting with `prefix`, for completion.
+pub fn names_starting_with(prefix: &str) -> Vec<&'static str> {
+    return COMMANDS
+        .iter()
+        .map(|c| c.name)
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(text: &str) -> Command {
+        return parse(text).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("hello"), None);
+        assert_eq!(parse("//etc/hosts"), None);
+        assert_eq!(unescape("//etc/hosts"), "/etc/hosts");
+        assert_eq!(
+            ok("/me waves"),
+            Command::Send {
+                text: "_waves_".to_string()
+            }
+        );
+        assert_eq!(
+            ok("/msg @bob see\nyou"),
+            Command::Msg {
+                user: "bob".to_string(),
+                text: "see\nyou".to_string()
+            }
+        );
+        assert_eq!(
+            ok("/explode 10m secret"),
+            Command::Explode {
+                lifetime: "10m".to_string(),
+                text: "secret".to_string()
+            }
+        );
+        assert_eq!(ok("/leave"), Command::Leave { channel: None });
+        assert_eq!(
+            ok("/help /msg"),
+            Command::Help {
+                command: Some("msg".to_string())
+            }
+        );
+        assert_eq!(
+            ok("/shrug ok"),
+            Command::Send {
+                text: "ok ¯\\_(ツ)_/¯".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse("/frobnicate now"),
+            Some(Err(
+                "Unknown command /frobnicate. Type /help for a list.".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("/explode soon secret"),
+            Some(Err("Usage: /explode <time> <text>".to_string()))
+        );
+        assert!(parse("/join general").unwrap().is_err());
+        assert!(parse("/mute everything").unwrap().is_err());
+        assert!(parse("/me").unwrap().is_err());
+        assert!(parse("/explode 10ä hi").unwrap().is_err());
+        assert!(parse("/explode ä hi").unwrap().is_err());
+        assert!(parse("/search").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_help() {
+        assert_eq!(
+            help(Some("mute")),
+            Ok("/mute - Turns off notifications for this conversation.".to_string())
+        );
+        assert!(help(Some("nope")).is_err());
+        assert_eq!(help(None).unwrap().lines().count(), COMMANDS.len());
+    }
+
+    #[test]
+    fn test_names_starting_with() {
+        assert_eq!(names_starting_with("m"), vec!["me", "msg", "mute"]);
+        assert_eq!(names_starting_with("j"), vec!["join"]);
+        assert!(names_starting_with("x").is_empty());
+    }
+}
@@ -0,0 +1,237 @@
+//! Tab completion of the word at the end of a message.
+//!
+//! `@al` completes to users, `#gen` to channels of the team, `:thu` to emoji
+//! shortcodes and `/ex` at the start of a message to commands. With several
+//! matches the first Tab completes as far as they agree and lists them, and
+//! every further Tab puts the next one in place.
+
+use super::commands;
+use std::collections::BTreeSet;
+
+/// Shortcodes offered before any have been seen in messages.
+const EMOJI: &[&str] = &[
+    "+1",
+    "-1",
+    "100",
+    "clap",
+    "cry",
+    "eyes",
+    "fire",
+    "grin",
+    "heart",
+    "joy",
+    "laughing",
+    "ok_hand",
+    "pray",
+    "rocket",
+    "scream",
+    "slightly_smiling_face",
+    "smile",
+    "sob",
+    "sunglasses",
+    "tada",
+    "thinking_face",
+    "thumbsdown",
+    "thumbsup",
+    "wave",
+    "white_check_mark",
+    "wink",
+    "x",
+];
+
+/// What can be completed in a conversation.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Words {
+    /// Usernames, without `@`.
+    pub users: BTreeSet<String>,
+    /// Channel names within the team, without `#`.
+    pub channels: BTreeSet<String>,
+    /// Emoji shortcodes, without colons.
+    pub emoji: BTreeSet<String>,
+}
+
+impl Words {
+    pub fn new() -> Self {
+        return Words {
+            emoji: EMOJI.iter().map(|e| e.to_string()).collect(),
+            ..Words::default()
+        };
+    }
+
+    /// Adds the `:shortcodes:` used in `text`.
+    pub fn add_emoji_from(&mut self, text: &str) {
+        let mut rest = text;
+        while let Some(open) = rest.find(':') {
+            let after = &rest[open + 1..];
+            match after.find(':') {
+                Some(close) if is_shortcode(&after[..close]) => {
+                    self.emoji.insert(after[..close].to_string());
+                    rest = &after[close + 1..];
+                }
+                // The closing colon may open the next one.
+                Some(_) => rest = after,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Returns true for words like `tada` or `+1`, but not for times like the
+/// `30` in `10:30:00`.
+fn is_shortcode(s: &str) -> bool {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '+' || c == '-';
+    return s.chars().all(valid) && !s.chars().all(|c| c.is_ascii_digit());
+}
+
+/// Where repeated Tabs are in the list of matches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cycle {
+    /// Byte position of the word being completed.
+    start: usize,
+    matches: Vec<String>,
+    /// The match in place, `None` right after the first Tab.
+    index: Option<usize>,
+    /// The text as the last Tab left it.
+    completed: String,
+}
+
+/// Returns where the last word of `text` starts and what it completes to.
+fn candidates(text: &str, words: &Words) -> Option<(usize, Vec<String>)> {
+    let start = match text.rfind(char::is_whitespace) {
+        Some(pos) => pos + text[pos..].chars().next().unwrap().len_utf8(),
+        None => 0,
+    };
+    let word = &text[start..];
+    let prefixed = |prefix: &str, set: &BTreeSet<String>, suffix: &str| {
+        return set
+            .iter()
+            .filter(|w| w.starts_with(&word[1..]))
+            .map(|w| format!("{}{}{}", prefix, w, suffix))
+            .collect();
+    };
+    let matches = match word.chars().next() {
+        Some('/') if start == 0 && !word.starts_with("//") => {
+            commands::names_starting_with(&word[1..])
+                .iter()
+                .map(|name| format!("/{}", name))
+                .collect()
+        }
+        Some('@') => prefixed("@", &words.users, ""),
+        Some('#') => prefixed("#", &words.channels, ""),
+        // Short ones would match too much, and `:)` isn't a shortcode.
+        Some(':') if word.len() >= 3 && !word[1..].contains(':') => {
+            prefixed(":", &words.emoji, ":")
+        }
+        _ => return None,
+    };
+    return Some((start, matches));
+}
+
+/// Completes the last word of `text`. `cycle` is kept between calls to step
+/// through several matches. Returns the new text and, on the first Tab with
+/// several matches, the matches to show.
+pub fn complete(text: &str, words: &Words, cycle: &mut Option<Cycle>) -> (String, Vec<String>) {
+    if let Some(c) = cycle.as_mut() {
+        if c.completed == text {
+            let index = match c.index {
+                Some(index) => (index + 1) % c.matches.len(),
+                None => 0,
+            };
+            c.index = Some(index);
+            c.completed = format!("{}{}", &text[..c.start], c.matches[index]);
+            return (c.completed.clone(), vec![]);
+        }
+    }
+    *cycle = None;
+
+    let (start, matches) = match candidates(text, words) {
+        Some(found) => found,
+        None => return (text.to_string(), vec![]),
+    };
+    match matches.len() {
+        0 => return (text.to_string(), vec![]),
+        1 => return (format!("{}{} ", &text[..start], matches[0]), vec![]),
+        _ => {
+            let mut common: &str = &matches[0];
+            for m in &matches[1..] {
+                let len: usize = common
+                    .chars()
+                    .zip(m.chars())
+                    .take_while(|(a, b)| a == b)
+                    .map(|(a, _)| a.len_utf8())
+                    .sum();
+                common = &common[..len];
+            }
+            let completed = format!("{}{}", &text[..start], common);
+            *cycle = Some(Cycle {
+                start: start,
+                matches: matches.clone(),
+                index: None,
+                completed: completed.clone(),
+            });
+            return (completed, matches);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words() -> Words {
+        let mut words = Words::new();
+        words.users.insert("alice".to_string());
+        words.users.insert("albert".to_string());
+        words.users.insert("bob".to_string());
+        words.channels.insert("general".to_string());
+        return words;
+    }
+
+    #[test]
+    fn test_complete() {
+        let words = words();
+        let mut cycle = None;
+        let mut tab = |text: &str| complete(text, &words, &mut cycle);
+        assert_eq!(tab("hi @b"), ("hi @bob ".to_string(), vec![]));
+        assert_eq!(tab("see #gen"), ("see #general ".to_string(), vec![]));
+        assert_eq!(tab(":thumbsu"), (":thumbsup: ".to_string(), vec![]));
+        assert_eq!(tab("/ex"), ("/explode ".to_string(), vec![]));
+        assert_eq!(tab("no @zed"), ("no @zed".to_string(), vec![]));
+        assert_eq!(tab("a /ex"), ("a /ex".to_string(), vec![]));
+        assert_eq!(tab("smile :)"), ("smile :)".to_string(), vec![]));
+    }
+
+    #[test]
+    fn test_cycle() {
+        let words = words();
+        let mut cycle = None;
+        let matches = vec!["@albert".to_string(), "@alice".to_string()];
+        assert_eq!(
+            complete("hi @a", &words, &mut cycle),
+            ("hi @al".to_string(), matches)
+        );
+        assert_eq!(complete("hi @al", &words, &mut cycle).0, "hi @albert");
+        assert_eq!(complete("hi @albert", &words, &mut cycle).0, "hi @alice");
+        assert_eq!(complete("hi @alice", &words, &mut cycle).0, "hi @albert");
+        // Typing starts over.
+        assert_eq!(complete("hi @alic", &words, &mut cycle).0, "hi @alice ");
+        assert_eq!(cycle, None);
+
+        assert_eq!(
+            complete("/m", &words, &mut cycle),
+            (
+                "/m".to_string(),
+                vec!["/me".to_string(), "/msg".to_string(), "/mute".to_string()]
+            )
+        );
+        assert_eq!(complete("/m", &words, &mut cycle).0, "/me");
+    }
+
+    #[test]
+    fn test_emoji_from() {
+        let mut words = Words::default();
+        words.add_emoji_from("nice :party_parrot: at 10:30:00 :x: :not one:");
+        let emoji: Vec<&str> = words.emoji.iter().map(String::as_str).collect();
+        assert_eq!(emoji, vec!["party_parrot", "x"]);
+    }
+}
@@ -0,0 +1,89 @@
+//! Helpers for writing messages.
+
+use std::ops::Range;
+
+/// Returns where `new` differs from `old`, as a byte range in `new`. The
+/// range is empty if text was only deleted.
+pub fn changed_range(old: &str, new: &str) -> Range<usize> {
+    let prefix: usize = old
+        .chars()
+        .zip(new.chars())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a.len_utf8())
+        .sum();
+    // After the prefix, so the two can't overlap.
+    let suffix: usize = old[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a.len_utf8())
+        .sum();
+    return prefix..new.len() - suffix;
+}
+
+/// Wraps text pasted into a message in a code fence if it has at least
+/// `min_lines` lines. `old` and `new` are the message before and after the
+/// paste. Returns the fenced message, or `None` if nothing was pasted.
+///
+/// Typing changes one character at a time, so anything shorter than two
+/// lines is not taken as a paste. A `min_lines` of 0 turns fencing off.
+pub fn fence_paste(old: &str, new: &str, min_lines: usize) -> Option<String> {
+    if min_lines == 0 {
+        return None;
+    }
+    let range = changed_range(old, new);
+    let pasted = new[range.clone()].trim_matches('\n');
+    let lines = pasted.lines().count();
+    if lines < std::cmp::max(min_lines, 2) || pasted.contains("```") {
+        return None;
+    }
+
+    let before = &new[..range.start];
+    let after = &new[range.end..];
+    let mut fenced = before.to_string();
+    if !before.is_empty() && !before.ends_with('\n') {
+        fenced.push('\n');
+    }
+    fenced.push_str("```\n");
+    fenced.push_str(pasted);
+    fenced.push_str("\n```");
+    if !after.is_empty() && !after.starts_with('\n') {
+        fenced.push('\n');
+    }
+    fenced.push_str(after);
+    return Some(fenced);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_range() {
+        assert_eq!(changed_range("ab", "axb"), 1..2);
+        assert_eq!(changed_range("", "abc"), 0..3);
+        assert_eq!(changed_range("aa", "aaa"), 2..3);
+        assert_eq!(changed_range("abc", "ac"), 1..1);
+        assert_eq!(changed_range("a-b", "aääb"), 1..5);
+    }
+
+    #[test]
+    fn test_fence_paste() {
+        let code = "fn main() {\n}\n";
+        assert_eq!(
+            fence_paste("look: ", &format!("look: {}", code), 2),
+            Some("look: \n```\nfn main() {\n}\n```".to_string())
+        );
+        assert_eq!(
+            fence_paste("ab", &format!("a{}b", code), 2),
+            Some("a\n```\nfn main() {\n}\n```\nb".to_string())
+        );
+        // Too short, typed, already fenced or turned off.
+        assert_eq!(fence_paste("", code, 3), None);
+        assert_eq!(fence_paste("a", "a\nb\n", 3), None);
+        assert_eq!(fence_paste("a", "a\n", 1), None);
+        assert_eq!(fence_paste("", "```\na\nb\n```", 2), None);
+        assert_eq!(fence_paste("", code, 0), None);
+    }
+}
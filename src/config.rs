@@ -0,0 +1,576 @@
+//! User configuration and locations of kbchatbox's own files.
+//!
+//! Settings are read from `~/.config/kbchatbox/config.json`. Missing keys
+//! keep their defaults, so the file only needs what the user wants to change:
+//!
+//! ```json
+//! {
+//!     "keywords": ["deploy-bot"],
+//!     "scrollback_lines": 10000,
+//!     "persist_drafts": true,
+//!     "fence_paste_lines": 5,
+//!     "sent_history_length": 100,
+//!     "notifications": {
+//!         "backend": "command",
+//!         "command": "dunstify -a kbchatbox {title} {body}",
+//!         "quiet_hours": "22:00-07:00",
+//!         "rules": { "team#random": "mentions", "team#bots": "muted" }
+//!     }
+//! }
+//! ```
+
+use super::appstate::SENT_LENGTH;
+use super::textbuffer::SCROLLBACK_LENGTH;
+use chrono::NaiveTime;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Returns `$XDG_CONFIG_HOME/kbchatbox`, falling back to
+/// `~/.config/kbchatbox`.
+pub fn config_dir() -> PathBuf {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".config"),
+            None => PathBuf::from("."),
+        },
+    };
+    return base.join("kbchatbox");
+}
+
+/// Returns the path of the configuration file.
+pub fn config_file() -> PathBuf {
+    return config_dir().join("config.json");
+}
+
+/// Returns the path of the file unsent drafts are kept in.
+pub fn drafts_file() -> PathBuf {
+    return config_dir().join("drafts.json");
+}
+
+/// Reads drafts by conversation id from JSON. Anything but strings is
+/// skipped.
+pub fn drafts_from_json(v: &Value) -> HashMap<String, String> {
+    let mut drafts = HashMap::new();
+    if let Some(map) = v.as_object() {
+        for (conversation_id, text) in map {
+            if let Some(text) = text.as_str() {
+                drafts.insert(conversation_id.clone(), text.to_string());
+            }
+        }
+    }
+    return drafts;
+}
+
+/// Reads one of our JSON files. A missing or broken file gives `None`.
+fn load_json(path: &PathBuf) -> Option<Value> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return None,
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(v) => Some(v),
+        Err(err) => {
+            eprintln!("Ignoring {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Writes `v` to `path`, readable only by the user. The file is written
+/// next to it first and renamed, so a crash can't leave half of it.
+fn save_json(path: &PathBuf, v: &Value) -> Result<(), String> {
+    if let Some(dir) = path.parent() {
+        if let Err(err) = fs::create_dir_all(dir) {
+            return Err(format!("Can't create {}: {}", dir.display(), err));
+        }
+    }
+
+    let contents = match serde_json::to_string_pretty(v) {
+        Ok(contents) => contents,
+        Err(err) => return Err(err.to_string()),
+    };
+
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+    // A leftover could have other permissions.
+    let _ = fs::remove_file(&tmp);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options.open(&tmp).and_then(|mut f| {
+        f.write_all(contents.as_bytes())?;
+        return f.sync_all();
+    });
+    if let Err(err) = written.and_then(|_| fs::rename(&tmp, path)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(format!("Can't write {}: {}", path.display(), err));
+    }
+    return Ok(());
+}
+
+/// Loads the drafts saved with `save_drafts`. A missing or broken file
+/// gives no drafts.
+pub fn load_drafts() -> HashMap<String, String> {
+    match load_json(&drafts_file()) {
+        Some(v) => drafts_from_json(&v),
+        None => HashMap::new(),
+    }
+}
+
+/// Writes drafts by conversation id to the drafts file.
+pub fn save_drafts(drafts: &HashMap<String, String>) -> Result<(), String> {
+    return save_json(&drafts_file(), &json!(drafts));
+}
+
+/// Returns the path of the file sent messages are kept in for recalling.
+pub fn sent_file() -> PathBuf {
+    return config_dir().join("sent.json");
+}
+
+/// Reads sent messages by conversation id from JSON, oldest first.
+/// Anything but strings is skipped.
+pub fn sent_from_json(v: &Value) -> HashMap<String, Vec<String>> {
+    let mut sent = HashMap::new();
+    if let Some(map) = v.as_object() {
+        for (conversation_id, texts) in map {
+            let texts: Vec<String> = match texts.as_array() {
+                Some(texts) => texts
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .map(String::from)
+                    .collect(),
+                None => continue,
+            };
+            sent.insert(conversation_id.clone(), texts);
+        }
+    }
+    return sent;
+}
+
+/// Loads the messages saved with `save_sent`. A missing or broken file
+/// gives none.
+pub fn load_sent() -> HashMap<String, Vec<String>> {
+    match load_json(&sent_file()) {
+        Some(v) => sent_from_json(&v),
+        None => HashMap::new(),
+    }
+}
+
+/// Writes sent messages by conversation id to the sent file.
+pub fn save_sent(sent: &HashMap<String, Vec<String>>) -> Result<(), String> {
+    return save_json(&sent_file(), &json!(sent));
+}
+
+/// Which desktop notification backend to use.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NotifierBackend {
+    /// The freedesktop notification spec over D-Bus.
+    Dbus,
+    /// The `notify-send` command.
+    NotifySend,
+    /// A custom command, see `NotificationConfig::command`.
+    Command,
+    /// No notifications.
+    Null,
+}
+
+/// When a conversation may notify.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NotifyRule {
+    All,
+    MentionsOnly,
+    Muted,
+}
+
+impl NotifyRule {
+    pub fn parse(s: &str) -> Option<NotifyRule> {
+        match s {
+            "all" => Some(NotifyRule::All),
+            "mentions" => Some(NotifyRule::MentionsOnly),
+            "muted" => Some(NotifyRule::Muted),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotifyRule::All => "all",
+            NotifyRule::MentionsOnly => "mentions",
+            NotifyRule::Muted => "muted",
+        }
+    }
+}
+
+/// A daily time range in local time. May wrap past midnight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Parses `HH:MM-HH:MM`.
+    pub fn parse(s: &str) -> Option<QuietHours> {
+        let mut parts = s.splitn(2, '-');
+        let start = NaiveTime::parse_from_str(parts.next()?.trim(), "%H:%M").ok()?;
+        let end = NaiveTime::parse_from_str(parts.next()?.trim(), "%H:%M").ok()?;
+        return Some(QuietHours {
+            start: start,
+            end: end,
+        });
+    }
+
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            return self.start <= time && time < self.end;
+        }
+        return time >= self.start || time < self.end;
+    }
+
+    pub fn format(&self) -> String {
+        return format!(
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        );
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NotificationConfig {
+    pub backend: NotifierBackend,
+    /// Command template for `NotifierBackend::Command`. `{title}` and
+    /// `{body}` are replaced in each argument.
+    pub command: String,
+    /// Icon name passed to the notification server.
+    pub icon: String,
+    /// Rules by conversation name or id. Conversations without a rule
+    /// notify for all messages.
+    pub rules: HashMap<String, NotifyRule>,
+    pub quiet_hours: Option<QuietHours>,
+    pub do_not_disturb: bool,
+    /// Include the message text in notifications.
+    pub show_preview: bool,
+}
+
+impl NotificationConfig {
+    pub fn rule_for(&self, conversation_id: &str, conversation_name: &str) -> NotifyRule {
+        match self
+            .rules
+            .get(conversation_name)
+            .or(self.rules.get(conversation_id))
+        {
+            Some(rule) => *rule,
+            None => NotifyRule::All,
+        }
+    }
+
+    /// Sets the rule for a conversation, given by its name or id.
+    /// `NotifyRule::All` removes it.
+    pub fn set_rule(&mut self, conversation: &str, rule: NotifyRule) {
+        if conversation.is_empty() {
+            return;
+        }
+        if rule == NotifyRule::All {
+            self.rules.remove(conversation);
+        } else {
+            self.rules.insert(conversation.to_string(), rule);
+        }
+    }
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        NotificationConfig {
+            backend: NotifierBackend::Dbus,
+            command: "notify-send {title} {body}".to_string(),
+            icon: "mail-read".to_string(),
+            rules: HashMap::new(),
+            quiet_hours: None,
+            do_not_disturb: false,
+            show_preview: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub notifications: NotificationConfig,
+    /// Words that count as mentions, e.g. names of services we look after.
+    pub keywords: Vec<String>,
+    /// Lines kept in the chat view for scrolling back.
+    pub scrollback_lines: usize,
+    /// Keep unsent drafts on disk, see `drafts_file`.
+    pub persist_drafts: bool,
+    /// Pastes of at least this many lines are put in a code fence, 0 turns
+    /// it off.
+    pub fence_paste_lines: usize,
+    /// Sent messages kept per conversation for recalling them, see
+    /// `sent_file`. 0 keeps none.
+    pub sent_history_length: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            notifications: NotificationConfig::default(),
+            keywords: Vec::new(),
+            scrollback_lines: SCROLLBACK_LENGTH,
+            persist_drafts: false,
+            fence_paste_lines: 0,
+            sent_history_length: SENT_LENGTH,
+        }
+    }
+}
+
+fn read_string(v: &Value, default: &str) -> String {
+    match v.as_str() {
+        Some(s) => s.to_string(),
+        None => default.to_string(),
+    }
+}
+
+fn read_rules(v: &Value) -> HashMap<String, NotifyRule> {
+    let mut rules = HashMap::new();
+    if let Some(map) = v.as_object() {
+        for (conversation, rule) in map {
+            match rule.as_str().and_then(NotifyRule::parse) {
+                Some(rule) => {
+                    rules.insert(conversation.clone(), rule);
+                }
+                None => eprintln!("Invalid rule for {}: {}", conversation, rule),
+            }
+        }
+    }
+    return rules;
+}
+
+impl Config {
+    /// Loads the configuration file. A missing or broken file gives the
+    /// defaults.
+    pub fn load() -> Config {
+        match load_json(&config_file()) {
+            Some(v) => Config::from_json(&v),
+            None => Config::default(),
+        }
+    }
+
+    /// Builds a configuration from JSON, using defaults for missing keys.
+    pub fn from_json(v: &Value) -> Config {
+        let defaults = NotificationConfig::default();
+        let n = &v["notifications"];
+        let backend = match n["backend"].as_str() {
+            Some("dbus") | None => NotifierBackend::Dbus,
+            Some("notify-send") => NotifierBackend::NotifySend,
+            Some("command") => NotifierBackend::Command,
+            Some("none") => NotifierBackend::Null,
+            Some(other) => {
+                eprintln!("Unknown notification backend: {}", other);
+                defaults.backend.clone()
+            }
+        };
+
+        let keywords = match v["keywords"].as_array() {
+            Some(keywords) => keywords
+                .iter()
+                .filter_map(|k| k.as_str())
+                .map(String::from)
+                .collect(),
+            None => Vec::new(),
+        };
+
+        return Config {
+            keywords: keywords,
+            scrollback_lines: match v["scrollback_lines"].as_u64() {
+                Some(lines) if lines > 0 => lines as usize,
+                _ => SCROLLBACK_LENGTH,
+            },
+            persist_drafts: v["persist_drafts"].as_bool().unwrap_or(false),
+            fence_paste_lines: v["fence_paste_lines"].as_u64().unwrap_or(0) as usize,
+            sent_history_length: match v["sent_history_length"].as_u64() {
+                Some(length) => length as usize,
+                None => SENT_LENGTH,
+            },
+            notifications: NotificationConfig {
+                backend: backend,
+                command: read_string(&n["command"], &defaults.command),
+                icon: read_string(&n["icon"], &defaults.icon),
+                rules: read_rules(&n["rules"]),
+                quiet_hours: match n["quiet_hours"].as_str() {
+                    Some(s) => match QuietHours::parse(s) {
+                        Some(quiet_hours) => Some(quiet_hours),
+                        None => {
+                            eprintln!("Invalid quiet_hours: {}", s);
+                            None
+                        }
+                    },
+                    None => None,
+                },
+                do_not_disturb: n["do_not_disturb"].as_bool().unwrap_or(false),
+                show_preview: n["show_preview"].as_bool().unwrap_or(defaults.show_preview),
+            },
+        };
+    }
+
+    /// Returns the configuration as JSON.
+    pub fn to_json(&self) -> Value {
+        let backend = match self.notifications.backend {
+            NotifierBackend::Dbus => "dbus",
+            NotifierBackend::NotifySend => "notify-send",
+            NotifierBackend::Command => "command",
+            NotifierBackend::Null => "none",
+        };
+        let mut rules = Map::new();
+        for (conversation, rule) in &self.notifications.rules {
+            rules.insert(conversation.clone(), json!(rule.as_str()));
+        }
+        let quiet_hours = match self.notifications.quiet_hours {
+            Some(ref quiet_hours) => json!(quiet_hours.format()),
+            None => Value::Null,
+        };
+        json!({
+            "notifications": {
+                "backend": backend,
+                "command": self.notifications.command,
+                "icon": self.notifications.icon,
+                "rules": rules,
+                "quiet_hours": quiet_hours,
+                "do_not_disturb": self.notifications.do_not_disturb,
+                "show_preview": self.notifications.show_preview,
+            },
+            "keywords": self.keywords,
+            "scrollback_lines": self.scrollback_lines,
+            "persist_drafts": self.persist_drafts,
+            "fence_paste_lines": self.fence_paste_lines,
+            "sent_history_length": self.sent_history_length,
+        })
+    }
+
+    /// Writes the configuration file.
+    pub fn save(&self) -> Result<(), String> {
+        return save_json(&config_file(), &self.to_json());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_hours() {
+        let t = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+
+        let night = QuietHours::parse("22:00-07:30").unwrap();
+        assert!(night.contains(t(23, 0)));
+        assert!(night.contains(t(3, 0)));
+        assert!(!night.contains(t(7, 30)));
+        assert!(!night.contains(t(12, 0)));
+        assert_eq!(night.format(), "22:00-07:30");
+
+        let lunch = QuietHours::parse("12:00 - 13:00").unwrap();
+        assert!(lunch.contains(t(12, 30)));
+        assert!(!lunch.contains(t(13, 30)));
+
+        assert_eq!(QuietHours::parse("22:00"), None);
+        assert_eq!(QuietHours::parse("25:00-07:00"), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let v = json!({
+            "notifications": {
+                "backend": "none",
+                "rules": { "team#random": "mentions", "bots": "muted", "x": "bogus" },
+                "quiet_hours": "22:00-07:00",
+                "do_not_disturb": true,
+            },
+            "keywords": ["deploy-bot"],
+            "scrollback_lines": 500,
+            "persist_drafts": true,
+            "fence_paste_lines": 5,
+            "sent_history_length": 0,
+        });
+        let config = Config::from_json(&v);
+        let n = &config.notifications;
+        assert_eq!(n.backend, NotifierBackend::Null);
+        assert_eq!(n.rule_for("c1", "team#random"), NotifyRule::MentionsOnly);
+        assert_eq!(n.rule_for("bots", "alice,me"), NotifyRule::Muted);
+        assert_eq!(n.rule_for("c2", "x"), NotifyRule::All);
+        assert!(n.do_not_disturb);
+
+        let again = Config::from_json(&config.to_json());
+        assert_eq!(again.notifications.rules, n.rules);
+        assert_eq!(again.notifications.quiet_hours, n.quiet_hours);
+        assert_eq!(again.notifications.icon, "mail-read");
+        assert_eq!(again.keywords, vec!["deploy-bot"]);
+        assert_eq!(again.scrollback_lines, 500);
+        assert!(again.persist_drafts);
+        assert_eq!(again.fence_paste_lines, 5);
+        assert_eq!(again.sent_history_length, 0);
+        assert!(!Config::from_json(&json!({})).persist_drafts);
+        assert_eq!(
+            Config::from_json(&json!({})).scrollback_lines,
+            SCROLLBACK_LENGTH
+        );
+    }
+
+    #[test]
+    fn test_set_rule() {
+        let mut n = NotificationConfig::default();
+        // Rules need a conversation to apply to.
+        n.set_rule("", NotifyRule::Muted);
+        assert!(n.rules.is_empty());
+        // Conversations without a known name are muted by id.
+        n.set_rule("c1", NotifyRule::Muted);
+        assert_eq!(n.rule_for("c1", ""), NotifyRule::Muted);
+        n.set_rule("c1", NotifyRule::All);
+        assert!(n.rules.is_empty());
+    }
+
+    #[test]
+    fn test_drafts() {
+        let v = json!({ "c1": "half a message", "c2": 5 });
+        let drafts = drafts_from_json(&v);
+        assert_eq!(drafts.len(), 1);
+        assert_eq!(drafts["c1"], "half a message");
+        assert_eq!(drafts_from_json(&json!(drafts)), drafts);
+    }
+
+    #[test]
+    fn test_sent() {
+        let v = json!({ "c1": ["first", 2, "second"], "c2": "not a list" });
+        let sent = sent_from_json(&v);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent["c1"], vec!["first", "second"]);
+        assert_eq!(sent_from_json(&json!(sent)), sent);
+    }
+
+    #[test]
+    fn test_save_json() {
+        let dir = env::temp_dir().join(format!("kbchatbox-test-{}", std::process::id()));
+        let path = dir.join("sent.json");
+        let v = json!({ "c1": ["hi"] });
+        save_json(&path, &v).unwrap();
+        save_json(&path, &v).unwrap();
+        assert_eq!(load_json(&path), Some(v));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
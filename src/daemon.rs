@@ -0,0 +1,324 @@
+//! Local HTTP and WebSocket API for other frontends and tools.
+//!
+//! Every request needs the token from the token file, either as an
+//! `Authorization: Bearer <token>` header or as a `?token=` parameter.
+//!
+//! - `GET /conversations` lists conversations.
+//! - `GET /conversations/<id>/messages?n=25` returns history, oldest first.
+//! - `POST /conversations/<id>/messages` sends the body, either plain text or
+//!   JSON like `{"text": "hello"}`.
+//! - `GET /events` upgrades to a WebSocket streaming every incoming
+//!   `KeybaseReply` as JSON.
+
+use super::token::{load_or_create_token, token_matches};
+use kbchatbox::hub::Hub;
+use kbchatbox::{Keybase, KeybaseReply};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::thread;
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+pub const DEFAULT_PORT: u16 = 8675;
+
+const DEFAULT_READ_COUNT: usize = 25;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    // Header names are lowercase.
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.map(|h| u8::from_str_radix(h, 16))) {
+            (b'%', Some(Ok(b))) => {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => out.push(b' '),
+            (b, _) => out.push(b),
+        }
+        i += 1;
+    }
+    return String::from_utf8_lossy(&out).to_string();
+}
+
+fn read_request(reader: &mut BufReader<TcpStream>) -> Result<Request, String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).is_err() || line.is_empty() {
+        return Err("Connection closed".to_string());
+    }
+
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("").to_string();
+    if method.is_empty() || !target.starts_with('/') {
+        return Err("Malformed request line".to_string());
+    }
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).is_err() {
+            return Err("Connection closed".to_string());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADERS {
+            return Err("Too many headers".to_string());
+        }
+        if let Some(pos) = line.find(':') {
+            let name = line[..pos].trim().to_ascii_lowercase();
+            headers.insert(name, line[pos + 1..].trim().to_string());
+        }
+    }
+
+    let length: usize = match headers.get("content-length") {
+        Some(length) => match length.parse() {
+            Ok(length) => length,
+            Err(_) => return Err("Invalid Content-Length".to_string()),
+        },
+        None => 0,
+    };
+    if length > MAX_BODY_BYTES {
+        return Err("Body too large".to_string());
+    }
+    let mut body = vec![0; length];
+    if reader.read_exact(&mut body).is_err() {
+        return Err("Connection closed".to_string());
+    }
+
+    let (path, query_str) = match target.find('?') {
+        Some(pos) => (&target[..pos], &target[pos + 1..]),
+        None => (&target[..], ""),
+    };
+    let mut query = HashMap::new();
+    for pair in query_str.split('&').filter(|p| !p.is_empty()) {
+        let mut kv = pair.splitn(2, '=');
+        let key = percent_decode(kv.next().unwrap_or(""));
+        let value = percent_decode(kv.next().unwrap_or(""));
+        query.insert(key, value);
+    }
+
+    return Ok(Request {
+        method: method,
+        path: path.to_string(),
+        query: query,
+        headers: headers,
+        body: body,
+    });
+}
+
+fn respond(stream: &mut TcpStream, status: u16, reason: &str, body: &Value) {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        eprintln!("Writing HTTP response failed: {}", err);
+    }
+}
+
+fn respond_error(stream: &mut TcpStream, status: u16, reason: &str, error: &str) {
+    respond(stream, status, reason, &json!({ "error": error }));
+}
+
+fn is_authorized(req: &Request, token: &str) -> bool {
+    let given = match req.headers.get("authorization") {
+        Some(auth) if auth.starts_with("Bearer ") => auth[7..].trim(),
+        _ => match req.query.get("token") {
+            Some(token) => token.as_str(),
+            None => return false,
+        },
+    };
+    return token_matches(given, token);
+}
+
+fn handle_list(stream: &mut TcpStream, hub: &Hub) {
+    match hub.call(Keybase::create_list_channels_req()) {
+        Ok(KeybaseReply::ChannelListReply { channels }) => {
+            let channels: Vec<Value> = channels.iter().map(|c| c.to_json()).collect();
+            respond(stream, 200, "OK", &json!(channels));
+        }
+        Ok(_) => respond_error(stream, 502, "Bad Gateway", "Unexpected reply to list"),
+        Err(err) => respond_error(stream, 502, "Bad Gateway", &err),
+    }
+}
+
+fn handle_read(stream: &mut TcpStream, hub: &Hub, conversation_id: &str, req: &Request) {
+    let count = match req.query.get("n") {
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => n,
+            _ => return respond_error(stream, 400, "Bad Request", "n must be positive"),
+        },
+        None => DEFAULT_READ_COUNT,
+    };
+
+    match hub.call(Keybase::create_read_conversation_req(
+        conversation_id,
+        count,
+    )) {
+        Ok(KeybaseReply::ChatMsgListReply { msgs }) => {
+            let msgs: Vec<Value> = msgs.iter().rev().map(|m| m.to_json()).collect();
+            respond(stream, 200, "OK", &json!(msgs));
+        }
+        Ok(_) => respond_error(stream, 502, "Bad Gateway", "Unexpected reply to read"),
+        Err(err) => respond_error(stream, 502, "Bad Gateway", &err),
+    }
+}
+
+fn handle_send(stream: &mut TcpStream, hub: &Hub, conversation_id: &str, req: &Request) {
+    let body = String::from_utf8_lossy(&req.body).to_string();
+    let is_json = match req.headers.get("content-type") {
+        Some(content_type) => content_type.starts_with("application/json"),
+        None => false,
+    };
+
+    let text = if is_json {
+        match serde_json::from_str::<Value>(&body) {
+            Ok(v) => v["text"].as_str().unwrap_or("").to_string(),
+            Err(_) => return respond_error(stream, 400, "Bad Request", "Invalid JSON"),
+        }
+    } else {
+        body
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        return respond_error(stream, 400, "Bad Request", "Empty message");
+    }
+
+    match hub.call(Keybase::create_msg_req(conversation_id, text)) {
+        Ok(KeybaseReply::SendReply { id }) => respond(stream, 200, "OK", &json!({ "id": id })),
+        Ok(_) => respond_error(stream, 502, "Bad Gateway", "Unexpected reply to send"),
+        Err(err) => respond_error(stream, 502, "Bad Gateway", &err),
+    }
+}
+
+fn handle_events(mut stream: TcpStream, hub: &Hub, req: &Request) {
+    let is_upgrade = match req.headers.get("upgrade") {
+        Some(upgrade) => upgrade.eq_ignore_ascii_case("websocket"),
+        None => false,
+    };
+    let key = match req.headers.get("sec-websocket-key") {
+        Some(key) if is_upgrade => key.clone(),
+        _ => {
+            return respond_error(
+                &mut stream,
+                426,
+                "Upgrade Required",
+                "Use a WebSocket client",
+            )
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    if stream.write_all(response.as_bytes()).is_err() {
+        return;
+    }
+
+    let events = hub.subscribe();
+    let mut ws = WebSocket::from_raw_socket(stream, Role::Server, None);
+    for reply in events.iter() {
+        if ws.send(Message::Text(reply.to_json().to_string())).is_err() {
+            break;
+        }
+    }
+    eprintln!("WebSocket client disconnected.");
+}
+
+fn handle_connection(stream: TcpStream, hub: Hub, token: &str) {
+    let mut reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(err) => {
+            eprintln!("Can't clone client socket: {}", err);
+            return;
+        }
+    };
+    let mut stream = stream;
+
+    let req = match read_request(&mut reader) {
+        Ok(req) => req,
+        Err(err) => return respond_error(&mut stream, 400, "Bad Request", &err),
+    };
+
+    if !is_authorized(&req, token) {
+        return respond_error(&mut stream, 401, "Unauthorized", "Invalid token");
+    }
+
+    let segments: Vec<String> = req
+        .path
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["conversations"]) => handle_list(&mut stream, &hub),
+        ("GET", ["conversations", id, "messages"]) => handle_read(&mut stream, &hub, id, &req),
+        ("POST", ["conversations", id, "messages"]) => handle_send(&mut stream, &hub, id, &req),
+        ("GET", ["events"]) => handle_events(stream, &hub, &req),
+        _ => respond_error(&mut stream, 404, "Not Found", "No such endpoint"),
+    }
+}
+
+/// Runs the API on `127.0.0.1:port` until the process is killed.
+pub fn run(port: u16, token_file: &Path) -> Result<(), String> {
+    let token = load_or_create_token(token_file)?;
+    let hub = Hub::new(Keybase::new());
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => return Err(format!("Can't listen on port {}: {}", port, err)),
+    };
+    eprintln!("HTTP API listening on 127.0.0.1:{}", port);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let hub = hub.clone();
+                let token = token.clone();
+                thread::spawn(move || handle_connection(stream, hub, &token));
+            }
+            Err(err) => eprintln!("Accepting HTTP client failed: {}", err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("team%23general"), "team#general");
+        assert_eq!(percent_decode("a+b%2"), "a b%2");
+        assert_eq!(percent_decode("%e2%9c%93"), "\u{2713}");
+    }
+}
@@ -0,0 +1,184 @@
+//! D-Bus service for desktop integration.
+//!
+//! Exports `io.github.ndob.Kbchatbox` on the session bus so that other apps
+//! and keybindings can talk to a running kbchatbox:
+//!
+//! ```text
+//! busctl --user call io.github.ndob.Kbchatbox /io/github/ndob/Kbchatbox \
+//!     io.github.ndob.Kbchatbox OpenConversation s team#general
+//! ```
+
+use super::gui::{SharedState, ThreadSafeState};
+use kbchatbox::{Channel, ChatMsg};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::{dbus_interface, fdo, SignalContext};
+
+pub const SERVICE_NAME: &str = "io.github.ndob.Kbchatbox";
+pub const OBJECT_PATH: &str = "/io/github/ndob/Kbchatbox";
+
+/// Requests that have to be carried out on the UI thread.
+pub enum ServiceCommand {
+    OpenConversation {
+        conversation_id: String,
+    },
+    /// Sends `text` through the outbox like a message typed in the window.
+    Send {
+        conversation_id: String,
+        text: String,
+    },
+}
+
+/// Finds a conversation by name or id.
+fn find_channel<'a>(state: &'a SharedState, name_or_id: &str) -> Option<&'a Channel> {
+    return state
+        .app
+        .channels()
+        .iter()
+        .find(|c| c.id == name_or_id || c.name == name_or_id);
+}
+
+struct KbchatboxService {
+    state: ThreadSafeState,
+    commands: Mutex<Sender<ServiceCommand>>,
+}
+
+impl KbchatboxService {
+    fn resolve(&self, conversation: &str) -> fdo::Result<String> {
+        match find_channel(&self.state.lock().unwrap(), conversation) {
+            Some(chan) => Ok(chan.id.clone()),
+            None => Err(fdo::Error::InvalidArgs(format!(
+                "No such conversation: {}",
+                conversation
+            ))),
+        }
+    }
+
+    /// Hands `command` to the UI thread.
+    fn run(&self, command: ServiceCommand) -> fdo::Result<()> {
+        match self.commands.lock().unwrap().send(command) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(fdo::Error::Failed("UI is not running".to_string())),
+        }
+    }
+}
+
+#[dbus_interface(name = "io.github.ndob.Kbchatbox")]
+impl KbchatboxService {
+    /// Shows a conversation, given by name or id, in the chat view.
+    fn open_conversation(&self, conversation: &str) -> fdo::Result<()> {
+        let conversation_id = self.resolve(conversation)?;
+        return self.run(ServiceCommand::OpenConversation {
+            conversation_id: conversation_id,
+        });
+    }
+
+    /// Sends `text` to a conversation given by name or id.
+    fn send_message(&self, conversation: &str, text: &str) -> fdo::Result<()> {
+        let conversation_id = self.resolve(conversation)?;
+        return self.run(ServiceCommand::Send {
+            conversation_id: conversation_id,
+            text: text.to_string(),
+        });
+    }
+
+    /// Unread message counts by conversation name. Conversations without
+    /// unread messages are left out.
+    fn unread_counts(&self) -> HashMap<String, u32> {
+        let state = self.state.lock().unwrap();
+        let mut counts = HashMap::new();
+        for chan in state.app.channels() {
+            if let Some(count) = state.app.unread().get(&chan.id) {
+                counts.insert(chan.name.clone(), *count);
+            }
+        }
+        return counts;
+    }
+
+    /// All conversations as (name, id, unread count).
+    fn list_conversations(&self) -> Vec<(String, String, u32)> {
+        let state = self.state.lock().unwrap();
+        return state
+            .app
+            .channels()
+            .iter()
+            .map(|c| {
+                let unread = state.app.unread_count(&c.id);
+                (c.name.clone(), c.id.clone(), unread)
+            })
+            .collect();
+    }
+
+    /// Emitted for every incoming message.
+    #[dbus_interface(signal)]
+    async fn message_received(
+        ctxt: &SignalContext<'_>,
+        conversation_id: &str,
+        conversation_name: &str,
+        sender: &str,
+        text: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// The exported service. Stays registered until dropped.
+pub struct Service {
+    connection: Connection,
+}
+
+impl Service {
+    /// Registers the service on the session bus. `commands` receives the
+    /// requests that need the UI thread.
+    pub fn start(
+        state: ThreadSafeState,
+        commands: Sender<ServiceCommand>,
+    ) -> Result<Service, String> {
+        let service = KbchatboxService {
+            state: state,
+            commands: Mutex::new(commands),
+        };
+
+        let connection = ConnectionBuilder::session()
+            .and_then(|b| b.name(SERVICE_NAME))
+            .and_then(|b| b.serve_at(OBJECT_PATH, service))
+            .and_then(|b| b.build());
+        match connection {
+            Ok(connection) => Ok(Service {
+                connection: connection,
+            }),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// Emits `MessageReceived` for `msg`.
+    pub fn emit_message_received(&self, msg: &ChatMsg, state: &ThreadSafeState) {
+        let conversation_name = match find_channel(&state.lock().unwrap(), &msg.conversation_id) {
+            Some(chan) => chan.name.clone(),
+            None => String::new(),
+        };
+
+        let iface = match self
+            .connection
+            .object_server()
+            .interface::<_, KbchatboxService>(OBJECT_PATH)
+        {
+            Ok(iface) => iface,
+            Err(err) => {
+                eprintln!("D-Bus interface lookup failed: {}", err);
+                return;
+            }
+        };
+
+        let res = zbus::block_on(KbchatboxService::message_received(
+            iface.signal_context(),
+            &msg.conversation_id,
+            &conversation_name,
+            &msg.sender,
+            &msg.text,
+        ));
+        if let Err(err) = res {
+            eprintln!("Emitting D-Bus signal failed: {}", err);
+        }
+    }
+}
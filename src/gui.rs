@@ -0,0 +1,1090 @@
+//! The libui front end.
+
+use super::chatview::ChatView;
+#[cfg(feature = "dbus")]
+use super::dbus;
+use super::libui::{self, EntryKey};
+use chrono::Local;
+use iui::controls::*;
+use iui::menus::{Menu, MenuItem};
+use iui::prelude::*;
+use kbchatbox::appstate::{
+    AppState, ConnectionStatus, Delivery, Effect, Outgoing, OutgoingKind, UserAction,
+};
+use kbchatbox::config::{self, Config, NotifyRule};
+use kbchatbox::markdown::{Span, Style};
+use kbchatbox::mentions::{MentionHit, MentionLog, MentionMatcher};
+use kbchatbox::notification::{self, chat_notification, Coalescer, NotificationEvent, Notifier};
+use kbchatbox::policy::{Decision, NotificationPolicy, ViewState};
+use kbchatbox::textbuffer::{FoundLink, StyledLine};
+use kbchatbox::{Channel, ChatMsg, Keybase, KeybaseReply, KeybaseRequest, TextBuffer};
+use std::process::{self, Command};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Conversation state shared with UI callbacks and the D-Bus service.
+#[derive(Default)]
+pub struct SharedState {
+    /// Conversations, their messages and drafts.
+    pub app: AppState,
+    /// Finds mentions of us, knows our username.
+    pub mentions: MentionMatcher,
+    pub config: Config,
+    /// Messages fetched when a conversation is opened, enough to fill the
+    /// chat view.
+    pub fetch_count: usize,
+}
+
+impl SharedState {
+    /// Returns the name of a conversation, or "" if it is not known.
+    pub fn conversation_name(&self, conversation_id: &str) -> &str {
+        return self.app.conversation_name(conversation_id);
+    }
+
+    /// Returns the key notification rules of a conversation are saved under:
+    /// its name, or its id while the name is not known.
+    fn rule_key(&self, conversation_id: &str) -> String {
+        let name = self.conversation_name(conversation_id);
+        if name.is_empty() {
+            return conversation_id.to_string();
+        }
+        return name.to_string();
+    }
+
+    /// Returns the window title with the number of unread messages in
+    /// conversations that are not muted.
+    fn window_title(&self) -> String {
+        let notifications = &self.config.notifications;
+        let count: u32 = self
+            .app
+            .unread()
+            .iter()
+            .filter(|(id, _)| {
+                notifications.rule_for(id, self.conversation_name(id)) != NotifyRule::Muted
+            })
+            .map(|(_, count)| count)
+            .sum();
+        let title = match self.app.status() {
+            ConnectionStatus::Reconnecting(_) => format!("{} - reconnecting", WINDOW_TITLE),
+            _ => WINDOW_TITLE.to_string(),
+        };
+        if count == 0 {
+            return title;
+        }
+        return format!("{} ({})", title, count);
+    }
+}
+
+pub type ThreadSafeState = Arc<Mutex<SharedState>>;
+
+/// Text buffer size before a view has been drawn and knows its size.
+const TEXTBUF_WIDTH: usize = 100;
+const TEXTBUF_HEIGHT: usize = 25;
+const WINDOW_TITLE: &str = "kbchatbox";
+
+fn safe_send(tx: &Sender<KeybaseRequest>, req: KeybaseRequest) {
+    match tx.send(req) {
+        Ok(_) => {}
+        Err(err) => {
+            eprintln!("Error sending: {}", err);
+        }
+    }
+}
+
+/// Returns a channel whose receiver is drained by the event loop. Other
+/// threads only know an mpsc sender, so every item is forwarded and the
+/// loop woken up.
+fn waking_channel<T: Send + 'static>() -> (Sender<T>, Receiver<T>) {
+    let (tx, forward_rx) = mpsc::channel();
+    let (forward_tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for item in forward_rx.iter() {
+            if forward_tx.send(item).is_err() {
+                break;
+            }
+            libui::wake_main();
+        }
+    });
+    return (tx, rx);
+}
+
+/// Hands what the user did to the event loop.
+fn send_action(actions: &Sender<UserAction>, action: UserAction) {
+    match actions.send(action) {
+        Ok(_) => libui::wake_main(),
+        Err(err) => {
+            eprintln!("Error sending: {}", err);
+        }
+    }
+}
+
+/// Returns true if our window is the active one. libui has no focus events,
+/// so this asks `xdotool`. Unknown counts as not focused.
+fn window_has_focus() -> bool {
+    let output = Command::new("xdotool")
+        .arg("getactivewindow")
+        .arg("getwindowname")
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).starts_with(WINDOW_TITLE)
+        }
+        _ => false,
+    }
+}
+
+/// Brings our window to the front with `xdotool`.
+fn raise_window() {
+    let res = Command::new("xdotool")
+        .arg("search")
+        .arg("--onlyvisible")
+        .arg("--pid")
+        .arg(process::id().to_string())
+        .arg("windowactivate")
+        .status();
+    if let Err(err) = res {
+        eprintln!("Couldn't raise window: {}", err);
+    }
+}
+
+/// Work for the notification thread. Checking the focus and showing a
+/// notification may block, so they are kept off the UI thread.
+enum NotificationJob {
+    Show {
+        msg: ChatMsg,
+        conversation_name: String,
+        show_preview: bool,
+        /// Skip it if the window has focus, the message is in the open
+        /// conversation.
+        check_focus: bool,
+    },
+    /// The conversation was opened, its messages are counted anew.
+    Reset { conversation_id: String },
+}
+
+fn spawn_notification_thread(notifier: Box<dyn Notifier>) -> Sender<NotificationJob> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut coalescer = Coalescer::new();
+        for job in rx.iter() {
+            match job {
+                NotificationJob::Show {
+                    msg,
+                    conversation_name,
+                    show_preview,
+                    check_focus,
+                } => {
+                    if check_focus && window_has_focus() {
+                        continue;
+                    }
+                    let count = coalescer.add(&msg.conversation_id, Local::now().timestamp());
+                    // Without replacing, one popup per burst is enough.
+                    if count > 1 && !notifier.replaces() {
+                        continue;
+                    }
+
+                    let notification =
+                        chat_notification(&msg, &conversation_name, count, show_preview);
+                    if let Err(err) = notifier.notify(&notification) {
+                        eprintln!("Notification failed: {}", err);
+                    }
+                }
+                NotificationJob::Reset { conversation_id } => coalescer.reset(&conversation_id),
+            }
+        }
+    });
+    return tx;
+}
+
+fn notify_chat_msg(msg: &ChatMsg, state: &ThreadSafeState, jobs: &Sender<NotificationJob>) {
+    let locked = state.lock().unwrap();
+    let conversation_name = locked.conversation_name(&msg.conversation_id);
+    let current_conversation_id = locked.app.current_conversation_id();
+    // The notification thread checks the focus, it spawns a process.
+    let view = ViewState {
+        current_conversation_id: current_conversation_id,
+        conversation_name: conversation_name,
+        window_focused: false,
+    };
+
+    let settings = &locked.config.notifications;
+    let policy = NotificationPolicy::new(&locked.mentions, settings);
+    if policy.decide(msg, &view, Local::now().time()) == Decision::Notify {
+        let _ = jobs.send(NotificationJob::Show {
+            msg: msg.clone(),
+            conversation_name: conversation_name.to_string(),
+            show_preview: settings.show_preview,
+            check_focus: msg.conversation_id == current_conversation_id,
+        });
+    }
+}
+
+/// Turns a click on a notification into what the user wants done.
+fn notification_action(event: NotificationEvent) -> UserAction {
+    match event {
+        NotificationEvent::Open { tag } => {
+            raise_window();
+            return UserAction::OpenConversation {
+                conversation_id: tag,
+            };
+        }
+        NotificationEvent::Reply { tag, text } => {
+            return UserAction::Send {
+                conversation_id: tag,
+                text: text,
+            };
+        }
+    }
+}
+
+fn save_config(state: &SharedState) {
+    if let Err(err) = state.config.save() {
+        eprintln!("Saving settings failed: {}", err);
+    }
+}
+
+/// The Notifications menu. The rule items apply to the open conversation.
+#[derive(Clone)]
+struct NotificationMenu {
+    do_not_disturb: MenuItem,
+    rules: Vec<(NotifyRule, MenuItem)>,
+}
+
+impl NotificationMenu {
+    /// Creates the menu. Has to be called before the window is created.
+    fn new(ui: &UI) -> Self {
+        let menu = Menu::new(ui, "Notifications");
+        let do_not_disturb = menu.append_check_item("Do not disturb");
+        menu.append_separator();
+        let rules = vec![
+            (NotifyRule::All, menu.append_check_item("All messages")),
+            (
+                NotifyRule::MentionsOnly,
+                menu.append_check_item("Mentions only"),
+            ),
+            (NotifyRule::Muted, menu.append_check_item("Muted")),
+        ];
+        NotificationMenu {
+            do_not_disturb: do_not_disturb,
+            rules: rules,
+        }
+    }
+
+    fn connect(&self, ui: &UI, state: &ThreadSafeState) {
+        self.do_not_disturb.on_clicked(ui, {
+            let ui = ui.clone();
+            let state = Arc::clone(state);
+            move |item, _win| {
+                let mut locked = state.lock().unwrap();
+                locked.config.notifications.do_not_disturb = item.checked(&ui);
+                save_config(&locked);
+            }
+        });
+
+        for (rule, item) in &self.rules {
+            let rule = *rule;
+            item.on_clicked(ui, {
+                let ui = ui.clone();
+                let state = Arc::clone(state);
+                let menu = self.clone();
+                move |_item, _win| {
+                    let mut locked = state.lock().unwrap();
+                    let current = locked.app.current_conversation_id();
+                    if !current.is_empty() {
+                        let key = locked.rule_key(current);
+                        locked.config.notifications.set_rule(&key, rule);
+                        save_config(&locked);
+                    }
+                    menu.sync(&ui, &locked);
+                }
+            });
+        }
+    }
+
+    /// Updates the check marks from the settings.
+    fn sync(&self, ui: &UI, state: &SharedState) {
+        let notifications = &state.config.notifications;
+        self.do_not_disturb
+            .set_checked(ui, notifications.do_not_disturb);
+
+        let current = state.app.current_conversation_id();
+        for (rule, item) in &self.rules {
+            let checked = !current.is_empty()
+                && notifications.rule_for(current, state.conversation_name(current)) == *rule;
+            item.set_checked(ui, checked);
+        }
+    }
+}
+
+/// A window listing recent mentions from all conversations.
+struct MentionsView {
+    window: Window,
+    label: Label,
+    log: MentionLog,
+    text_buf: TextBuffer,
+}
+
+impl MentionsView {
+    fn new(ui: &UI) -> Self {
+        let mut window = Window::new(
+            ui,
+            &format!("Mentions - {}", WINDOW_TITLE),
+            640,
+            320,
+            WindowType::NoMenubar,
+        );
+        window.on_closing(ui, {
+            let ui = ui.clone();
+            move |window| window.hide(&ui)
+        });
+
+        let mut text_buf = TextBuffer::new(TEXTBUF_WIDTH, TEXTBUF_HEIGHT);
+        text_buf.append("No mentions yet.");
+        let label = Label::new(ui, &text_buf.get_newest_formatted());
+        let mut vbox = VerticalBox::new(ui);
+        vbox.set_padded(ui, true);
+        vbox.append(ui, label.clone(), LayoutStrategy::Compact);
+        window.set_child(ui, vbox);
+        window.set_margined(ui, true);
+
+        MentionsView {
+            window: window,
+            label: label,
+            log: MentionLog::new(),
+            text_buf: text_buf,
+        }
+    }
+
+    /// Records `msg` if it mentions us.
+    fn check(&mut self, msg: &ChatMsg, state: &ThreadSafeState, ui: &UI) {
+        let locked = state.lock().unwrap();
+        let mention = match locked.mentions.find(msg) {
+            Some(mention) => mention,
+            None => return,
+        };
+        self.log.push(MentionHit {
+            conversation_name: locked.conversation_name(&msg.conversation_id).to_string(),
+            msg: msg.clone(),
+            mention: mention,
+        });
+
+        self.text_buf.clear();
+        for hit in self.log.hits() {
+            self.text_buf.append(&hit.format());
+        }
+        self.label
+            .set_text(ui, &self.text_buf.get_newest_formatted());
+    }
+}
+
+/// A window listing the links in the open conversation with their senders
+/// and times. Clicking one opens it.
+struct LinksView {
+    window: Window,
+    view: ChatView,
+    shown: Vec<FoundLink>,
+}
+
+impl LinksView {
+    fn new(ui: &UI) -> Self {
+        let mut window = Window::new(
+            ui,
+            &format!("Links in this conversation - {}", WINDOW_TITLE),
+            640,
+            320,
+            WindowType::NoMenubar,
+        );
+        window.on_closing(ui, {
+            let ui = ui.clone();
+            move |window| window.hide(&ui)
+        });
+
+        let mut text_buf = TextBuffer::new(TEXTBUF_WIDTH, TEXTBUF_HEIGHT);
+        text_buf.append("No links yet.");
+        let view = ChatView::new(text_buf);
+        let mut vbox = VerticalBox::new(ui);
+        vbox.append(ui, view.area(), LayoutStrategy::Stretchy);
+        window.set_child(ui, vbox);
+        window.set_margined(ui, true);
+
+        LinksView {
+            window: window,
+            view: view,
+            shown: Vec::new(),
+        }
+    }
+
+    /// Shows the links of `chat_view` if they have changed.
+    fn refresh(&mut self, chat_view: &ChatView) {
+        let links = chat_view.links();
+        if links == self.shown {
+            return;
+        }
+        self.view.clear_selection();
+        self.view.update(|text_buf| {
+            text_buf.clear();
+            if links.is_empty() {
+                text_buf.append("No links yet.");
+            }
+            for link in &links {
+                text_buf.append_line(link.to_line());
+            }
+        });
+        self.shown = links;
+    }
+}
+
+/// Returns `text` as a dim line, for things that aren't messages.
+fn notice_line(text: &str) -> StyledLine {
+    let style = Style {
+        dim: true,
+        ..Style::default()
+    };
+    return StyledLine {
+        spans: vec![Span::new(text, style)],
+        ..StyledLine::default()
+    };
+}
+
+/// Shows a message from the outbox like a message from us, followed by how
+/// far it got unless it was sent.
+fn append_outgoing(text_buf: &mut TextBuffer, outgoing: &Outgoing, me: &str) {
+    let text = match outgoing.kind {
+        OutgoingKind::Message => outgoing.text.clone(),
+        OutgoingKind::To { ref name } => format!("(to {}) {}", name, outgoing.text),
+        OutgoingKind::Exploding { ref lifetime } => {
+            format!("(explodes in {}) {}", lifetime, outgoing.text)
+        }
+        OutgoingKind::Edit { .. } => format!("(edit) {}", outgoing.text),
+        OutgoingKind::Reaction { .. } => format!("(reaction) {}", outgoing.text),
+    };
+    let msg = ChatMsg {
+        utc_timestamp: outgoing.utc_timestamp,
+        sender: me.to_string(),
+        conversation_id: outgoing.conversation_id.clone(),
+        text: text,
+        ..ChatMsg::default()
+    };
+    text_buf.append_msg(&msg, false);
+    let status = match outgoing.delivery {
+        Delivery::Queued => "Waiting for Keybase...".to_string(),
+        Delivery::Pending => "Sending...".to_string(),
+        Delivery::Sent { .. } => return,
+        Delivery::Failed { ref error } => format!(
+            "Not sent: {}. Type /retry to send it again or /discard to drop it.",
+            error
+        ),
+    };
+    text_buf.append_line(notice_line(&status));
+}
+
+/// Search hits asked for with `/search`.
+const SEARCH_HITS: usize = 50;
+
+/// What the reply to a request with its own reply channel is for.
+enum Waiting {
+    /// Sending the outbox message with this id.
+    Delivery(u64),
+    /// The history of this conversation.
+    History(String),
+    /// Searching for this text.
+    Search(String),
+    /// Joining this channel.
+    Join(String),
+    /// Leaving this channel.
+    Leave(String),
+}
+
+/// The widgets of the main window. They show what `AppState` tells them to
+/// and send what the user does back as `UserAction`s.
+struct MainView {
+    ui: UI,
+    chat_view: ChatView,
+    entry: MultilineEntry,
+    conversations_vbox: VerticalBox,
+    /// The buttons in `conversations_vbox`, in order.
+    conversation_buttons: Vec<Button>,
+    notification_menu: NotificationMenu,
+    actions: Sender<UserAction>,
+    /// Requests whose replies are waited for.
+    waiting: Vec<(Waiting, Receiver<KeybaseReply>)>,
+    /// Notices from work done in other threads.
+    notices_tx: Sender<String>,
+    notices: Receiver<String>,
+}
+
+impl MainView {
+    /// Carries out `effects`. Returns true if the chat view changed.
+    fn run(
+        &mut self,
+        effects: Vec<Effect>,
+        state: &ThreadSafeState,
+        sender: &Sender<KeybaseRequest>,
+    ) -> bool {
+        let mut chat_changed = false;
+        for effect in effects {
+            let mut locked = state.lock().unwrap();
+            match effect {
+                Effect::Fetch { conversation_id } => {
+                    println!("Changed channel.");
+                    let count = match locked.fetch_count {
+                        0 => TEXTBUF_HEIGHT,
+                        count => count,
+                    };
+                    let req = Keybase::create_read_conversation_req(&conversation_id, count);
+                    self.wait_for(Waiting::History(conversation_id), req, sender);
+                }
+                Effect::Send {
+                    outgoing_id,
+                    conversation_id,
+                    text,
+                    kind,
+                } => {
+                    let req = match kind {
+                        OutgoingKind::Message => Keybase::create_msg_req(&conversation_id, &text),
+                        OutgoingKind::To { name } => Keybase::create_msg_to_name_req(&name, &text),
+                        OutgoingKind::Exploding { lifetime } => {
+                            Keybase::create_exploding_msg_req(&conversation_id, &text, &lifetime)
+                        }
+                        OutgoingKind::Edit { message_id } => {
+                            Keybase::create_edit_req(&conversation_id, message_id, &text)
+                        }
+                        OutgoingKind::Reaction { message_id } => {
+                            Keybase::create_reaction_req(&conversation_id, message_id, &text)
+                        }
+                    };
+                    self.wait_for(Waiting::Delivery(outgoing_id), req, sender);
+                }
+                Effect::Join { channel } => {
+                    let req = Keybase::create_join_req(&channel);
+                    self.wait_for(Waiting::Join(channel), req, sender);
+                }
+                Effect::Leave { channel } => {
+                    let req = Keybase::create_leave_req(&channel);
+                    self.wait_for(Waiting::Leave(channel), req, sender);
+                }
+                Effect::Mute { conversation_id } => {
+                    let key = locked.rule_key(&conversation_id);
+                    locked
+                        .config
+                        .notifications
+                        .set_rule(&key, NotifyRule::Muted);
+                    save_config(&locked);
+                    self.notification_menu.sync(&self.ui, &locked);
+                }
+                Effect::Search {
+                    conversation_id,
+                    query,
+                } => {
+                    let req = Keybase::create_search_req(&conversation_id, &query, SEARCH_HITS);
+                    self.wait_for(Waiting::Search(query), req, sender);
+                }
+                Effect::SetTopic { channel, text } => {
+                    let notices = self.notices_tx.clone();
+                    // Runs a keybase command, which takes a while.
+                    thread::spawn(move || {
+                        let mut parts = channel.splitn(2, '#');
+                        let team = parts.next().unwrap_or("");
+                        let topic = parts.next().unwrap_or("");
+                        let notice = match Keybase::set_headline(team, topic, &text) {
+                            Ok(()) => format!("Set the topic of {}.", channel),
+                            Err(err) => format!("Setting the topic of {} failed: {}", channel, err),
+                        };
+                        let _ = notices.send(notice);
+                        libui::wake_main();
+                    });
+                }
+                Effect::ShowNotice { text } => {
+                    self.show_notice(&text);
+                    chat_changed = true;
+                }
+                Effect::ShowConversation => {
+                    let msgs = locked.app.messages(locked.app.current_conversation_id());
+                    self.chat_view.clear_selection();
+                    self.chat_view.update(|text_buf| {
+                        text_buf.clear();
+                        for msg in msgs {
+                            let highlight = locked.mentions.find(msg).is_some();
+                            text_buf.append_msg(msg, highlight);
+                        }
+                        for outgoing in locked.app.outbox(locked.app.current_conversation_id()) {
+                            append_outgoing(text_buf, outgoing, locked.mentions.me());
+                        }
+                    });
+                    chat_changed = true;
+                }
+                Effect::ShowMessage { msg } => {
+                    // Mentions of us are highlighted.
+                    let highlight = locked.mentions.find(&msg).is_some();
+                    let own = msg.sender == locked.mentions.me();
+                    self.chat_view.update(|text_buf| {
+                        text_buf.append_msg(&msg, highlight);
+                        // What we send is shown even if scrolled up.
+                        if own {
+                            text_buf.scroll_to_bottom();
+                        }
+                    });
+                    chat_changed = true;
+                }
+                Effect::ShowDraft => {
+                    let draft = locked.app.draft(locked.app.current_conversation_id());
+                    self.entry.set_value(&self.ui, draft);
+                }
+                Effect::ShowChannels => {
+                    self.show_channels(locked.app.channels());
+                }
+            }
+        }
+        return chat_changed;
+    }
+
+    /// Shows `text` below the messages until the conversation is shown
+    /// again.
+    fn show_notice(&self, text: &str) {
+        self.chat_view.update(|text_buf| {
+            for line in text.lines() {
+                text_buf.append_line(notice_line(line));
+            }
+            text_buf.scroll_to_bottom();
+        });
+    }
+
+    /// Sends `req` with a reply channel of its own, so that the reply is
+    /// known to be for `waiting`.
+    fn wait_for(
+        &mut self,
+        waiting: Waiting,
+        mut req: KeybaseRequest,
+        sender: &Sender<KeybaseRequest>,
+    ) {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        req.reply_tx = Some(reply_tx);
+        // If this fails the reply sender is gone, which is noticed in
+        // `take_replies`.
+        safe_send(sender, req);
+        self.waiting.push((waiting, reply_rx));
+    }
+
+    /// Returns the replies that arrived for requests sent with `wait_for`.
+    fn take_replies(&mut self) -> Vec<(Waiting, KeybaseReply)> {
+        let mut done = Vec::new();
+        let mut still_waiting = Vec::new();
+        for (waiting, reply_rx) in self.waiting.drain(..) {
+            let reply = match reply_rx.try_recv() {
+                Ok(reply) => reply,
+                Err(TryRecvError::Empty) => {
+                    still_waiting.push((waiting, reply_rx));
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => KeybaseReply::ErrorReply {
+                    error: "Keybase API is not running".to_string(),
+                },
+            };
+            done.push((waiting, reply));
+        }
+        self.waiting = still_waiting;
+        return done;
+    }
+
+    /// Lists what `/search` found below the conversation.
+    fn show_search_results(&self, query: &str, reply: KeybaseReply) {
+        let (header, msgs) = match reply {
+            KeybaseReply::SearchReply { ref msgs } if msgs.is_empty() => {
+                (format!("No messages here contain \"{}\".", query), vec![])
+            }
+            KeybaseReply::SearchReply { msgs } => {
+                (format!("Messages containing \"{}\":", query), msgs)
+            }
+            KeybaseReply::ErrorReply { error } => (format!("Search failed: {}", error), vec![]),
+            _ => (
+                "Search failed: unexpected reply from Keybase".to_string(),
+                vec![],
+            ),
+        };
+        self.chat_view.update(|text_buf| {
+            text_buf.append_line(notice_line(&header));
+            for msg in &msgs {
+                text_buf.append_msg(msg, false);
+            }
+            text_buf.scroll_to_bottom();
+        });
+    }
+
+    /// Tells how joining or leaving `channel` went. The conversation list is
+    /// asked for again, so it shows up or goes away.
+    fn show_membership(
+        &self,
+        verb: &str,
+        done: &str,
+        channel: &str,
+        reply: KeybaseReply,
+        sender: &Sender<KeybaseRequest>,
+    ) {
+        let notice = match reply {
+            KeybaseReply::ErrorReply { error } | KeybaseReply::Disconnected { error } => {
+                format!("Couldn't {} {}: {}", verb, channel, error)
+            }
+            _ => {
+                safe_send(sender, Keybase::create_list_channels_req());
+                format!("{} {}.", done, channel)
+            }
+        };
+        self.show_notice(&notice);
+    }
+
+    fn show_channels(&mut self, channels: &[Channel]) {
+        let old_buttons = self.conversation_buttons.drain(..).collect();
+        libui::clear_box(&self.conversations_vbox, old_buttons);
+        for chan in channels {
+            // Create a button for each conversation.
+            let mut button = Button::new(&self.ui, &chan.name);
+            let channel_id = chan.id.clone();
+            button.on_clicked(&self.ui, {
+                let actions = self.actions.clone();
+                move |_btn| {
+                    let action = UserAction::OpenConversation {
+                        conversation_id: channel_id.clone(),
+                    };
+                    send_action(&actions, action);
+                }
+            });
+            self.conversations_vbox
+                .append(&self.ui, button.clone(), LayoutStrategy::Compact);
+            self.conversation_buttons.push(button);
+        }
+    }
+}
+
+pub fn run() {
+    let state: ThreadSafeState = Arc::new(Mutex::new(SharedState::default()));
+    let config = Config::load();
+    let (notification_tx, notification_events) = waking_channel();
+    let notifier = notification::from_config(&config.notifications, notification_tx);
+    let notification_jobs = spawn_notification_thread(notifier);
+    let kb = Keybase::new();
+    match kb.login() {
+        Ok(_) => println!("Successfully logged in to Keybase."),
+        Err(reason) => panic!("Keybase login failed: {}", reason),
+    }
+
+    let me = match kb.get_username() {
+        Ok(me) => me,
+        Err(err) => {
+            eprintln!("Couldn't get username: {}", err);
+            String::new()
+        }
+    };
+    {
+        let mut locked = state.lock().unwrap();
+        locked.mentions = MentionMatcher::new(&me, &config.keywords);
+        locked.app.set_me(&me);
+        if config.persist_drafts {
+            locked.app.restore_drafts(config::load_drafts());
+        }
+        locked.app.set_fence_paste_lines(config.fence_paste_lines);
+        locked.app.set_sent_length(config.sent_history_length);
+        if config.sent_history_length > 0 {
+            locked.app.restore_sent(config::load_sent());
+        }
+        locked.config = config;
+    }
+
+    let req = Keybase::create_list_channels_req();
+    let sender = kb.get_message_sender();
+    safe_send(&sender, req);
+
+    // What the user does in the widgets, applied on the next tick.
+    let (action_tx, actions) = mpsc::channel();
+
+    let ui = UI::init().expect("Libui init failed.");
+    let notification_menu = NotificationMenu::new(&ui);
+    notification_menu.connect(&ui, &state);
+    notification_menu.sync(&ui, &state.lock().unwrap());
+    let view_menu = Menu::new(&ui, "View");
+    let mentions_item = view_menu.append_item("Mentions");
+    let links_item = view_menu.append_item("Links in this conversation");
+    let mut win = Window::new(&ui, WINDOW_TITLE, 640, 480, WindowType::HasMenubar);
+
+    let mut grid = LayoutGrid::new(&ui);
+    grid.set_padded(&ui, true);
+
+    // Create space for conversation buttons (left).
+    let conversations_vbox = VerticalBox::new(&ui);
+    let mut conversations_group = Group::new(&ui, "Conversations");
+
+    conversations_group.set_child(&ui, conversations_vbox.clone());
+    grid.append(
+        &ui,
+        conversations_group.clone(),
+        0,
+        0,
+        1,
+        1,
+        GridExpand::Neither,
+        GridAlignment::Fill,
+        GridAlignment::Fill,
+    );
+
+    // Create the chat view (right).
+    let mut chat_vbox = VerticalBox::new(&ui);
+    chat_vbox.set_padded(&ui, true);
+
+    let mut text_buf = TextBuffer::new(TEXTBUF_WIDTH, TEXTBUF_HEIGHT);
+    text_buf.set_scrollback(state.lock().unwrap().config.scrollback_lines);
+    text_buf.append("<--- Click to select a channel.");
+
+    let chat_view = ChatView::new(text_buf);
+    chat_vbox.append(&ui, chat_view.area(), LayoutStrategy::Stretchy);
+    grid.append(
+        &ui,
+        chat_vbox.clone(),
+        1,
+        0,
+        1,
+        1,
+        GridExpand::Both,
+        GridAlignment::Fill,
+        GridAlignment::Fill,
+    );
+
+    // Create the text entry.
+    let mut entry = MultilineEntry::new(&ui);
+    // Enter sends and Shift+Enter starts a new line. Without Enter events
+    // any newline sends, like pasting multiple lines.
+    // Up and Down only recall sent messages in an empty entry or one
+    // showing a sent message, and move the cursor otherwise.
+    let enter_sends = libui::on_entry_key(&entry, {
+        let actions = action_tx.clone();
+        let state = state.clone();
+        move |key| {
+            let action = match key {
+                EntryKey::Enter => UserAction::SendDraft,
+                EntryKey::Tab => UserAction::Complete,
+                EntryKey::Up | EntryKey::Down if !state.lock().unwrap().app.recalling() => {
+                    return false;
+                }
+                EntryKey::Up => UserAction::RecallOlder,
+                EntryKey::Down => UserAction::RecallNewer,
+                EntryKey::Search => UserAction::SearchSent,
+            };
+            send_action(&actions, action);
+            return true;
+        }
+    });
+    entry.on_changed(&ui, {
+        let actions = action_tx.clone();
+        move |val| {
+            let newline_found = val.contains('\n');
+            send_action(&actions, UserAction::EditDraft { text: val });
+            if newline_found && !enter_sends {
+                send_action(&actions, UserAction::SendDraft);
+            }
+        }
+    });
+    grid.append(
+        &ui,
+        entry.clone(),
+        1,
+        1,
+        1,
+        1,
+        GridExpand::Horizontal,
+        GridAlignment::Fill,
+        GridAlignment::Fill,
+    );
+
+    win.set_child(&ui, grid);
+    win.show(&ui);
+
+    let (notices_tx, notices) = mpsc::channel();
+    let mut main_view = MainView {
+        ui: ui.clone(),
+        chat_view: chat_view,
+        entry: entry,
+        conversations_vbox: conversations_vbox,
+        conversation_buttons: Vec::new(),
+        notification_menu: notification_menu.clone(),
+        actions: action_tx,
+        waiting: Vec::new(),
+        notices_tx: notices_tx,
+        notices: notices,
+    };
+
+    let mut mentions_view = MentionsView::new(&ui);
+    mentions_item.on_clicked(&ui, {
+        let ui = ui.clone();
+        let mut window = mentions_view.window.clone();
+        move |_item, _win| window.show(&ui)
+    });
+    let mut links_view = LinksView::new(&ui);
+    links_item.on_clicked(&ui, {
+        let ui = ui.clone();
+        let mut window = links_view.window.clone();
+        move |_item, _win| window.show(&ui)
+    });
+
+    // Requests from other desktop apps are handled on the UI thread.
+    #[cfg(feature = "dbus")]
+    let (dbus_service, dbus_commands) = {
+        let (command_tx, command_rx) = waking_channel();
+        let service = match dbus::Service::start(Arc::clone(&state), command_tx) {
+            Ok(service) => Some(service),
+            Err(err) => {
+                eprintln!("D-Bus service not available: {}", err);
+                None
+            }
+        };
+        (service, command_rx)
+    };
+
+    // The event loop sleeps until there is a UI event, so the backend wakes
+    // it up when replies arrive. Anything that came before is handled on the
+    // first wakeup.
+    kb.set_wakeup(libui::wake_main);
+    libui::wake_main();
+
+    let mut event_loop = ui.event_loop();
+    event_loop.on_tick(&ui, {
+        let ui = ui.clone();
+        let mut win = win.clone();
+        let sender = sender.clone();
+        let mut shown_conversation_id = String::new();
+        let mut shown_title = WINDOW_TITLE.to_string();
+        let mut saved_drafts = state.lock().unwrap().app.drafts().clone();
+        let mut saved_sent = state.lock().unwrap().app.sent().clone();
+        move || {
+            // Each message takes at least one row.
+            state.lock().unwrap().fetch_count = main_view.chat_view.visible_rows();
+
+            let mut new_actions: Vec<UserAction> = actions.try_iter().collect();
+            new_actions.extend(notification_events.try_iter().map(notification_action));
+            #[cfg(feature = "dbus")]
+            for command in dbus_commands.try_iter() {
+                match command {
+                    dbus::ServiceCommand::OpenConversation { conversation_id } => {
+                        new_actions.push(UserAction::OpenConversation {
+                            conversation_id: conversation_id,
+                        });
+                    }
+                    dbus::ServiceCommand::Send {
+                        conversation_id,
+                        text,
+                    } => {
+                        new_actions.push(UserAction::Send {
+                            conversation_id: conversation_id,
+                            text: text,
+                        });
+                    }
+                }
+            }
+
+            let mut chat_changed = false;
+            for action in new_actions {
+                let effects = state.lock().unwrap().app.apply_action(action);
+                chat_changed |= main_view.run(effects, &state, &sender);
+            }
+            {
+                // Saved so that a crash doesn't lose them.
+                let locked = state.lock().unwrap();
+                if locked.config.persist_drafts && *locked.app.drafts() != saved_drafts {
+                    saved_drafts = locked.app.drafts().clone();
+                    if let Err(err) = config::save_drafts(&saved_drafts) {
+                        eprintln!("Saving drafts failed: {}", err);
+                    }
+                }
+                if locked.config.sent_history_length > 0 && *locked.app.sent() != saved_sent {
+                    saved_sent = locked.app.sent().clone();
+                    if let Err(err) = config::save_sent(&saved_sent) {
+                        eprintln!("Saving sent messages failed: {}", err);
+                    }
+                }
+            }
+
+            // Everything that arrived since the last tick is handled in one
+            // batch. The views only queue a redraw, so they are drawn once.
+            loop {
+                let reply = match kb.get_message_receiver().try_recv() {
+                    Ok(reply) => reply,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        panic!("Msg recv error");
+                    }
+                };
+                match reply {
+                    KeybaseReply::ChatMsgReply { ref msg } => {
+                        mentions_view.check(msg, &state, &ui);
+                        notify_chat_msg(msg, &state, &notification_jobs);
+                        #[cfg(feature = "dbus")]
+                        {
+                            if let Some(ref service) = dbus_service {
+                                service.emit_message_received(msg, &state);
+                            }
+                        }
+                    }
+                    KeybaseReply::ErrorReply { ref error }
+                    | KeybaseReply::ListenerStopped { ref error } => {
+                        eprintln!("Keybase error: {}", error);
+                    }
+                    _ => {}
+                }
+                let effects = state.lock().unwrap().app.apply_reply(reply);
+                chat_changed |= main_view.run(effects, &state, &sender);
+            }
+            for (waiting, reply) in main_view.take_replies() {
+                let effects = match waiting {
+                    Waiting::Delivery(outgoing_id) => {
+                        if let KeybaseReply::ErrorReply { ref error } = reply {
+                            eprintln!("Sending failed: {}", error);
+                        }
+                        let mut locked = state.lock().unwrap();
+                        locked.app.apply_delivery(outgoing_id, reply)
+                    }
+                    Waiting::History(conversation_id) => {
+                        let mut locked = state.lock().unwrap();
+                        locked.app.apply_history(&conversation_id, reply)
+                    }
+                    Waiting::Search(query) => {
+                        main_view.show_search_results(&query, reply);
+                        chat_changed = true;
+                        continue;
+                    }
+                    Waiting::Join(channel) => {
+                        main_view.show_membership("join", "Joined", &channel, reply, &sender);
+                        chat_changed = true;
+                        continue;
+                    }
+                    Waiting::Leave(channel) => {
+                        main_view.show_membership("leave", "Left", &channel, reply, &sender);
+                        chat_changed = true;
+                        continue;
+                    }
+                };
+                chat_changed |= main_view.run(effects, &state, &sender);
+            }
+            let notices: Vec<String> = main_view.notices.try_iter().collect();
+            for notice in notices {
+                main_view.show_notice(&notice);
+                chat_changed = true;
+            }
+            if chat_changed {
+                links_view.refresh(&main_view.chat_view);
+            }
+
+            let locked = state.lock().unwrap();
+            if locked.app.current_conversation_id() != shown_conversation_id {
+                shown_conversation_id = locked.app.current_conversation_id().to_string();
+                let _ = notification_jobs.send(NotificationJob::Reset {
+                    conversation_id: shown_conversation_id.clone(),
+                });
+                notification_menu.sync(&ui, &locked);
+            }
+            let title = locked.window_title();
+            if title != shown_title {
+                win.set_title(&ui, &title);
+                shown_title = title;
+            }
+        }
+    });
+    event_loop.run(&ui);
+}
@@ -0,0 +1,58 @@
+//! Sharing one backend between several consumers.
+//!
+//! `Keybase` has a single receiver. Gateways with many clients wrap it in a
+//! `Hub`, which moves the backend to a dispatcher thread and copies every
+//! incoming reply to each subscriber.
+
+use super::keybase::{Keybase, KeybaseReply, KeybaseRequest};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Cloneable handle to a shared `Keybase` backend.
+#[derive(Clone)]
+pub struct Hub {
+    sender: Sender<KeybaseRequest>,
+    subscribers: Arc<Mutex<Vec<Sender<KeybaseReply>>>>,
+}
+
+impl Hub {
+    /// Takes over `kb` and starts the dispatcher thread. The backend lives
+    /// as long as the process.
+    pub fn new(kb: Keybase) -> Self {
+        let hub = Hub {
+            sender: kb.get_message_sender(),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        let subscribers = Arc::clone(&hub.subscribers);
+        thread::spawn(move || {
+            for reply in kb.get_message_receiver().iter() {
+                let mut locked = subscribers.lock().unwrap();
+                // Forget subscribers whose receiver has been dropped.
+                locked.retain(|tx| tx.send(reply.clone()).is_ok());
+            }
+            eprintln!("Hub dispatcher stopped.");
+        });
+        return hub;
+    }
+
+    /// Returns a receiver that gets a copy of every reply and incoming
+    /// message from now on.
+    pub fn subscribe(&self) -> Receiver<KeybaseReply> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        return rx;
+    }
+
+    /// Sends `req` and blocks until its reply arrives, see `Keybase::call`.
+    pub fn call(&self, req: KeybaseRequest) -> Result<KeybaseReply, String> {
+        return Keybase::call_with_sender(&self.sender, req);
+    }
+
+    /// Sender for requests whose replies go to all subscribers.
+    pub fn get_message_sender(&self) -> Sender<KeybaseRequest> {
+        return self.sender.clone();
+    }
+}
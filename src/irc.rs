@@ -0,0 +1,707 @@
+//! Local IRC gateway.
+//!
+//! Runs an IRC server on localhost so that regular IRC clients can be used
+//! for Keybase chat. Team channels show up as `#team/topic`, group
+//! conversations as `#alice+bob` and one-on-one conversations as queries with
+//! the other user.
+//!
+//! Clients have to send the token from the token file with `PASS` to
+//! register.
+
+use super::token::{load_or_create_token, token_matches};
+use chrono::{Local, TimeZone};
+use kbchatbox::hub::Hub;
+use kbchatbox::{Channel, ChatMsg, Keybase, KeybaseReply};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_PORT: u16 = 6667;
+
+const SERVER_NAME: &str = "kbchatbox";
+
+// Number of messages played back when joining a channel.
+const HISTORY_LENGTH: usize = 50;
+
+/// How long to wait for the echo of a sent message before forgetting it.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A line from a client. The prefix is dropped as clients don't need one.
+#[derive(Debug, PartialEq)]
+struct IrcLine {
+    command: String,
+    params: Vec<String>,
+}
+
+fn parse_line(line: &str) -> Option<IrcLine> {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+
+    // Skip IRCv3 message tags and the prefix.
+    if rest.starts_with('@') {
+        rest = rest.split_once(' ')?.1.trim_start();
+    }
+    if rest.starts_with(':') {
+        rest = rest.split_once(' ')?.1.trim_start();
+    }
+
+    let (head, trailing) = match rest.find(" :") {
+        Some(pos) => (&rest[..pos], Some(&rest[pos + 2..])),
+        None => (rest, None),
+    };
+
+    let mut words = head.split_whitespace();
+    let command = words.next()?.to_ascii_uppercase();
+    let mut params: Vec<String> = words.map(|w| w.to_string()).collect();
+    if let Some(trailing) = trailing {
+        params.push(trailing.to_string());
+    }
+
+    return Some(IrcLine {
+        command: command,
+        params: params,
+    });
+}
+
+/// Returns the IRC name of a conversation: a channel for team channels and
+/// group conversations, the other user's nick for one-on-one conversations.
+fn irc_target(chan: &Channel, me: &str) -> String {
+    if chan.name.contains('#') {
+        return format!("#{}", chan.name.replacen('#', "/", 1));
+    }
+
+    let others: Vec<&str> = chan.name.split(',').filter(|n| *n != me).collect();
+    match others.len() {
+        // Conversation with oneself.
+        0 => me.to_string(),
+        1 => others[0].to_string(),
+        _ => format!("#{}", others.join("+")),
+    }
+}
+
+/// Maps between conversations and IRC names.
+struct Directory {
+    me: String,
+    // Lowercase IRC name -> conversation.
+    by_target: HashMap<String, Channel>,
+    // Conversation id -> IRC name.
+    by_id: HashMap<String, String>,
+}
+
+impl Directory {
+    fn new(me: &str) -> Self {
+        Directory {
+            me: me.to_string(),
+            by_target: HashMap::new(),
+            by_id: HashMap::new(),
+        }
+    }
+
+    fn update(&mut self, channels: Vec<Channel>) {
+        self.by_target.clear();
+        self.by_id.clear();
+        for chan in channels {
+            let target = irc_target(&chan, &self.me);
+            self.by_id.insert(chan.id.clone(), target.clone());
+            self.by_target.insert(target.to_lowercase(), chan);
+        }
+    }
+
+    fn target_for(&self, conversation_id: &str) -> Option<String> {
+        return self.by_id.get(conversation_id).cloned();
+    }
+
+    fn channel_for(&self, target: &str) -> Option<Channel> {
+        return self.by_target.get(&target.to_lowercase()).cloned();
+    }
+}
+
+fn refresh_directory(hub: &Hub, directory: &Mutex<Directory>) -> Result<(), String> {
+    match hub.call(Keybase::create_list_channels_req())? {
+        KeybaseReply::ChannelListReply { channels } => {
+            directory.lock().unwrap().update(channels);
+            Ok(())
+        }
+        _ => Err("Unexpected reply to list".to_string()),
+    }
+}
+
+/// A message sent from this client that api-listen will echo back.
+struct SentMsg {
+    /// Local number to find it again after sending.
+    serial: u64,
+    conversation_id: String,
+    /// The trimmed text, for an echo that comes before the id is known.
+    text: String,
+    /// The message id, once Keybase gave it.
+    id: Option<u64>,
+    sent_at: Instant,
+}
+
+impl SentMsg {
+    fn is_echo(&self, msg: &ChatMsg) -> bool {
+        if msg.conversation_id != self.conversation_id {
+            return false;
+        }
+        match self.id {
+            Some(id) => msg.id == id,
+            None => msg.text.trim() == self.text,
+        }
+    }
+}
+
+struct SessionState {
+    /// What the client sent with `PASS`, checked on registering.
+    pass: Option<String>,
+    nick: String,
+    user_received: bool,
+    registered: bool,
+    // Lowercase names of joined channels.
+    joined: HashSet<String>,
+    // Messages whose echo is skipped, forgotten after `ECHO_TIMEOUT`.
+    sent: Vec<SentMsg>,
+    next_serial: u64,
+}
+
+/// One connected IRC client.
+struct Session {
+    hub: Hub,
+    directory: Arc<Mutex<Directory>>,
+    writer: Mutex<TcpStream>,
+    state: Mutex<SessionState>,
+    token: String,
+}
+
+impl Session {
+    fn send(&self, line: &str) -> bool {
+        let mut writer = self.writer.lock().unwrap();
+        return writer.write_all(format!("{}\r\n", line).as_bytes()).is_ok();
+    }
+
+    fn send_numeric(&self, numeric: &str, text: &str) {
+        let nick = self.state.lock().unwrap().nick.clone();
+        self.send(&format!(":{} {} {} {}", SERVER_NAME, numeric, nick, text));
+    }
+
+    fn me(&self) -> String {
+        return self.directory.lock().unwrap().me.clone();
+    }
+
+    fn handle_line(&self, line: &IrcLine) -> bool {
+        match line.command.as_str() {
+            "CAP" => self.handle_cap(line),
+            "PASS" => self.handle_pass(line),
+            "NICK" => return self.handle_nick(line),
+            "USER" => {
+                self.state.lock().unwrap().user_received = true;
+                return self.try_register();
+            }
+            "PING" => {
+                let token = line.params.first().map(|t| t.as_str()).unwrap_or("");
+                self.send(&format!(":{} PONG {} :{}", SERVER_NAME, SERVER_NAME, token));
+            }
+            "QUIT" => return false,
+            _ if !self.state.lock().unwrap().registered => {
+                self.send_numeric("451", ":You have not registered");
+            }
+            "JOIN" => self.handle_join(line),
+            "PART" => self.handle_part(line),
+            "PRIVMSG" | "NOTICE" => self.handle_privmsg(line),
+            "LIST" => self.handle_list(),
+            "MODE" => {
+                if let Some(target) = line.params.first() {
+                    if target.starts_with('#') {
+                        self.send_numeric("324", &format!("{} +nt", target));
+                    }
+                }
+            }
+            "WHO" => {
+                let mask = line.params.first().map(|t| t.as_str()).unwrap_or("*");
+                self.send_numeric("315", &format!("{} :End of WHO list", mask));
+            }
+            other => {
+                self.send_numeric("421", &format!("{} :Unknown command", other));
+            }
+        }
+        return true;
+    }
+
+    fn handle_pass(&self, line: &IrcLine) {
+        if self.state.lock().unwrap().registered {
+            self.send_numeric("462", ":You may not reregister");
+            return;
+        }
+        match line.params.first() {
+            Some(given) => self.state.lock().unwrap().pass = Some(given.clone()),
+            None => self.send_numeric("461", "PASS :Not enough parameters"),
+        }
+    }
+
+    fn handle_cap(&self, line: &IrcLine) {
+        match line.params.first().map(|p| p.as_str()) {
+            Some("LS") => {
+                self.send(&format!(":{} CAP * LS :", SERVER_NAME));
+            }
+            Some("REQ") => {
+                let caps = line.params.get(1).map(|p| p.as_str()).unwrap_or("");
+                self.send(&format!(":{} CAP * NAK :{}", SERVER_NAME, caps));
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns false if the client has to go.
+    fn handle_nick(&self, line: &IrcLine) -> bool {
+        let new_nick = match line.params.first() {
+            Some(nick) => nick.clone(),
+            None => {
+                self.send_numeric("431", ":No nickname given");
+                return true;
+            }
+        };
+
+        let (old_nick, registered) = {
+            let mut state = self.state.lock().unwrap();
+            let old_nick = state.nick.clone();
+            state.nick = new_nick.clone();
+            (old_nick, state.registered)
+        };
+
+        if registered {
+            self.send(&format!(
+                ":{}!{}@localhost NICK {}",
+                old_nick, old_nick, new_nick
+            ));
+            return true;
+        }
+        return self.try_register();
+    }
+
+    /// Registers the client once it sent NICK and USER, if its PASS is the
+    /// token. Returns false if the client has to go.
+    fn try_register(&self) -> bool {
+        let authenticated = {
+            let mut state = self.state.lock().unwrap();
+            if state.registered || !state.user_received || state.nick == "*" {
+                return true;
+            }
+            let authenticated = match state.pass {
+                Some(ref given) => token_matches(given, &self.token),
+                None => false,
+            };
+            state.registered = authenticated;
+            authenticated
+        };
+        if !authenticated {
+            self.send_numeric(
+                "464",
+                ":Password incorrect, send the token from the token file with PASS",
+            );
+            return false;
+        }
+
+        let me = self.me();
+        self.send_numeric(
+            "001",
+            &format!(":Welcome to kbchatbox, the Keybase IRC gateway for {}", me),
+        );
+        self.send_numeric("002", &format!(":Your host is {}", SERVER_NAME));
+        self.send_numeric("003", ":This server was created just now");
+        self.send_numeric("004", &format!("{} kbchatbox o o", SERVER_NAME));
+        self.send_numeric("422", ":MOTD File is missing");
+        return true;
+    }
+
+    fn handle_join(&self, line: &IrcLine) {
+        let targets = match line.params.first() {
+            Some(targets) => targets.clone(),
+            None => {
+                self.send_numeric("461", "JOIN :Not enough parameters");
+                return;
+            }
+        };
+
+        for target in targets.split(',') {
+            let chan = self.directory.lock().unwrap().channel_for(target);
+            match chan {
+                Some(ref chan) if target.starts_with('#') => {
+                    self.join(target, chan);
+                    self.play_history(target, chan);
+                }
+                _ => {
+                    self.send_numeric("403", &format!("{} :No such channel", target));
+                }
+            }
+        }
+    }
+
+    fn join(&self, target: &str, chan: &Channel) {
+        let nick = {
+            let mut state = self.state.lock().unwrap();
+            if !state.joined.insert(target.to_lowercase()) {
+                return;
+            }
+            state.nick.clone()
+        };
+
+        self.send(&format!(":{}!{}@localhost JOIN {}", nick, nick, target));
+        self.send_numeric("332", &format!("{} :{}", target, chan.name));
+
+        // Group conversations list their members in the name.
+        let me = self.me();
+        let mut names = vec![nick.clone()];
+        if !chan.name.contains('#') {
+            names.extend(
+                chan.name
+                    .split(',')
+                    .filter(|n| *n != me)
+                    .map(|n| n.to_string()),
+            );
+        }
+        self.send_numeric("353", &format!("= {} :{}", target, names.join(" ")));
+        self.send_numeric("366", &format!("{} :End of NAMES list", target));
+    }
+
+    fn play_history(&self, target: &str, chan: &Channel) {
+        let req = Keybase::create_read_conversation_req(&chan.id, HISTORY_LENGTH);
+        match self.hub.call(req) {
+            Ok(KeybaseReply::ChatMsgListReply { msgs }) => {
+                for msg in msgs.iter().rev() {
+                    let ts = Local.from_utc_datetime(&msg.utc_timestamp);
+                    let prefix = format!("[{}] ", ts.format("%F %T"));
+                    self.send_privmsg(&msg.sender, target, &prefix, &msg.text);
+                }
+            }
+            Ok(_) => {}
+            Err(err) => self.send_notice(&format!("Reading history failed: {}", err)),
+        }
+    }
+
+    fn handle_part(&self, line: &IrcLine) {
+        let targets = line.params.first().cloned().unwrap_or_default();
+        for target in targets.split(',') {
+            let (removed, nick) = {
+                let mut state = self.state.lock().unwrap();
+                (
+                    state.joined.remove(&target.to_lowercase()),
+                    state.nick.clone(),
+                )
+            };
+            if removed {
+                self.send(&format!(":{}!{}@localhost PART {}", nick, nick, target));
+            } else {
+                self.send_numeric("442", &format!("{} :You're not on that channel", target));
+            }
+        }
+    }
+
+    fn handle_privmsg(&self, line: &IrcLine) {
+        if line.params.len() < 2 {
+            self.send_numeric("412", ":No text to send");
+            return;
+        }
+
+        let target = &line.params[0];
+        let mut text = line.params[1].clone();
+        if text.starts_with("\u{1}ACTION ") {
+            // Keybase has no emotes, send /me as italic text.
+            text = format!("_{}_", text[8..].trim_end_matches('\u{1}'));
+        } else if text.starts_with('\u{1}') {
+            // Other CTCP requests have no Keybase equivalent.
+            return;
+        }
+
+        let chan = match self.directory.lock().unwrap().channel_for(target) {
+            Some(chan) => chan,
+            None => {
+                self.send_numeric("401", &format!("{} :No such nick/channel", target));
+                return;
+            }
+        };
+
+        // Remember the message before sending so the echo can't race us.
+        // Keybase trims what it sends.
+        let serial = {
+            let mut state = self.state.lock().unwrap();
+            let serial = state.next_serial;
+            state.next_serial += 1;
+            state.sent.retain(|s| s.sent_at.elapsed() < ECHO_TIMEOUT);
+            state.sent.push(SentMsg {
+                serial: serial,
+                conversation_id: chan.id.clone(),
+                text: text.trim().to_string(),
+                id: None,
+                sent_at: Instant::now(),
+            });
+            serial
+        };
+        let reply = self.hub.call(Keybase::create_msg_req(&chan.id, &text));
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(pos) = state.sent.iter().position(|s| s.serial == serial) {
+                match reply {
+                    // From now on only this message is its echo.
+                    Ok(KeybaseReply::SendReply { id }) if id != 0 => state.sent[pos].id = Some(id),
+                    Ok(_) => {}
+                    Err(_) => {
+                        state.sent.remove(pos);
+                    }
+                }
+            }
+        }
+        if let Err(err) = reply {
+            self.send_notice(&format!("Sending to {} failed: {}", target, err));
+        }
+    }
+
+    fn handle_list(&self) {
+        let mut targets: Vec<(String, String)> = Vec::new();
+        {
+            let directory = self.directory.lock().unwrap();
+            for chan in directory.by_target.values() {
+                let target = irc_target(chan, &directory.me);
+                if target.starts_with('#') {
+                    targets.push((target, chan.name.clone()));
+                }
+            }
+        }
+        targets.sort();
+
+        self.send_numeric("321", "Channel :Users Name");
+        for (target, name) in targets {
+            self.send_numeric("322", &format!("{} 0 :{}", target, name));
+        }
+        self.send_numeric("323", ":End of LIST");
+    }
+
+    fn send_privmsg(&self, from: &str, target: &str, prefix: &str, text: &str) -> bool {
+        for line in text.lines() {
+            let sent = self.send(&format!(
+                ":{}!{}@keybase PRIVMSG {} :{}{}",
+                from, from, target, prefix, line
+            ));
+            if !sent {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    fn send_notice(&self, text: &str) {
+        let nick = self.state.lock().unwrap().nick.clone();
+        self.send(&format!(":{} NOTICE {} :{}", SERVER_NAME, nick, text));
+    }
+
+    /// Relays a message from api-listen. Returns false once the client is
+    /// gone.
+    fn relay(&self, msg: &ChatMsg) -> bool {
+        let mut target = self
+            .directory
+            .lock()
+            .unwrap()
+            .target_for(&msg.conversation_id);
+        if target.is_none() {
+            // A conversation we haven't seen yet.
+            if let Err(err) = refresh_directory(&self.hub, &self.directory) {
+                eprintln!("Refreshing conversations failed: {}", err);
+            }
+            target = self
+                .directory
+                .lock()
+                .unwrap()
+                .target_for(&msg.conversation_id);
+        }
+        let target = match target {
+            Some(target) => target,
+            None => return true,
+        };
+
+        let me = self.me();
+        let nick = {
+            let mut state = self.state.lock().unwrap();
+            if !state.registered {
+                return true;
+            }
+
+            // Skip the echo of our own message, the client shows it already.
+            if msg.sender == me {
+                state.sent.retain(|s| s.sent_at.elapsed() < ECHO_TIMEOUT);
+                if let Some(pos) = state.sent.iter().position(|s| s.is_echo(msg)) {
+                    state.sent.remove(pos);
+                    return true;
+                }
+            }
+            state.nick.clone()
+        };
+
+        if target.starts_with('#') {
+            let chan = self.directory.lock().unwrap().channel_for(&target);
+            if let Some(chan) = chan {
+                self.join(&target, &chan);
+            }
+            return self.send_privmsg(&msg.sender, &target, "", &msg.text);
+        }
+
+        // Queries are addressed to the client, or to the other user when we
+        // wrote the message from another device.
+        if msg.sender == me {
+            return self.send_privmsg(&me, &target, "", &msg.text);
+        }
+        return self.send_privmsg(&msg.sender, &nick, "", &msg.text);
+    }
+}
+
+fn handle_client(stream: TcpStream, hub: Hub, directory: Arc<Mutex<Directory>>, token: String) {
+    let reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(err) => {
+            eprintln!("Can't clone client socket: {}", err);
+            return;
+        }
+    };
+
+    let session = Arc::new(Session {
+        hub: hub.clone(),
+        directory: directory,
+        writer: Mutex::new(stream),
+        state: Mutex::new(SessionState {
+            pass: None,
+            nick: "*".to_string(),
+            user_received: false,
+            registered: false,
+            joined: HashSet::new(),
+            sent: Vec::new(),
+            next_serial: 0,
+        }),
+        token: token,
+    });
+
+    let incoming = hub.subscribe();
+    thread::spawn({
+        let session = Arc::clone(&session);
+        move || {
+            for reply in incoming.iter() {
+                if let KeybaseReply::ChatMsgReply { msg } = reply {
+                    if !session.relay(&msg) {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if let Some(parsed) = parse_line(&line) {
+            if !session.handle_line(&parsed) {
+                break;
+            }
+        }
+    }
+
+    let _ = session
+        .writer
+        .lock()
+        .unwrap()
+        .shutdown(std::net::Shutdown::Both);
+    eprintln!("IRC client disconnected.");
+}
+
+/// Runs the gateway on `127.0.0.1:port` until the process is killed.
+pub fn run(port: u16, token_file: &Path) -> Result<(), String> {
+    let token = load_or_create_token(token_file)?;
+    let kb = Keybase::new();
+    let me = kb.get_username()?;
+    let hub = Hub::new(kb);
+    let directory = Arc::new(Mutex::new(Directory::new(&me)));
+    refresh_directory(&hub, &directory)?;
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(listener) => listener,
+        Err(err) => return Err(format!("Can't listen on port {}: {}", port, err)),
+    };
+    eprintln!("IRC gateway for {} listening on 127.0.0.1:{}", me, port);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let hub = hub.clone();
+                let directory = Arc::clone(&directory);
+                let token = token.clone();
+                thread::spawn(move || handle_client(stream, hub, directory, token));
+            }
+            Err(err) => eprintln!("Accepting IRC client failed: {}", err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(name: &str) -> Channel {
+        Channel {
+            name: name.to_string(),
+            id: "id".to_string(),
+            unread_msgs: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let line = parse_line("@time=x :nick!u@h PRIVMSG #team/general :hello there\r\n");
+        assert_eq!(
+            line,
+            Some(IrcLine {
+                command: "PRIVMSG".to_string(),
+                params: vec!["#team/general".to_string(), "hello there".to_string()],
+            })
+        );
+
+        let line = parse_line("nick alice").unwrap();
+        assert_eq!(line.command, "NICK");
+        assert_eq!(line.params, vec!["alice"]);
+
+        assert_eq!(parse_line(""), None);
+    }
+
+    #[test]
+    fn test_is_echo() {
+        let mut sent = SentMsg {
+            serial: 0,
+            conversation_id: "c".to_string(),
+            text: "hi".to_string(),
+            id: None,
+            sent_at: Instant::now(),
+        };
+        let mut msg = ChatMsg {
+            id: 5,
+            conversation_id: "c".to_string(),
+            text: "hi\n".to_string(),
+            ..ChatMsg::default()
+        };
+        assert!(sent.is_echo(&msg));
+        sent.id = Some(6);
+        assert!(!sent.is_echo(&msg));
+        msg.id = 6;
+        msg.text = "edited".to_string();
+        assert!(sent.is_echo(&msg));
+        msg.conversation_id = "d".to_string();
+        assert!(!sent.is_echo(&msg));
+    }
+
+    #[test]
+    fn test_irc_target() {
+        assert_eq!(irc_target(&channel("team#general"), "me"), "#team/general");
+        assert_eq!(irc_target(&channel("alice,me"), "me"), "alice");
+        assert_eq!(irc_target(&channel("alice,bob,me"), "me"), "#alice+bob");
+        assert_eq!(irc_target(&channel("me"), "me"), "me");
+    }
+}
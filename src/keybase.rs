@@ -1,54 +1,193 @@
-extern crate chrono;
-extern crate iui;
-use super::notification;
-use chrono::NaiveDateTime;
+//! Backend for the Keybase chat API.
+//!
+//! `Keybase` spawns `keybase chat api` and `keybase chat api-listen` in
+//! background threads. Requests are sent in through the channel returned by
+//! `Keybase::get_message_sender` and every parsed reply or incoming message is
+//! delivered through `Keybase::get_message_receiver`. A callback set with
+//! `Keybase::set_wakeup` is called whenever something is delivered there.
+
+use chrono::{Local, TimeZone, Utc};
 use serde_json::json;
 use serde_json::Value;
-use std::error::Error;
 use std::io::{BufRead, BufReader, Write};
+use std::panic::{self, AssertUnwindSafe};
 use std::process::{ChildStdin, ChildStdout, Command, Stdio};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How long `Keybase::call` waits for the API to answer.
+const API_TIMEOUT_SECS: u64 = 30;
+
+/// How long to wait before starting `keybase chat api` again after it
+/// stopped.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
 
 #[derive(PartialEq)]
 enum MsgType {
     ChannelList,
     ChatMsg,
     ChatMsgList,
+    SearchHits,
+    Sent,
+    Error,
+    /// Any other answer without an error.
+    Done,
     Unknown,
 }
 
+/// A single text message in a conversation.
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct ChatMsg {
+    /// Id of the message within its conversation.
+    pub id: u64,
+    /// Time the message was sent, in UTC.
     pub utc_timestamp: chrono::NaiveDateTime,
-    pub channel: String,
+    /// Username of the sender.
+    pub sender: String,
+    /// Id of the conversation the message belongs to.
     pub conversation_id: String,
+    /// Message body with surrounding whitespace trimmed.
     pub text: String,
+    /// Users mentioned with `@name`, as reported by Keybase.
+    pub at_mentions: Vec<String>,
+    /// True if the message mentions `@here` or `@channel`.
+    pub channel_mention: bool,
+}
+
+impl ChatMsg {
+    /// Formats the message as `timestamp - sender: text` in local time.
+    pub fn format(&self) -> String {
+        let ts = Local.from_utc_datetime(&self.utc_timestamp);
+        return format!("{} - {}: {}", ts.format("%F %T"), self.sender, self.text);
+    }
+
+    /// Returns the message as a JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "sent_at": Utc.from_utc_datetime(&self.utc_timestamp).timestamp(),
+            "sender": self.sender,
+            "conversation_id": self.conversation_id,
+            "text": self.text,
+        })
+    }
 }
 
+/// A conversation the user is a member of.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Channel {
+    /// Display name, `team#topic` for team channels.
     pub name: String,
+    /// Conversation id used in requests.
     pub id: String,
+    /// True if the conversation has unread messages.
     pub unread_msgs: bool,
 }
 
+impl Channel {
+    /// Returns the conversation as a JSON object.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "id": self.id,
+            "unread": self.unread_msgs,
+        })
+    }
+}
+
+/// A JSON request for `keybase chat api`. Use the `Keybase::create_*_req`
+/// functions to build these.
 pub struct KeybaseRequest {
     pub msg: Value,
+    /// Where to deliver the reply. Replies go to the shared receiver when
+    /// this is `None`.
+    pub reply_tx: Option<Sender<KeybaseReply>>,
 }
 
+/// Parsed reply from the API or a new message from the listener.
+#[derive(Clone, Debug)]
 pub enum KeybaseReply {
+    /// A new message received by the listener.
     ChatMsgReply { msg: ChatMsg },
+    /// Conversation history, newest message first.
     ChatMsgListReply { msgs: Vec<ChatMsg> },
+    /// All conversations, sorted by name.
     ChannelListReply { channels: Vec<Channel> },
+    /// Messages found by a search, oldest first.
+    SearchReply { msgs: Vec<ChatMsg> },
+    /// A message was accepted by the API and got the id `id`.
+    SendReply { id: u64 },
+    /// The API rejected a request.
+    ErrorReply { error: String },
+    /// The API did what was asked, e.g. joined a channel.
+    Done,
+    /// `keybase chat api` stopped. Requests wait until it is started again
+    /// and `Reconnected` is sent, except the one it stopped at, which gets
+    /// this as its reply. If that one had no reply channel, this comes to
+    /// the shared receiver twice.
+    Disconnected { error: String },
+    /// `keybase chat api` was started again after `Disconnected`.
+    Reconnected,
+    /// `keybase chat api-listen` stopped, no more messages will arrive.
+    ListenerStopped { error: String },
+}
+
+impl KeybaseReply {
+    /// Returns the reply as a JSON object with a `type` field.
+    pub fn to_json(&self) -> Value {
+        match self {
+            KeybaseReply::ChatMsgReply { msg } => json!({
+                "type": "chat_msg",
+                "msg": msg.to_json(),
+            }),
+            KeybaseReply::ChatMsgListReply { msgs } => json!({
+                "type": "chat_msg_list",
+                "msgs": msgs.iter().map(|m| m.to_json()).collect::<Vec<Value>>(),
+            }),
+            KeybaseReply::ChannelListReply { channels } => json!({
+                "type": "channel_list",
+                "channels": channels.iter().map(|c| c.to_json()).collect::<Vec<Value>>(),
+            }),
+            KeybaseReply::SearchReply { msgs } => json!({
+                "type": "search",
+                "msgs": msgs.iter().map(|m| m.to_json()).collect::<Vec<Value>>(),
+            }),
+            KeybaseReply::SendReply { id } => json!({
+                "type": "sent",
+                "id": id,
+            }),
+            KeybaseReply::ErrorReply { error } => json!({
+                "type": "error",
+                "error": error,
+            }),
+            KeybaseReply::Disconnected { error } => json!({
+                "type": "disconnected",
+                "error": error,
+            }),
+            KeybaseReply::Reconnected => json!({
+                "type": "reconnected",
+            }),
+            KeybaseReply::Done => json!({
+                "type": "done",
+            }),
+            KeybaseReply::ListenerStopped { error } => json!({
+                "type": "listener_stopped",
+                "error": error,
+            }),
+        }
+    }
 }
 
 #[derive(Debug)]
 enum KeybaseInternalError {
     IoError,
+    /// The other end of a channel is gone.
+    Closed,
     ParseError,
     UnknownMessage,
     InvalidMessageFormat,
@@ -56,13 +195,13 @@ enum KeybaseInternalError {
 
 impl From<std::sync::mpsc::RecvError> for KeybaseInternalError {
     fn from(_: std::sync::mpsc::RecvError) -> KeybaseInternalError {
-        KeybaseInternalError::IoError
+        KeybaseInternalError::Closed
     }
 }
 
 impl From<std::sync::mpsc::SendError<KeybaseReply>> for KeybaseInternalError {
     fn from(_: std::sync::mpsc::SendError<KeybaseReply>) -> KeybaseInternalError {
-        KeybaseInternalError::IoError
+        KeybaseInternalError::Closed
     }
 }
 
@@ -91,6 +230,17 @@ fn safe_json_to_string(v: &Value) -> String {
     }
 }
 
+/// Callback shared with the backend threads, see `Keybase::set_wakeup`.
+type Wakeup = Arc<Mutex<Option<Box<dyn Fn() + Send>>>>;
+
+fn wake(wakeup: &Wakeup) {
+    if let Some(ref f) = *wakeup.lock().unwrap() {
+        f();
+    }
+}
+
+/// Handle to the Keybase backend threads. The threads are stopped when this
+/// is dropped.
 pub struct Keybase {
     is_running: Arc<AtomicBool>,
     listener_thread: Option<JoinHandle<()>>,
@@ -98,28 +248,32 @@ pub struct Keybase {
     incoming_tx: Sender<KeybaseReply>,
     incoming_rx: Receiver<KeybaseReply>,
     outgoing_tx: Sender<KeybaseRequest>,
+    wakeup: Wakeup,
 }
 
 impl Drop for Keybase {
     fn drop(&mut self) {
-        println!("Destructing Keybase.");
+        eprintln!("Destructing Keybase.");
         self.is_running.swap(false, SeqCst);
 
         if let Some(handle) = self.api_thread.take() {
-            println!("Joining API thread back.");
-            let empty_msg = KeybaseRequest { msg: Value::Null };
+            eprintln!("Joining API thread back.");
+            let empty_msg = KeybaseRequest {
+                msg: Value::Null,
+                reply_tx: None,
+            };
             match self.outgoing_tx.send(empty_msg) {
                 Ok(_) => {
                     handle.join().expect("API thread join failed.");
                 }
                 Err(_) => {
-                    println!("Can't join API thread.");
+                    eprintln!("Can't join API thread.");
                 }
             }
         }
 
         if let Some(_handle) = self.listener_thread.take() {
-            println!("Joining listener thread back.");
+            eprintln!("Joining listener thread back.");
             // TODO:
             //handle.join().expect("Listener thread join failed.");
         }
@@ -127,7 +281,18 @@ impl Drop for Keybase {
 }
 
 impl Keybase {
+    /// Spawns the API and listener threads.
     pub fn new() -> Self {
+        return Keybase::start(true);
+    }
+
+    /// Spawns only the API thread. Nothing but replies to requests will be
+    /// received.
+    pub fn new_api_only() -> Self {
+        return Keybase::start(false);
+    }
+
+    fn start(with_listener: bool) -> Self {
         let (outgoing_tx, outgoing_rx): (Sender<KeybaseRequest>, Receiver<KeybaseRequest>) =
             mpsc::channel();
         let (incoming_tx, incoming_rx): (Sender<KeybaseReply>, Receiver<KeybaseReply>) =
@@ -139,9 +304,12 @@ impl Keybase {
             incoming_tx: incoming_tx,
             incoming_rx: incoming_rx,
             outgoing_tx: outgoing_tx,
+            wakeup: Arc::new(Mutex::new(None)),
         };
 
-        ret.listen_new_kb_msgs();
+        if with_listener {
+            ret.listen_new_kb_msgs();
+        }
         ret.start_api_loop(outgoing_rx);
         return ret;
     }
@@ -150,146 +318,272 @@ impl Keybase {
         stdout: &mut BufReader<ChildStdout>,
     ) -> Result<KeybaseReply, KeybaseInternalError> {
         let mut s = String::new();
-        stdout.read_line(&mut s)?;
+        if stdout.read_line(&mut s)? == 0 {
+            // The process is gone.
+            return Err(KeybaseInternalError::IoError);
+        }
 
         let parsed = Keybase::parse_json(&s)?;
         let keyb_msg = Keybase::to_keybase_msg(&parsed)?;
         return Ok(keyb_msg);
     }
 
-    fn listen_new_kb_msgs(&mut self) {
-        println!("Spawning listener thread");
+    /// Passes on what `keybase chat api-listen` prints until it stops.
+    /// Returns why it stopped, or Ok if it was asked to.
+    fn run_listener(
+        tx: &Sender<KeybaseReply>,
+        wakeup: &Wakeup,
+        is_running: &AtomicBool,
+    ) -> Result<(), String> {
+        // keybase chat api-listen
+        let mut process = match Command::new("keybase")
+            .arg("chat")
+            .arg("api-listen")
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Err(err) => return Err(format!("Couldn't spawn API listener: {}", err)),
+            Ok(process) => process,
+        };
 
-        let is_running = Arc::clone(&self.is_running);
-        let tx = self.incoming_tx.clone();
-        self.listener_thread = Some(thread::spawn(move || {
-            // keybase chat api-listen
-            let process = match Command::new("keybase")
-                .arg("chat")
-                .arg("api-listen")
-                .stdout(Stdio::piped())
-                .spawn()
-            {
-                Err(err) => panic!("Couldn't spawn API listener: {}", err.description()),
-                Ok(process) => process,
-            };
+        let proc_stdout = match process.stdout.take() {
+            Some(proc_stdout) => proc_stdout,
+            None => return Err("Couldn't map stdout.".to_string()),
+        };
 
-            let proc_stdout = match process.stdout {
-                Some(proc_stdout) => proc_stdout,
-                None => panic!("Couldn't map stdout."),
-            };
+        eprintln!("Starting listen loop.");
+        let mut stdout_buf = BufReader::new(proc_stdout);
+        let result = loop {
+            if !is_running.load(SeqCst) {
+                break Ok(());
+            }
 
-            println!("Starting listen loop.");
-            let mut stdout_buf = BufReader::new(proc_stdout);
-            loop {
-                if is_running.load(SeqCst) == false {
-                    break;
+            let keyb_msg = match Keybase::get_next_message(&mut stdout_buf) {
+                Err(KeybaseInternalError::IoError) => {
+                    break Err("Lost keybase chat api-listen".to_string());
                 }
+                Err(_) => continue,
+                Ok(keyb_msg) => keyb_msg,
+            };
 
-                let keyb_msg = match Keybase::get_next_message(&mut stdout_buf) {
-                    Err(KeybaseInternalError::IoError) => {
-                        // IO-error can't be recovered for now.
-                        panic!("Error in listen loop loop.");
-                    }
-                    Err(_) => continue,
-                    Ok(keyb_msg) => keyb_msg,
-                };
+            match tx.send(keyb_msg) {
+                Ok(_) => wake(wakeup),
+                Err(_) => break Ok(()),
+            }
+        };
+        let _ = process.kill();
+        let _ = process.wait();
+        return result;
+    }
 
-                match &keyb_msg {
-                    KeybaseReply::ChatMsgReply { msg } => {
-                        notification::send_desktop_notification(&format!(
-                            "Keybase: New message from {}",
-                            msg.channel
-                        ));
-                    }
-                    // Ignore other types.
-                    _ => (),
-                }
+    fn listen_new_kb_msgs(&mut self) {
+        eprintln!("Spawning listener thread");
 
-                match tx.send(keyb_msg) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        println!("Error sending: {}", err);
-                    }
+        let is_running = Arc::clone(&self.is_running);
+        let tx = self.incoming_tx.clone();
+        let wakeup = Arc::clone(&self.wakeup);
+        self.listener_thread = Some(thread::spawn(move || {
+            // Whoever reads the messages has to learn that no more come,
+            // also when the listener panics.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                return Keybase::run_listener(&tx, &wakeup, &is_running);
+            }));
+            let error = match result {
+                Ok(Ok(())) => None,
+                Ok(Err(error)) => Some(error),
+                Err(_) => Some("The listener crashed".to_string()),
+            };
+            if let Some(error) = error {
+                eprintln!("{}", error);
+                if tx
+                    .send(KeybaseReply::ListenerStopped { error: error })
+                    .is_ok()
+                {
+                    wake(&wakeup);
                 }
             }
 
-            println!("Closing listener thread.");
+            eprintln!("Closing listener thread.");
         }));
     }
 
+    /// Writes `msg` to the API and reads the answer.
+    fn api_call(
+        stdin: &mut ChildStdin,
+        stdout: &mut BufReader<ChildStdout>,
+        msg: &Value,
+    ) -> Result<String, KeybaseInternalError> {
+        let json_str = serde_json::to_string(msg)?;
+        stdin.write_all(json_str.as_bytes())?;
+
+        let mut s = String::new();
+        if stdout.read_line(&mut s)? == 0 {
+            // The process is gone.
+            return Err(KeybaseInternalError::IoError);
+        }
+        return Ok(s);
+    }
+
     fn handle_next_call(
         stdin: &mut ChildStdin,
         stdout: &mut BufReader<ChildStdout>,
         rx: &Receiver<KeybaseRequest>,
         tx: &Sender<KeybaseReply>,
+        wakeup: &Wakeup,
     ) -> Result<(), KeybaseInternalError> {
         let new_msg = rx.recv()?;
         if new_msg.msg == Value::Null {
             return Err(KeybaseInternalError::ParseError);
         }
 
-        let json_str = serde_json::to_string(&new_msg.msg)?;
-        stdin.write(json_str.as_bytes())?;
-
-        let mut s = String::new();
-        stdout.read_line(&mut s)?;
-        let parsed = Keybase::parse_json(&s)?;
+        let s = match Keybase::api_call(stdin, stdout, &new_msg.msg) {
+            Ok(s) => s,
+            Err(err) => {
+                let method = new_msg.msg["method"].as_str().unwrap_or("request");
+                let reply = match err {
+                    KeybaseInternalError::IoError => KeybaseReply::Disconnected {
+                        error: format!("Lost the connection to Keybase, {} was not done", method),
+                    },
+                    _ => KeybaseReply::ErrorReply {
+                        error: format!("Couldn't encode the {} request", method),
+                    },
+                };
+                // Without a reply channel the failure goes to the receiver
+                // of all other replies.
+                let sent = match new_msg.reply_tx {
+                    Some(reply_tx) => reply_tx.send(reply).is_ok(),
+                    None => tx.send(reply).is_ok(),
+                };
+                if sent {
+                    wake(wakeup);
+                }
+                return Err(err);
+            }
+        };
+        let keyb_msg = match Keybase::parse_json(&s).and_then(|v| Keybase::to_keybase_msg(&v)) {
+            Ok(keyb_msg) => keyb_msg,
+            Err(err) => {
+                // Callers waiting for a reply must always get one.
+                if let Some(reply_tx) = new_msg.reply_tx {
+                    let error = "Unexpected reply from Keybase".to_string();
+                    let _ = reply_tx.send(KeybaseReply::ErrorReply { error: error });
+                    wake(wakeup);
+                }
+                return Err(err);
+            }
+        };
 
-        let keyb_msg = Keybase::to_keybase_msg(&parsed)?;
-        tx.send(keyb_msg)?;
+        match new_msg.reply_tx {
+            Some(reply_tx) => {
+                // The caller may have timed out already.
+                if reply_tx.send(keyb_msg).is_ok() {
+                    wake(wakeup);
+                }
+            }
+            None => {
+                tx.send(keyb_msg)?;
+                wake(wakeup);
+            }
+        }
         Ok(())
     }
 
+    /// Talks to one `keybase chat api` process until it stops. Returns Ok if
+    /// the API thread should stop too, or why the process stopped.
+    /// `connected` is false after an outage and set again once the process
+    /// is up.
+    fn run_api_process(
+        outgoing_rx: &Receiver<KeybaseRequest>,
+        tx: &Sender<KeybaseReply>,
+        wakeup: &Wakeup,
+        is_running: &AtomicBool,
+        connected: &mut bool,
+    ) -> Result<(), String> {
+        // keybase chat api
+        let mut process = match Command::new("keybase")
+            .arg("chat")
+            .arg("api")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Err(why) => return Err(format!("Couldn't start keybase chat api: {}", why)),
+            Ok(process) => process,
+        };
+
+        let mut stdin = match process.stdin.take() {
+            Some(stdin) => stdin,
+            None => return Err("Couldn't map stdin.".to_string()),
+        };
+        let stdout = match process.stdout.take() {
+            Some(stdout) => stdout,
+            None => return Err("Couldn't map stdout.".to_string()),
+        };
+        if !*connected {
+            *connected = true;
+            let _ = tx.send(KeybaseReply::Reconnected);
+            wake(wakeup);
+        }
+
+        eprintln!("Starting API msg loop.");
+        let mut stdout_buf = BufReader::new(stdout);
+        let result = loop {
+            if !is_running.load(SeqCst) {
+                break Ok(());
+            }
+
+            match Keybase::handle_next_call(&mut stdin, &mut stdout_buf, outgoing_rx, tx, wakeup) {
+                Err(KeybaseInternalError::IoError) => {
+                    break Err("Lost the connection to keybase chat api".to_string());
+                }
+                Err(KeybaseInternalError::Closed) => break Ok(()),
+                Err(_) => continue,
+                Ok(()) => continue,
+            }
+        };
+        let _ = process.kill();
+        let _ = process.wait();
+        return result;
+    }
+
     fn start_api_loop(&mut self, outgoing_rx: Receiver<KeybaseRequest>) {
-        println!("Spawning input thread");
+        eprintln!("Spawning input thread");
 
         let tx = self.incoming_tx.clone();
+        let wakeup = Arc::clone(&self.wakeup);
         let is_running = Arc::clone(&self.is_running);
         self.api_thread = Some(thread::spawn(move || {
-            // keybase chat api
-            let mut process = match Command::new("keybase")
-                .arg("chat")
-                .arg("api")
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-            {
-                Err(why) => panic!("Couldn't spawn keybase comms thread: {}", why.description()),
-                Ok(process) => process,
-            };
-
-            println!("Starting API msg loop.");
-            let mut stdin = match process.stdin.as_mut() {
-                Some(stdin) => stdin,
-                None => panic!("Couldn't map stdin."),
-            };
-
-            let stdout = match process.stdout {
-                Some(stdout) => stdout,
-                None => panic!("Couldn't map stdout."),
-            };
-
-            let mut stdout_buf = BufReader::new(stdout);
-            loop {
-                if is_running.load(SeqCst) == false {
-                    break;
+            // Starts the process again whenever it stops, telling the
+            // receiver once per outage.
+            let mut connected = true;
+            while is_running.load(SeqCst) {
+                if !connected {
+                    thread::sleep(RECONNECT_DELAY);
                 }
-
-                match Keybase::handle_next_call(&mut stdin, &mut stdout_buf, &outgoing_rx, &tx) {
-                    Err(KeybaseInternalError::IoError) => {
-                        // IO-error can't be recovered for now.
-                        panic!("Error in API loop.");
+                match Keybase::run_api_process(
+                    &outgoing_rx,
+                    &tx,
+                    &wakeup,
+                    &is_running,
+                    &mut connected,
+                ) {
+                    Ok(()) => break,
+                    Err(error) => {
+                        eprintln!("{}", error);
+                        if connected {
+                            connected = false;
+                            let _ = tx.send(KeybaseReply::Disconnected { error: error });
+                            wake(&wakeup);
+                        }
                     }
-                    Err(_) => continue,
-                    Ok(()) => continue,
                 }
             }
 
-            println!("Closing API thread.");
+            eprintln!("Closing API thread.");
         }));
     }
 
+    /// Runs `keybase login`, which may prompt on the terminal.
     pub fn login(&self) -> Result<(), String> {
         let status = match Command::new("keybase").arg("login").status() {
             Ok(s) => s,
@@ -302,14 +596,72 @@ impl Keybase {
         Ok(())
     }
 
+    /// Returns the username of the logged in user.
+    pub fn get_username(&self) -> Result<String, String> {
+        let output = match Command::new("keybase").arg("status").arg("--json").output() {
+            Ok(output) => output,
+            Err(_) => return Err("Spawning keybase process failed".to_string()),
+        };
+
+        let status: Value = match serde_json::from_slice(&output.stdout) {
+            Ok(status) => status,
+            Err(_) => return Err("Can't parse keybase status".to_string()),
+        };
+
+        match status["Username"].as_str() {
+            Some(username) if !username.is_empty() => Ok(username.to_string()),
+            _ => Err("Not logged in".to_string()),
+        }
+    }
+
+    /// Receiver for all replies and incoming messages.
     pub fn get_message_receiver(&self) -> &Receiver<KeybaseReply> {
         return &self.incoming_rx;
     }
 
+    /// Sets a callback that is called from the backend threads whenever a
+    /// reply or message can be read from `get_message_receiver` or the
+    /// `reply_tx` of a request. Use it to
+    /// wake up a thread that would otherwise have to poll.
+    pub fn set_wakeup<F: Fn() + Send + 'static>(&self, f: F) {
+        *self.wakeup.lock().unwrap() = Some(Box::new(f));
+    }
+
+    /// Sender for requests to the API thread. Can be cloned freely.
     pub fn get_message_sender(&self) -> Sender<KeybaseRequest> {
         return self.outgoing_tx.clone();
     }
 
+    /// Sends `req` and blocks until its reply arrives. Error replies, timeouts
+    /// and a stopped backend are returned as `Err`.
+    pub fn call(&self, req: KeybaseRequest) -> Result<KeybaseReply, String> {
+        return Keybase::call_with_sender(&self.outgoing_tx, req);
+    }
+
+    /// Like `call`, but usable from threads that only hold a sender from
+    /// `get_message_sender`.
+    pub fn call_with_sender(
+        sender: &Sender<KeybaseRequest>,
+        mut req: KeybaseRequest,
+    ) -> Result<KeybaseReply, String> {
+        let (reply_tx, reply_rx) = mpsc::channel();
+        req.reply_tx = Some(reply_tx);
+        if sender.send(req).is_err() {
+            return Err("Keybase API thread is not running".to_string());
+        }
+
+        match reply_rx.recv_timeout(Duration::from_secs(API_TIMEOUT_SECS)) {
+            Ok(KeybaseReply::ErrorReply { error }) => Err(error),
+            Ok(KeybaseReply::Disconnected { error }) => Err(error),
+            Ok(reply) => Ok(reply),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err("Keybase API timed out".to_string()),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err("Keybase API thread stopped".to_string())
+            }
+        }
+    }
+
+    /// Creates a request that sends `text` to a conversation.
     pub fn create_msg_req(conversation_id: &str, text: &str) -> KeybaseRequest {
         KeybaseRequest {
             msg: json!({
@@ -321,9 +673,124 @@ impl Keybase {
                     }
                 }
             }),
+            reply_tx: None,
+        }
+    }
+
+    /// Creates a request that sends `text` to a conversation given by name,
+    /// e.g. a username for a private conversation.
+    pub fn create_msg_to_name_req(name: &str, text: &str) -> KeybaseRequest {
+        KeybaseRequest {
+            msg: json!({
+                "method": "send",
+                "params": {
+                    "options": {
+                        "channel": {"name": name},
+                        "message": {"body": text}
+                    }
+                }
+            }),
+            reply_tx: None,
+        }
+    }
+
+    /// Creates a request that sends `text` to a conversation as an exploding
+    /// message. `lifetime` is a duration like `10m` or `1h`.
+    pub fn create_exploding_msg_req(
+        conversation_id: &str,
+        text: &str,
+        lifetime: &str,
+    ) -> KeybaseRequest {
+        KeybaseRequest {
+            msg: json!({
+                "method": "send",
+                "params": {
+                    "options": {
+                        "conversation_id": conversation_id,
+                        "message": {"body": text},
+                        "exploding_lifetime": lifetime
+                    }
+                }
+            }),
+            reply_tx: None,
+        }
+    }
+
+    /// Creates a request that replaces the text of one of our messages.
+    pub fn create_edit_req(conversation_id: &str, message_id: u64, text: &str) -> KeybaseRequest {
+        KeybaseRequest {
+            msg: json!({
+                "method": "edit",
+                "params": {
+                    "options": {
+                        "conversation_id": conversation_id,
+                        "message_id": message_id,
+                        "message": {"body": text}
+                    }
+                }
+            }),
+            reply_tx: None,
+        }
+    }
+
+    /// Creates a request that reacts to a message with `reaction`, e.g.
+    /// `:+1:`.
+    pub fn create_reaction_req(
+        conversation_id: &str,
+        message_id: u64,
+        reaction: &str,
+    ) -> KeybaseRequest {
+        KeybaseRequest {
+            msg: json!({
+                "method": "reaction",
+                "params": {
+                    "options": {
+                        "conversation_id": conversation_id,
+                        "message_id": message_id,
+                        "message": {"body": reaction}
+                    }
+                }
+            }),
+            reply_tx: None,
+        }
+    }
+
+    /// Creates a request that joins (`method` "join") or leaves ("leave") a
+    /// team channel given as `team#channel`.
+    fn create_channel_membership_req(method: &str, channel: &str) -> KeybaseRequest {
+        let (team, topic) = match channel.find('#') {
+            Some(pos) => (&channel[..pos], &channel[pos + 1..]),
+            None => (channel, "general"),
+        };
+        KeybaseRequest {
+            msg: json!({
+                "method": method,
+                "params": {
+                    "options": {
+                        "channel": {
+                            "name": team,
+                            "members_type": "team",
+                            "topic_name": topic
+                        }
+                    }
+                }
+            }),
+            reply_tx: None,
         }
     }
 
+    /// Creates a request that joins a team channel given as `team#channel`.
+    pub fn create_join_req(channel: &str) -> KeybaseRequest {
+        return Keybase::create_channel_membership_req("join", channel);
+    }
+
+    /// Creates a request that leaves a team channel given as `team#channel`.
+    pub fn create_leave_req(channel: &str) -> KeybaseRequest {
+        return Keybase::create_channel_membership_req("leave", channel);
+    }
+
+    /// Creates a request for the newest `num_msgs` messages of a conversation.
+    /// The reply is a `KeybaseReply::ChatMsgListReply`.
     pub fn create_read_conversation_req(conversation_id: &str, num_msgs: usize) -> KeybaseRequest {
         KeybaseRequest {
             msg: json!({
@@ -337,14 +804,66 @@ impl Keybase {
                     }
                 }
             }),
+            reply_tx: None,
         }
     }
 
+    /// Creates a request for at most `max_hits` messages of a conversation
+    /// that contain `query`. The reply is a `KeybaseReply::SearchReply`.
+    pub fn create_search_req(
+        conversation_id: &str,
+        query: &str,
+        max_hits: usize,
+    ) -> KeybaseRequest {
+        KeybaseRequest {
+            msg: json!({
+                "method": "searchregexp",
+                "params": {
+                    "options": {
+                        "conversation_id": conversation_id,
+                        "query": query,
+                        "is_regex": false,
+                        "max_hits": max_hits
+                    }
+                }
+            }),
+            reply_tx: None,
+        }
+    }
+
+    /// Sets the headline of the team channel `channel` in `team`. The chat
+    /// API can't do this, so it runs `keybase chat send --set-headline`.
+    pub fn set_headline(team: &str, channel: &str, text: &str) -> Result<(), String> {
+        let output = match Command::new("keybase")
+            .args([
+                "chat",
+                "send",
+                "--channel",
+                channel,
+                "--set-headline",
+                text,
+                team,
+            ])
+            .stdin(Stdio::null())
+            .output()
+        {
+            Ok(output) => output,
+            Err(_) => return Err("Spawning keybase process failed".to_string()),
+        };
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        return Ok(());
+    }
+
+    /// Creates a request for all conversations. The reply is a
+    /// `KeybaseReply::ChannelListReply`.
     pub fn create_list_channels_req() -> KeybaseRequest {
         KeybaseRequest {
             msg: json!({
                 "method": "list"
             }),
+            reply_tx: None,
         }
     }
 
@@ -352,11 +871,11 @@ impl Keybase {
         match serde_json::from_str(&json_str) {
             Ok(val) => {
                 // For debugging.
-                // println!("{}", safe_json_to_string(&val));
+                // eprintln!("{}", safe_json_to_string(&val));
                 Ok(val)
             }
             Err(err) => {
-                println!("Parse error: {}", err);
+                eprintln!("Parse error: {}", err);
                 Err(KeybaseInternalError::ParseError)
             }
         }
@@ -365,8 +884,8 @@ impl Keybase {
     fn parse_chat_msg(v: &Value) -> Result<ChatMsg, KeybaseInternalError> {
         if v["msg"]["content"]["type"] == "text" {
             let ts_unix_epoch = v["msg"]["sent_at"].to_string().parse()?;
-            let channel = match v["msg"]["sender"]["username"].as_str() {
-                Some(channel) => channel.to_string(),
+            let sender = match v["msg"]["sender"]["username"].as_str() {
+                Some(sender) => sender.to_string(),
                 None => return Err(KeybaseInternalError::ParseError),
             };
             let text = match v["msg"]["content"]["text"]["body"].as_str() {
@@ -379,14 +898,34 @@ impl Keybase {
                 None => return Err(KeybaseInternalError::ParseError),
             };
 
+            let at_mentions = match v["msg"]["at_mention_usernames"].as_array() {
+                Some(names) => names
+                    .iter()
+                    .filter_map(|n| n.as_str())
+                    .map(String::from)
+                    .collect(),
+                None => Vec::new(),
+            };
+            let utc_timestamp = match chrono::DateTime::from_timestamp(ts_unix_epoch, 0) {
+                Some(time) => time.naive_utc(),
+                None => return Err(KeybaseInternalError::ParseError),
+            };
+            let channel_mention = matches!(
+                v["msg"]["channel_mention"].as_str(),
+                Some("all") | Some("here")
+            );
+
             return Ok(ChatMsg {
-                utc_timestamp: NaiveDateTime::from_timestamp(ts_unix_epoch, 0),
-                channel: channel,
+                id: v["msg"]["id"].as_u64().unwrap_or(0),
+                utc_timestamp: utc_timestamp,
+                sender: sender,
                 conversation_id: conversation_id,
                 text: text,
+                at_mentions: at_mentions,
+                channel_mention: channel_mention,
             });
         }
-        println!("Not a chat msg: {}", safe_json_to_string(&v));
+        eprintln!("Not a chat msg: {}", safe_json_to_string(v));
         return Err(KeybaseInternalError::ParseError);
     }
 
@@ -394,7 +933,7 @@ impl Keybase {
         match Keybase::parse_chat_msg(&v) {
             Ok(chat_msg) => return Ok(KeybaseReply::ChatMsgReply { msg: chat_msg }),
             Err(err) => {
-                println!("Not a chat msg: {}.", safe_json_to_string(&v));
+                eprintln!("Not a chat msg: {}.", safe_json_to_string(v));
                 return Err(err);
             }
         }
@@ -404,7 +943,7 @@ impl Keybase {
         let messages = match v["result"]["messages"].as_array() {
             Some(messages) => messages,
             None => {
-                println!("Not a chat msg list: {}", safe_json_to_string(&v));
+                eprintln!("Not a chat msg list: {}", safe_json_to_string(v));
                 return Err(KeybaseInternalError::ParseError);
             }
         };
@@ -416,18 +955,36 @@ impl Keybase {
                     ret.push(chat_msg);
                 }
                 Err(_) => {
-                    println!("Skipped message: {}", safe_json_to_string(&v));
+                    eprintln!("Skipped message: {}", safe_json_to_string(v));
                 }
             }
         }
         return Ok(KeybaseReply::ChatMsgListReply { msgs: ret });
     }
 
+    fn create_search_reply(v: &Value) -> Result<KeybaseReply, KeybaseInternalError> {
+        let hits = match v["result"]["hits"].as_array() {
+            Some(hits) => hits,
+            None => return Err(KeybaseInternalError::InvalidMessageFormat),
+        };
+
+        let mut ret = Vec::new();
+        for hit in hits {
+            // Hits have the message where `read` has it in `msg`.
+            match Keybase::parse_chat_msg(&json!({ "msg": hit["hit_msg"] })) {
+                Ok(chat_msg) => ret.push(chat_msg),
+                Err(_) => eprintln!("Skipped search hit: {}", safe_json_to_string(hit)),
+            }
+        }
+        ret.sort_by_key(|m| m.utc_timestamp);
+        return Ok(KeybaseReply::SearchReply { msgs: ret });
+    }
+
     fn create_channel_list_reply(v: &Value) -> Result<KeybaseReply, KeybaseInternalError> {
         let conversations = match v["result"]["conversations"].as_array() {
             Some(converstations) => converstations,
             None => {
-                println!("Not a channel list: {}", safe_json_to_string(&v),);
+                eprintln!("Not a channel list: {}", safe_json_to_string(v),);
                 return Err(KeybaseInternalError::InvalidMessageFormat);
             }
         };
@@ -470,13 +1027,38 @@ impl Keybase {
         return Ok(KeybaseReply::ChannelListReply { channels: ret });
     }
 
+    fn create_sent_reply(v: &Value) -> Result<KeybaseReply, KeybaseInternalError> {
+        match v["result"]["id"].as_u64() {
+            Some(id) => Ok(KeybaseReply::SendReply { id: id }),
+            // Edits and reactions may only say "message sent".
+            None if v["result"]["message"].is_string() => Ok(KeybaseReply::SendReply { id: 0 }),
+            None => Err(KeybaseInternalError::InvalidMessageFormat),
+        }
+    }
+
+    fn create_error_reply(v: &Value) -> Result<KeybaseReply, KeybaseInternalError> {
+        let error = match v["error"]["message"].as_str() {
+            Some(error) => error.to_string(),
+            None => safe_json_to_string(&v["error"]),
+        };
+        return Ok(KeybaseReply::ErrorReply { error: error });
+    }
+
     fn get_msg_type(v: &Value) -> MsgType {
         if v["type"] == "chat" && v["msg"]["content"]["type"] == "text" {
             return MsgType::ChatMsg;
         } else if v["result"]["messages"].is_array() {
             return MsgType::ChatMsgList;
+        } else if v["result"]["hits"].is_array() {
+            return MsgType::SearchHits;
         } else if v["result"]["conversations"].is_array() {
             return MsgType::ChannelList;
+        } else if v["result"]["id"].is_u64() || v["result"]["message"].is_string() {
+            return MsgType::Sent;
+        } else if v["error"].is_object() {
+            return MsgType::Error;
+        } else if v["result"].is_object() {
+            return MsgType::Done;
         }
         return MsgType::Unknown;
     }
@@ -507,10 +1089,114 @@ impl Keybase {
                     return Err(err);
                 }
             },
+            MsgType::SearchHits => Keybase::create_search_reply(v),
+            MsgType::Sent => Keybase::create_sent_reply(v),
+            MsgType::Error => Keybase::create_error_reply(v),
+            MsgType::Done => Ok(KeybaseReply::Done),
             MsgType::Unknown => {
-                println!("Unknown message: {}", safe_json_to_string(&v));
+                eprintln!("Unknown message: {}", safe_json_to_string(v));
                 return Err(KeybaseInternalError::UnknownMessage);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat_msg_json() -> Value {
+        return json!({
+            "type": "chat",
+            "msg": {
+                "id": 42,
+                "conversation_id": "c1",
+                "sender": { "username": "alice" },
+                "sent_at": 1600000000,
+                "content": { "type": "text", "text": { "body": " hi @bob @here " } },
+                "at_mention_usernames": ["bob"],
+                "channel_mention": "here"
+            }
+        });
+    }
+
+    #[test]
+    fn test_chat_msg() {
+        let msg = match Keybase::to_keybase_msg(&chat_msg_json()) {
+            Ok(KeybaseReply::ChatMsgReply { msg }) => msg,
+            other => panic!("Unexpected reply: {:?}", other),
+        };
+        assert_eq!(msg.id, 42);
+        assert_eq!(msg.sender, "alice");
+        assert_eq!(msg.conversation_id, "c1");
+        assert_eq!(msg.text, "hi @bob @here");
+        assert_eq!(msg.at_mentions, vec!["bob"]);
+        assert!(msg.channel_mention);
+        assert_eq!(
+            msg.utc_timestamp,
+            chrono::DateTime::from_timestamp(1600000000, 0)
+                .unwrap()
+                .naive_utc()
+        );
+
+        let mut v = chat_msg_json();
+        v["msg"]["channel_mention"] = json!("none");
+        v["msg"]["at_mention_usernames"] = Value::Null;
+        let msg = Keybase::parse_chat_msg(&v).unwrap();
+        assert!(msg.at_mentions.is_empty());
+        assert!(!msg.channel_mention);
+
+        v["msg"]["sender"] = Value::Null;
+        assert!(Keybase::parse_chat_msg(&v).is_err());
+    }
+
+    #[test]
+    fn test_sent() {
+        match Keybase::to_keybase_msg(&json!({ "result": { "id": 43 } })) {
+            Ok(KeybaseReply::SendReply { id }) => assert_eq!(id, 43),
+            other => panic!("Unexpected reply: {:?}", other),
+        }
+        // Edits and reactions don't give the new message id.
+        match Keybase::to_keybase_msg(&json!({ "result": { "message": "message sent" } })) {
+            Ok(KeybaseReply::SendReply { id }) => assert_eq!(id, 0),
+            other => panic!("Unexpected reply: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error() {
+        let v = json!({ "error": { "code": 0, "message": "no such conversation" } });
+        match Keybase::to_keybase_msg(&v) {
+            Ok(KeybaseReply::ErrorReply { error }) => assert_eq!(error, "no such conversation"),
+            other => panic!("Unexpected reply: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_search_hits() {
+        let mut newer = chat_msg_json()["msg"].clone();
+        newer["sent_at"] = json!(1600000100);
+        let older = chat_msg_json()["msg"].clone();
+        let v = json!({
+            "result": {
+                "hits": [{ "hit_msg": newer }, { "hit_msg": older }, { "hit_msg": {} }]
+            }
+        });
+        let msgs = match Keybase::to_keybase_msg(&v) {
+            Ok(KeybaseReply::SearchReply { msgs }) => msgs,
+            other => panic!("Unexpected reply: {:?}", other),
+        };
+        // Oldest first, broken hits skipped.
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[0].utc_timestamp < msgs[1].utc_timestamp);
+    }
+
+    #[test]
+    fn test_done() {
+        match Keybase::to_keybase_msg(&json!({ "result": {} })) {
+            Ok(KeybaseReply::Done) => {}
+            other => panic!("Unexpected reply: {:?}", other),
+        }
+        assert!(Keybase::to_keybase_msg(&json!({ "foo": 1 })).is_err());
+    }
+}
@@ -0,0 +1,46 @@
+//! Lightweight Keybase chat client library.
+//!
+//! The crate wraps the Keybase command line client (`keybase chat api` and
+//! `keybase chat api-listen`) behind a small message passing backend and
+//! provides the models and helpers that the kbchatbox GUI is built from.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use kbchatbox::{Keybase, KeybaseReply};
+//!
+//! let kb = Keybase::new();
+//! let sender = kb.get_message_sender();
+//! sender.send(Keybase::create_list_channels_req()).unwrap();
+//!
+//! match kb.get_message_receiver().recv().unwrap() {
+//!     KeybaseReply::ChannelListReply { channels } => {
+//!         for chan in channels {
+//!             println!("{}", chan.name);
+//!         }
+//!     }
+//!     _ => {}
+//! }
+//! ```
+
+extern crate chrono;
+extern crate linkify;
+extern crate serde_json;
+extern crate unicode_segmentation;
+extern crate unicode_width;
+
+pub mod appstate;
+pub mod commands;
+pub mod completion;
+pub mod compose;
+pub mod config;
+pub mod hub;
+pub mod keybase;
+pub mod markdown;
+pub mod mentions;
+pub mod notification;
+pub mod policy;
+pub mod textbuffer;
+
+pub use keybase::{Channel, ChatMsg, Keybase, KeybaseReply, KeybaseRequest};
+pub use textbuffer::TextBuffer;
@@ -0,0 +1,759 @@
+//! Parts of libui that iui doesn't wrap: areas, text drawing, the
+//! clipboard, Enter in a multiline entry and waking up the event loop from
+//! other threads.
+//!
+//! The structs in ui-sys don't match the libui it builds, so the ones used
+//! here are declared again from `ui.h`.
+
+use iui::controls::{Control, MultilineEntry, VerticalBox};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_double, c_int, c_uint, c_void};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::SeqCst;
+
+#[allow(non_camel_case_types)]
+enum uiArea {}
+#[allow(non_camel_case_types)]
+enum uiDrawContext {}
+#[allow(non_camel_case_types)]
+enum uiDrawPath {}
+#[allow(non_camel_case_types)]
+enum uiAttributedString {}
+#[allow(non_camel_case_types)]
+enum uiAttribute {}
+#[allow(non_camel_case_types)]
+enum uiDrawTextLayout {}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct uiAreaHandler {
+    Draw: extern "C" fn(*mut uiAreaHandler, *mut uiArea, *mut uiAreaDrawParams),
+    MouseEvent: extern "C" fn(*mut uiAreaHandler, *mut uiArea, *mut uiAreaMouseEvent),
+    MouseCrossed: extern "C" fn(*mut uiAreaHandler, *mut uiArea, c_int),
+    DragBroken: extern "C" fn(*mut uiAreaHandler, *mut uiArea),
+    KeyEvent: extern "C" fn(*mut uiAreaHandler, *mut uiArea, *mut uiAreaKeyEvent) -> c_int,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct uiAreaMouseEvent {
+    X: c_double,
+    Y: c_double,
+    AreaWidth: c_double,
+    AreaHeight: c_double,
+    Down: c_int,
+    Up: c_int,
+    Count: c_int,
+    Modifiers: c_uint,
+    Held1To64: u64,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct uiAreaKeyEvent {
+    Key: c_char,
+    ExtKey: c_uint,
+    Modifier: c_uint,
+    Modifiers: c_uint,
+    Up: c_int,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct uiAreaDrawParams {
+    Context: *mut uiDrawContext,
+    AreaWidth: c_double,
+    AreaHeight: c_double,
+    ClipX: c_double,
+    ClipY: c_double,
+    ClipWidth: c_double,
+    ClipHeight: c_double,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct uiDrawBrush {
+    Type: c_uint,
+    R: c_double,
+    G: c_double,
+    B: c_double,
+    A: c_double,
+    X0: c_double,
+    Y0: c_double,
+    X1: c_double,
+    Y1: c_double,
+    OuterRadius: c_double,
+    Stops: *mut c_void,
+    NumStops: usize,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct uiFontDescriptor {
+    Family: *mut c_char,
+    Size: c_double,
+    Weight: c_uint,
+    Italic: c_uint,
+    Stretch: c_uint,
+}
+
+#[repr(C)]
+#[allow(non_snake_case)]
+struct uiDrawTextLayoutParams {
+    String: *mut uiAttributedString,
+    DefaultFont: *mut uiFontDescriptor,
+    Width: c_double,
+    Align: c_uint,
+}
+
+const BRUSH_SOLID: c_uint = 0;
+const FILL_MODE_WINDING: c_uint = 0;
+const TEXT_WEIGHT_NORMAL: c_uint = 400;
+const TEXT_WEIGHT_BOLD: c_uint = 700;
+const TEXT_ITALIC_NORMAL: c_uint = 0;
+const TEXT_ITALIC_ITALIC: c_uint = 2;
+const TEXT_STRETCH_NORMAL: c_uint = 4;
+const TEXT_ALIGN_LEFT: c_uint = 0;
+const UNDERLINE_SINGLE: c_uint = 1;
+
+/// Modifier key bit of `MouseEvent::modifiers` and `KeyEvent::modifiers`.
+pub const MODIFIER_CTRL: u32 = 1 << 0;
+
+/// Values of `KeyEvent::ext_key`.
+pub const EXT_KEY_HOME: u32 = 4;
+pub const EXT_KEY_END: u32 = 5;
+pub const EXT_KEY_PAGE_UP: u32 = 6;
+pub const EXT_KEY_PAGE_DOWN: u32 = 7;
+
+extern "C" {
+    fn uiQueueMain(f: extern "C" fn(*mut c_void), data: *mut c_void);
+    fn uiControlHandle(c: *mut c_void) -> usize;
+    fn uiBoxDelete(b: *mut c_void, index: c_int);
+    fn uiNewArea(ah: *mut uiAreaHandler) -> *mut uiArea;
+    fn uiAreaQueueRedrawAll(a: *mut uiArea);
+
+    fn uiDrawNewPath(fill_mode: c_uint) -> *mut uiDrawPath;
+    fn uiDrawFreePath(p: *mut uiDrawPath);
+    fn uiDrawPathAddRectangle(
+        p: *mut uiDrawPath,
+        x: c_double,
+        y: c_double,
+        w: c_double,
+        h: c_double,
+    );
+    fn uiDrawPathEnd(p: *mut uiDrawPath);
+    fn uiDrawFill(c: *mut uiDrawContext, path: *mut uiDrawPath, b: *mut uiDrawBrush);
+
+    fn uiNewAttributedString(initial: *const c_char) -> *mut uiAttributedString;
+    fn uiFreeAttributedString(s: *mut uiAttributedString);
+    fn uiAttributedStringLen(s: *const uiAttributedString) -> usize;
+    fn uiAttributedStringAppendUnattributed(s: *mut uiAttributedString, text: *const c_char);
+    fn uiAttributedStringSetAttribute(
+        s: *mut uiAttributedString,
+        a: *mut uiAttribute,
+        start: usize,
+        end: usize,
+    );
+    fn uiNewFamilyAttribute(family: *const c_char) -> *mut uiAttribute;
+    fn uiNewWeightAttribute(weight: c_uint) -> *mut uiAttribute;
+    fn uiNewItalicAttribute(italic: c_uint) -> *mut uiAttribute;
+    fn uiNewColorAttribute(r: c_double, g: c_double, b: c_double, a: c_double) -> *mut uiAttribute;
+    fn uiNewBackgroundAttribute(
+        r: c_double,
+        g: c_double,
+        b: c_double,
+        a: c_double,
+    ) -> *mut uiAttribute;
+    fn uiNewUnderlineAttribute(u: c_uint) -> *mut uiAttribute;
+
+    fn uiDrawNewTextLayout(params: *mut uiDrawTextLayoutParams) -> *mut uiDrawTextLayout;
+    fn uiDrawFreeTextLayout(tl: *mut uiDrawTextLayout);
+    fn uiDrawText(c: *mut uiDrawContext, tl: *mut uiDrawTextLayout, x: c_double, y: c_double);
+    fn uiDrawTextLayoutExtents(tl: *mut uiDrawTextLayout, w: *mut c_double, h: *mut c_double);
+}
+
+/// Turns `text` into a C string, dropping any NUL bytes.
+fn c_string(text: &str) -> CString {
+    return CString::new(text.replace('\0', "")).unwrap();
+}
+
+/// An RGBA color with components from 0.0 to 1.0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub a: f64,
+}
+
+impl Color {
+    pub const fn rgb(r: f64, g: f64, b: f64) -> Self {
+        Color {
+            r: r,
+            g: g,
+            b: b,
+            a: 1.0,
+        }
+    }
+}
+
+/// Something to draw into, valid while a draw callback runs.
+pub struct DrawContext {
+    ctx: *mut uiDrawContext,
+}
+
+impl DrawContext {
+    pub fn fill_rect(&self, x: f64, y: f64, width: f64, height: f64, color: Color) {
+        let mut brush = uiDrawBrush {
+            Type: BRUSH_SOLID,
+            R: color.r,
+            G: color.g,
+            B: color.b,
+            A: color.a,
+            X0: 0.0,
+            Y0: 0.0,
+            X1: 0.0,
+            Y1: 0.0,
+            OuterRadius: 0.0,
+            Stops: std::ptr::null_mut(),
+            NumStops: 0,
+        };
+        unsafe {
+            let path = uiDrawNewPath(FILL_MODE_WINDING);
+            uiDrawPathAddRectangle(path, x, y, width, height);
+            uiDrawPathEnd(path);
+            uiDrawFill(self.ctx, path, &mut brush);
+            uiDrawFreePath(path);
+        }
+    }
+
+    pub fn draw_text(&self, layout: &TextLayout, x: f64, y: f64) {
+        unsafe {
+            uiDrawText(self.ctx, layout.layout, x, y);
+        }
+    }
+}
+
+/// A text attribute applied to a range of an `AttributedString`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Attribute {
+    Family(String),
+    Bold,
+    Italic,
+    Color(Color),
+    Background(Color),
+    Underline,
+}
+
+/// Text with attributes on byte ranges.
+pub struct AttributedString {
+    s: *mut uiAttributedString,
+}
+
+impl AttributedString {
+    pub fn new() -> Self {
+        let empty = c_string("");
+        AttributedString {
+            s: unsafe { uiNewAttributedString(empty.as_ptr()) },
+        }
+    }
+
+    /// Appends `text` and returns the byte range it ended up in.
+    pub fn append(&mut self, text: &str) -> (usize, usize) {
+        let text = c_string(text);
+        unsafe {
+            let start = uiAttributedStringLen(self.s);
+            uiAttributedStringAppendUnattributed(self.s, text.as_ptr());
+            return (start, uiAttributedStringLen(self.s));
+        }
+    }
+
+    pub fn set_attribute(&mut self, attr: &Attribute, range: (usize, usize)) {
+        if range.0 >= range.1 {
+            return;
+        }
+        unsafe {
+            let a = match attr {
+                Attribute::Family(family) => {
+                    let family = c_string(family);
+                    uiNewFamilyAttribute(family.as_ptr())
+                }
+                Attribute::Bold => uiNewWeightAttribute(TEXT_WEIGHT_BOLD),
+                Attribute::Italic => uiNewItalicAttribute(TEXT_ITALIC_ITALIC),
+                Attribute::Color(c) => uiNewColorAttribute(c.r, c.g, c.b, c.a),
+                Attribute::Background(c) => uiNewBackgroundAttribute(c.r, c.g, c.b, c.a),
+                Attribute::Underline => uiNewUnderlineAttribute(UNDERLINE_SINGLE),
+            };
+            // The string takes ownership of the attribute.
+            uiAttributedStringSetAttribute(self.s, a, range.0, range.1);
+        }
+    }
+}
+
+impl Drop for AttributedString {
+    fn drop(&mut self) {
+        unsafe {
+            uiFreeAttributedString(self.s);
+        }
+    }
+}
+
+/// The font used for text without a family attribute.
+#[derive(Clone, Debug)]
+pub struct Font {
+    pub family: String,
+    pub size: f64,
+}
+
+/// An `AttributedString` laid out for drawing.
+pub struct TextLayout {
+    layout: *mut uiDrawTextLayout,
+}
+
+impl TextLayout {
+    /// Lays out `text` with lines wrapped at `width`.
+    pub fn new(text: &AttributedString, font: &Font, width: f64) -> Self {
+        let family = c_string(&font.family);
+        let mut descriptor = uiFontDescriptor {
+            Family: family.as_ptr() as *mut c_char,
+            Size: font.size,
+            Weight: TEXT_WEIGHT_NORMAL,
+            Italic: TEXT_ITALIC_NORMAL,
+            Stretch: TEXT_STRETCH_NORMAL,
+        };
+        let mut params = uiDrawTextLayoutParams {
+            String: text.s,
+            DefaultFont: &mut descriptor,
+            Width: width,
+            Align: TEXT_ALIGN_LEFT,
+        };
+        TextLayout {
+            layout: unsafe { uiDrawNewTextLayout(&mut params) },
+        }
+    }
+
+    /// Returns the width and height of the laid out text.
+    pub fn extents(&self) -> (f64, f64) {
+        let mut width = 0.0;
+        let mut height = 0.0;
+        unsafe {
+            uiDrawTextLayoutExtents(self.layout, &mut width, &mut height);
+        }
+        return (width, height);
+    }
+}
+
+impl Drop for TextLayout {
+    fn drop(&mut self) {
+        unsafe {
+            uiDrawFreeTextLayout(self.layout);
+        }
+    }
+}
+
+/// A mouse button press, release or move over an `Area`.
+#[derive(Clone, Debug)]
+pub struct MouseEvent {
+    pub x: f64,
+    pub y: f64,
+    /// Number of the button pressed, or 0.
+    pub down: i32,
+    /// Number of the button released, or 0.
+    pub up: i32,
+    /// 2 for a double click.
+    pub count: i32,
+    pub modifiers: u32,
+    /// Bit n - 1 is set while button n is held.
+    pub held: u64,
+}
+
+/// A key press or release while an `Area` has the focus.
+#[derive(Clone, Debug)]
+pub struct KeyEvent {
+    /// The key as typed, or '\0' for keys without a character.
+    pub key: char,
+    pub ext_key: u32,
+    pub modifiers: u32,
+    pub up: bool,
+}
+
+/// Callbacks of an `Area`. Those that return true make the area redraw.
+pub trait AreaHandler {
+    fn draw(&mut self, ctx: &DrawContext, width: f64, height: f64);
+
+    fn mouse_event(&mut self, _event: &MouseEvent) -> bool {
+        return false;
+    }
+
+    /// Mouse wheel moved by `dy` steps, down is positive.
+    fn scroll(&mut self, _dy: f64) -> bool {
+        return false;
+    }
+
+    /// Returning true also tells libui the key was handled.
+    fn key_event(&mut self, _event: &KeyEvent) -> bool {
+        return false;
+    }
+}
+
+/// The handler given to libui. `ah` must stay the first field so libui's
+/// pointer to it is also a pointer to the whole struct.
+#[repr(C)]
+struct Handler {
+    ah: uiAreaHandler,
+    area: *mut uiArea,
+    inner: Box<dyn AreaHandler>,
+}
+
+extern "C" fn on_draw(ah: *mut uiAreaHandler, _area: *mut uiArea, params: *mut uiAreaDrawParams) {
+    unsafe {
+        let handler = &mut *(ah as *mut Handler);
+        let params = &*params;
+        let ctx = DrawContext {
+            ctx: params.Context,
+        };
+        handler
+            .inner
+            .draw(&ctx, params.AreaWidth, params.AreaHeight);
+    }
+}
+
+extern "C" fn on_mouse_event(ah: *mut uiAreaHandler, area: *mut uiArea, e: *mut uiAreaMouseEvent) {
+    unsafe {
+        let handler = &mut *(ah as *mut Handler);
+        let e = &*e;
+        let event = MouseEvent {
+            x: e.X,
+            y: e.Y,
+            down: e.Down,
+            up: e.Up,
+            count: e.Count,
+            modifiers: e.Modifiers,
+            held: e.Held1To64,
+        };
+        if handler.inner.mouse_event(&event) {
+            uiAreaQueueRedrawAll(area);
+        }
+    }
+}
+
+extern "C" fn on_mouse_crossed(_ah: *mut uiAreaHandler, _area: *mut uiArea, _left: c_int) {}
+
+extern "C" fn on_drag_broken(_ah: *mut uiAreaHandler, _area: *mut uiArea) {}
+
+extern "C" fn on_key_event(
+    ah: *mut uiAreaHandler,
+    area: *mut uiArea,
+    e: *mut uiAreaKeyEvent,
+) -> c_int {
+    unsafe {
+        let handler = &mut *(ah as *mut Handler);
+        let e = &*e;
+        let event = KeyEvent {
+            key: e.Key as u8 as char,
+            ext_key: e.ExtKey,
+            modifiers: e.Modifiers,
+            up: e.Up != 0,
+        };
+        if handler.inner.key_event(&event) {
+            uiAreaQueueRedrawAll(area);
+            return 1;
+        }
+        return 0;
+    }
+}
+
+/// Called from GTK with the `Handler` of the area that was scrolled.
+#[cfg(target_os = "linux")]
+fn on_scroll(handler: *mut c_void, dy: f64) {
+    unsafe {
+        let handler = &mut *(handler as *mut Handler);
+        if handler.inner.scroll(dy) {
+            uiAreaQueueRedrawAll(handler.area);
+        }
+    }
+}
+
+/// A control that is drawn by its `AreaHandler`.
+#[derive(Clone)]
+pub struct Area {
+    area: *mut uiArea,
+}
+
+impl Area {
+    pub fn new(handler: Box<dyn AreaHandler>) -> Self {
+        // libui keeps the pointer for as long as the area exists, which is
+        // as long as the program runs.
+        let handler = Box::into_raw(Box::new(Handler {
+            ah: uiAreaHandler {
+                Draw: on_draw,
+                MouseEvent: on_mouse_event,
+                MouseCrossed: on_mouse_crossed,
+                DragBroken: on_drag_broken,
+                KeyEvent: on_key_event,
+            },
+            area: std::ptr::null_mut(),
+            inner: handler,
+        }));
+        unsafe {
+            let area = uiNewArea(handler as *mut uiAreaHandler);
+            (*handler).area = area;
+            // libui only sends wheel events to scrolling areas, which
+            // scroll by pixels. Ours scroll by lines, so listen to GTK.
+            #[cfg(target_os = "linux")]
+            gtk::connect_scroll(uiControlHandle(area as *mut c_void), handler as *mut c_void);
+            return Area { area: area };
+        }
+    }
+
+    pub fn queue_redraw_all(&self) {
+        unsafe {
+            uiAreaQueueRedrawAll(self.area);
+        }
+    }
+}
+
+impl From<Area> for Control {
+    fn from(area: Area) -> Control {
+        unsafe {
+            return Control::from_ui_control(area.area as *mut _);
+        }
+    }
+}
+
+/// Set while a wakeup is queued, so a burst of wakeups runs the event loop
+/// only once.
+static WAKEUP_QUEUED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_wakeup(_data: *mut c_void) {
+    WAKEUP_QUEUED.store(false, SeqCst);
+}
+
+/// Makes the event loop on the UI thread run its `on_tick` callback. Can be
+/// called from any thread.
+pub fn wake_main() {
+    if !WAKEUP_QUEUED.swap(true, SeqCst) {
+        unsafe {
+            uiQueueMain(on_wakeup, std::ptr::null_mut());
+        }
+    }
+}
+
+/// Keys of a multiline entry reported by `on_entry_key`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryKey {
+    /// Enter without Shift. Shift+Enter inserts a newline as usual.
+    Enter,
+    Tab,
+    Up,
+    Down,
+    /// Ctrl+R.
+    Search,
+}
+
+/// Removes `children`, all of the children of `vbox` in order, and destroys
+/// them. iui can only append to a box.
+pub fn clear_box<T: Into<Control>>(vbox: &VerticalBox, children: Vec<T>) {
+    let control: Control = vbox.clone().into();
+    let b = control.as_ui_control() as *mut c_void;
+    for (index, child) in children.into_iter().enumerate().rev() {
+        let child: Control = child.into();
+        unsafe {
+            uiBoxDelete(b, index as c_int);
+            child.destroy();
+        }
+    }
+}
+
+/// Calls `f` when one of the `EntryKey`s is pressed in `entry`. If `f`
+/// returns true the key press is used up. Returns false if this is not
+/// supported on the platform.
+#[cfg(target_os = "linux")]
+pub fn on_entry_key<F: FnMut(EntryKey) -> bool + 'static>(entry: &MultilineEntry, f: F) -> bool {
+    let control: Control = entry.clone().into();
+    // Lives as long as the entry, i.e. the program.
+    let callback: Box<Box<dyn FnMut(EntryKey) -> bool>> = Box::new(Box::new(f));
+    unsafe {
+        let widget = uiControlHandle(control.as_ui_control() as *mut c_void);
+        gtk::connect_entry_keys(widget, Box::into_raw(callback) as *mut c_void);
+    }
+    return true;
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn on_entry_key<F: FnMut(EntryKey) -> bool + 'static>(_entry: &MultilineEntry, _f: F) -> bool {
+    return false;
+}
+
+/// Puts `text` on the clipboard.
+#[cfg(target_os = "linux")]
+pub fn set_clipboard_text(text: &str) {
+    gtk::set_clipboard_text(text);
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_clipboard_text(_text: &str) {
+    eprintln!("Copying is not supported on this platform.");
+}
+
+/// The bits of GTK needed for wheel events and the clipboard.
+#[cfg(target_os = "linux")]
+mod gtk {
+    use super::{c_string, on_scroll, EntryKey};
+    use std::os::raw::{c_char, c_double, c_int, c_uint, c_ulong, c_void};
+
+    const GDK_SCROLL_MASK: c_int = 1 << 21;
+    const GDK_SCROLL_UP: c_uint = 0;
+    const GDK_SCROLL_DOWN: c_uint = 1;
+    const GDK_SCROLL_SMOOTH: c_uint = 4;
+    const GDK_SHIFT_MASK: c_uint = 1;
+    const GDK_CONTROL_MASK: c_uint = 1 << 2;
+    const GDK_KEY_RETURN: c_uint = 0xff0d;
+    const GDK_KEY_KP_ENTER: c_uint = 0xff8d;
+    const GDK_KEY_TAB: c_uint = 0xff09;
+    const GDK_KEY_UP: c_uint = 0xff52;
+    const GDK_KEY_DOWN: c_uint = 0xff54;
+    const GDK_KEY_KP_UP: c_uint = 0xff97;
+    const GDK_KEY_KP_DOWN: c_uint = 0xff99;
+    const GDK_KEY_LOWER_R: c_uint = 0x72;
+
+    #[repr(C)]
+    struct GdkEventScroll {
+        event_type: c_int,
+        window: *mut c_void,
+        send_event: i8,
+        time: u32,
+        x: c_double,
+        y: c_double,
+        state: c_uint,
+        direction: c_uint,
+        device: *mut c_void,
+        x_root: c_double,
+        y_root: c_double,
+        delta_x: c_double,
+        delta_y: c_double,
+    }
+
+    /// The start of `GdkEventKey`, up to the fields used here.
+    #[repr(C)]
+    struct GdkEventKey {
+        event_type: c_int,
+        window: *mut c_void,
+        send_event: i8,
+        time: u32,
+        state: c_uint,
+        keyval: c_uint,
+    }
+
+    #[link(name = "gtk-3")]
+    extern "C" {
+        fn gtk_bin_get_child(bin: *mut c_void) -> *mut c_void;
+        fn gtk_text_view_im_context_filter_keypress(
+            text_view: *mut c_void,
+            event: *mut GdkEventKey,
+        ) -> c_int;
+        fn gtk_widget_add_events(widget: *mut c_void, events: c_int);
+        fn gtk_clipboard_get(selection: *mut c_void) -> *mut c_void;
+        fn gtk_clipboard_set_text(clipboard: *mut c_void, text: *const c_char, len: c_int);
+    }
+
+    #[link(name = "gdk-3")]
+    extern "C" {
+        fn gdk_atom_intern(name: *const c_char, only_if_exists: c_int) -> *mut c_void;
+    }
+
+    #[link(name = "gobject-2.0")]
+    extern "C" {
+        fn g_signal_connect_data(
+            instance: *mut c_void,
+            signal: *const c_char,
+            handler: *const c_void,
+            data: *mut c_void,
+            destroy_data: *const c_void,
+            flags: c_int,
+        ) -> c_ulong;
+    }
+
+    extern "C" fn on_scroll_event(
+        _widget: *mut c_void,
+        event: *mut GdkEventScroll,
+        data: *mut c_void,
+    ) -> c_int {
+        let event = unsafe { &*event };
+        let dy = match event.direction {
+            GDK_SCROLL_UP => -1.0,
+            GDK_SCROLL_DOWN => 1.0,
+            GDK_SCROLL_SMOOTH => event.delta_y,
+            _ => return 0,
+        };
+        on_scroll(data, dy);
+        return 1;
+    }
+
+    /// Passes the wheel events of `widget` to `on_scroll` with `data`.
+    pub fn connect_scroll(widget: usize, data: *mut c_void) {
+        let signal = c_string("scroll-event");
+        unsafe {
+            gtk_widget_add_events(widget as *mut c_void, GDK_SCROLL_MASK);
+            g_signal_connect_data(
+                widget as *mut c_void,
+                signal.as_ptr(),
+                on_scroll_event as *const c_void,
+                data,
+                std::ptr::null(),
+                0,
+            );
+        }
+    }
+
+    extern "C" fn on_key_press(
+        text_view: *mut c_void,
+        event: *mut GdkEventKey,
+        data: *mut c_void,
+    ) -> c_int {
+        let key = unsafe { &*event };
+        let shift = key.state & GDK_SHIFT_MASK != 0;
+        let control = key.state & GDK_CONTROL_MASK != 0;
+        let entry_key = match key.keyval {
+            GDK_KEY_RETURN | GDK_KEY_KP_ENTER if !shift => EntryKey::Enter,
+            GDK_KEY_TAB => EntryKey::Tab,
+            GDK_KEY_UP | GDK_KEY_KP_UP if !shift && !control => EntryKey::Up,
+            GDK_KEY_DOWN | GDK_KEY_KP_DOWN if !shift && !control => EntryKey::Down,
+            GDK_KEY_LOWER_R if control => EntryKey::Search,
+            _ => return 0,
+        };
+        // The key may finish text in an input method instead.
+        if unsafe { gtk_text_view_im_context_filter_keypress(text_view, event) } != 0 {
+            return 1;
+        }
+        let callback = unsafe { &mut *(data as *mut Box<dyn FnMut(EntryKey) -> bool>) };
+        return callback(entry_key) as c_int;
+    }
+
+    /// Calls the `Box<dyn FnMut(EntryKey) -> bool>` at `data` for key presses
+    /// in the text view of the multiline entry `entry`.
+    pub fn connect_entry_keys(entry: usize, data: *mut c_void) {
+        let signal = c_string("key-press-event");
+        unsafe {
+            // The entry is a scrolled window around the text view.
+            let text_view = gtk_bin_get_child(entry as *mut c_void);
+            g_signal_connect_data(
+                text_view,
+                signal.as_ptr(),
+                on_key_press as *const c_void,
+                data,
+                std::ptr::null(),
+                0,
+            );
+        }
+    }
+
+    pub fn set_clipboard_text(text: &str) {
+        let name = c_string("CLIPBOARD");
+        let text = c_string(text);
+        unsafe {
+            let clipboard = gtk_clipboard_get(gdk_atom_intern(name.as_ptr(), 0));
+            gtk_clipboard_set_text(clipboard, text.as_ptr(), -1);
+        }
+    }
+}
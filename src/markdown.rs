@@ -0,0 +1,330 @@
+//! Keybase flavoured markdown.
+//!
+//! Turns a message body into lines of styled spans. Supported markup is
+//! `*bold*`, `_italic_`, `~strike~`, `` `code` ``, fenced code blocks and
+//! `>` quotes. Markup that isn't closed is shown as is. URLs and email
+//! addresses outside of code become links.
+
+use linkify::{LinkFinder, LinkKind};
+
+/// How a span of text is shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub strike: bool,
+    /// Inline code.
+    pub code: bool,
+    /// A line of a fenced code block.
+    pub code_block: bool,
+    pub quote: bool,
+    /// Less important text, like timestamps.
+    pub dim: bool,
+}
+
+/// A run of text with one style.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+    /// What to open when the span is a link, e.g. `https://keybase.io` or
+    /// `mailto:alice@example.com`.
+    pub link: Option<String>,
+}
+
+impl Span {
+    pub fn new(text: &str, style: Style) -> Self {
+        Span {
+            text: text.to_string(),
+            style: style,
+            link: None,
+        }
+    }
+
+    pub fn plain(text: &str) -> Self {
+        return Span::new(text, Style::default());
+    }
+}
+
+const FENCE: &str = "```";
+
+/// Parses `text` into lines of spans. Code blocks keep their line breaks.
+pub fn parse(text: &str) -> Vec<Vec<Span>> {
+    let mut lines = Vec::new();
+    // Outside and inside of code fences alternate.
+    for (i, block) in text.split(FENCE).enumerate() {
+        let in_code = i % 2 == 1;
+        let closed = text.matches(FENCE).count() > i;
+        if in_code && closed {
+            let style = Style {
+                code_block: true,
+                ..Style::default()
+            };
+            for line in block.trim_matches('\n').split('\n') {
+                lines.push(vec![Span::new(line, style)]);
+            }
+            continue;
+        }
+
+        // An unclosed fence is shown as typed.
+        let block = if in_code {
+            format!("{}{}", FENCE, block)
+        } else {
+            block.to_string()
+        };
+        // Nothing but the line break around a fence.
+        if block.trim().is_empty() && text.contains(FENCE) {
+            continue;
+        }
+        for line in block.trim_matches('\n').split('\n') {
+            lines.push(find_links(parse_line(line)));
+        }
+    }
+    return lines;
+}
+
+/// Splits the links out of `spans`. Code is left alone.
+fn find_links(spans: Vec<Span>) -> Vec<Span> {
+    let finder = LinkFinder::new();
+    let mut ret = Vec::new();
+    for span in spans {
+        if span.style.code {
+            ret.push(span);
+            continue;
+        }
+        for part in finder.spans(&span.text) {
+            let mut new_span = Span::new(part.as_str(), span.style);
+            new_span.link = match part.kind() {
+                Some(LinkKind::Url) => Some(part.as_str().to_string()),
+                Some(LinkKind::Email) => Some(format!("mailto:{}", part.as_str())),
+                _ => None,
+            };
+            ret.push(new_span);
+        }
+    }
+    return ret;
+}
+
+fn parse_line(line: &str) -> Vec<Span> {
+    let mut style = Style::default();
+    let mut text = line;
+    if text.starts_with('>') {
+        style.quote = true;
+        text = &text[1..];
+        if text.starts_with(' ') {
+            text = &text[1..];
+        }
+    }
+
+    let mut spans = Vec::new();
+    parse_inline(text, style, &mut spans);
+    return spans;
+}
+
+fn is_boundary(c: Option<char>) -> bool {
+    match c {
+        None => true,
+        Some(c) => c.is_whitespace() || (c.is_ascii_punctuation() && c != '`'),
+    }
+}
+
+/// Finds the closing `delim` for a span starting at byte `start`. Returns its
+/// byte position.
+fn find_closing(text: &str, start: usize, delim: char) -> Option<usize> {
+    let mut prev = None;
+    for (pos, c) in text[start..].char_indices() {
+        let pos = start + pos;
+        if c == delim && pos > start {
+            let before_ok = prev.is_some_and(|p: char| !p.is_whitespace());
+            let after = text[pos + c.len_utf8()..].chars().next();
+            if before_ok && is_boundary(after) {
+                return Some(pos);
+            }
+        }
+        prev = Some(c);
+    }
+    return None;
+}
+
+fn push_span(spans: &mut Vec<Span>, text: &str, style: Style) {
+    if text.is_empty() {
+        return;
+    }
+    if let Some(last) = spans.last_mut() {
+        if last.style == style {
+            last.text.push_str(text);
+            return;
+        }
+    }
+    spans.push(Span::new(text, style));
+}
+
+fn parse_inline(text: &str, style: Style, spans: &mut Vec<Span>) {
+    let mut plain_start = 0;
+    let mut prev: Option<char> = None;
+    let mut pos = 0;
+    while let Some(c) = text[pos..].chars().next() {
+        let inner_start = pos + c.len_utf8();
+        let next = text[inner_start..].chars().next();
+
+        let closing = match c {
+            '`' if !style.code => text[inner_start..].find('`').map(|p| inner_start + p),
+            '*' | '_' | '~' if is_boundary(prev) && next.is_some_and(|n| !n.is_whitespace()) => {
+                find_closing(text, inner_start, c)
+            }
+            _ => None,
+        };
+
+        let end = match closing {
+            Some(end) if end > inner_start => end,
+            _ => {
+                prev = Some(c);
+                pos = inner_start;
+                continue;
+            }
+        };
+
+        push_span(spans, &text[plain_start..pos], style);
+        let inner = &text[inner_start..end];
+        let mut inner_style = style;
+        match c {
+            '`' => {
+                inner_style.code = true;
+                push_span(spans, inner, inner_style);
+            }
+            _ => {
+                match c {
+                    '*' => inner_style.bold = true,
+                    '_' => inner_style.italic = true,
+                    _ => inner_style.strike = true,
+                }
+                parse_inline(inner, inner_style, spans);
+            }
+        }
+
+        // Continue after the closing delimiter.
+        pos = end + c.len_utf8();
+        plain_start = pos;
+        prev = Some(c);
+    }
+    push_span(spans, &text[plain_start..], style);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn styled(text: &str, f: fn(&mut Style)) -> Span {
+        let mut style = Style::default();
+        f(&mut style);
+        return Span::new(text, style);
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(parse("hello"), vec![vec![Span::plain("hello")]]);
+        assert_eq!(
+            parse("a *bold* and _it_ ~no~"),
+            vec![vec![
+                Span::plain("a "),
+                styled("bold", |s| s.bold = true),
+                Span::plain(" and "),
+                styled("it", |s| s.italic = true),
+                Span::plain(" "),
+                styled("no", |s| s.strike = true),
+            ]]
+        );
+        assert_eq!(
+            parse("*_both_*"),
+            vec![vec![styled("both", |s| {
+                s.bold = true;
+                s.italic = true
+            })]]
+        );
+        assert_eq!(
+            parse("run `a *b* c` now"),
+            vec![vec![
+                Span::plain("run "),
+                styled("a *b* c", |s| s.code = true),
+                Span::plain(" now"),
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_not_markup() {
+        assert_eq!(parse("2 * 3 * 4"), vec![vec![Span::plain("2 * 3 * 4")]]);
+        assert_eq!(
+            parse("snake_case_name"),
+            vec![vec![Span::plain("snake_case_name")]]
+        );
+        assert_eq!(parse("*open"), vec![vec![Span::plain("*open")]]);
+        assert_eq!(parse("**"), vec![vec![Span::plain("**")]]);
+        assert_eq!(parse("ä*ö*"), vec![vec![Span::plain("ä*ö*")]]);
+    }
+
+    #[test]
+    fn test_code_block() {
+        let code = |t| styled(t, |s| s.code_block = true);
+        assert_eq!(
+            parse("look:\n```\nfn main() {\n    *x*\n}\n```\nok"),
+            vec![
+                vec![Span::plain("look:")],
+                vec![code("fn main() {")],
+                vec![code("    *x*")],
+                vec![code("}")],
+                vec![Span::plain("ok")],
+            ]
+        );
+        assert_eq!(parse("```a```"), vec![vec![code("a")]]);
+        assert_eq!(parse("```open"), vec![vec![Span::plain("```open")]]);
+    }
+
+    fn link(text: &str, target: &str) -> Span {
+        let mut span = Span::plain(text);
+        span.link = Some(target.to_string());
+        return span;
+    }
+
+    #[test]
+    fn test_links() {
+        assert_eq!(
+            parse("see https://keybase.io/docs, or mail bob@example.com"),
+            vec![vec![
+                Span::plain("see "),
+                link("https://keybase.io/docs", "https://keybase.io/docs"),
+                Span::plain(", or mail "),
+                link("bob@example.com", "mailto:bob@example.com"),
+            ]]
+        );
+        let mut bold = link("http://a.b/c", "http://a.b/c");
+        bold.style.bold = true;
+        assert_eq!(parse("*http://a.b/c*"), vec![vec![bold]]);
+        // Not in code.
+        assert_eq!(
+            parse("`http://a.b`"),
+            vec![vec![styled("http://a.b", |s| s.code = true)]]
+        );
+        assert_eq!(
+            parse("```\nhttp://a.b\n```"),
+            vec![vec![styled("http://a.b", |s| s.code_block = true)]]
+        );
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(
+            parse("> said *this*\nreply"),
+            vec![
+                vec![
+                    styled("said ", |s| s.quote = true),
+                    styled("this", |s| {
+                        s.quote = true;
+                        s.bold = true
+                    }),
+                ],
+                vec![Span::plain("reply")],
+            ]
+        );
+    }
+}
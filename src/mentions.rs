@@ -0,0 +1,212 @@
+//! Mention and keyword detection.
+//!
+//! A message mentions us if Keybase says so in its metadata, or if the text
+//! contains `@ourname`, `@here`, `@channel` or one of the configured
+//! keywords as a whole word.
+
+use super::keybase::ChatMsg;
+use chrono::{Local, TimeZone};
+use std::collections::VecDeque;
+
+/// Number of hits kept in a `MentionLog`.
+pub const MENTION_LOG_LENGTH: usize = 100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mention {
+    /// `@ourname`.
+    User,
+    /// `@here` or `@channel`.
+    Channel,
+    Keyword(String),
+}
+
+fn is_word_char(c: char) -> bool {
+    return c.is_alphanumeric() || c == '_';
+}
+
+/// Returns true if `text` contains `word` with no word characters directly
+/// before or after it. Ignores case.
+fn contains_word(text: &str, word: &str) -> bool {
+    if word.is_empty() {
+        return false;
+    }
+
+    let text = text.to_lowercase();
+    let word = word.to_lowercase();
+    for (pos, _) in text.match_indices(&word) {
+        let prev = text[..pos].chars().last();
+        let next = text[pos + word.len()..].chars().next();
+        // Word characters at the edges of `word` itself need a boundary,
+        // e.g. "bob@me.com" is not a mention of "@me".
+        let needs_prev = word.starts_with(|c| is_word_char(c) || c == '@');
+        let needs_next = word.ends_with(is_word_char);
+        if needs_prev && prev.map(is_word_char) == Some(true) {
+            continue;
+        }
+        if needs_next && next.map(is_word_char) == Some(true) {
+            continue;
+        }
+        return true;
+    }
+    return false;
+}
+
+#[derive(Default)]
+pub struct MentionMatcher {
+    me: String,
+    keywords: Vec<String>,
+}
+
+impl MentionMatcher {
+    pub fn new(me: &str, keywords: &[String]) -> Self {
+        MentionMatcher {
+            me: me.to_string(),
+            keywords: keywords.to_vec(),
+        }
+    }
+
+    /// Our username.
+    pub fn me(&self) -> &str {
+        return &self.me;
+    }
+
+    /// Returns how `msg` mentions us, if it does. Our own messages never do.
+    pub fn find(&self, msg: &ChatMsg) -> Option<Mention> {
+        if msg.sender == self.me {
+            return None;
+        }
+
+        let me_mentioned = !self.me.is_empty()
+            && (msg.at_mentions.contains(&self.me)
+                || contains_word(&msg.text, &format!("@{}", self.me)));
+        if me_mentioned {
+            return Some(Mention::User);
+        }
+
+        if msg.channel_mention
+            || contains_word(&msg.text, "@here")
+            || contains_word(&msg.text, "@channel")
+        {
+            return Some(Mention::Channel);
+        }
+
+        for keyword in &self.keywords {
+            if contains_word(&msg.text, keyword) {
+                return Some(Mention::Keyword(keyword.clone()));
+            }
+        }
+        return None;
+    }
+}
+
+/// A message that mentioned us.
+#[derive(Clone, Debug)]
+pub struct MentionHit {
+    pub conversation_name: String,
+    pub msg: ChatMsg,
+    pub mention: Mention,
+}
+
+impl MentionHit {
+    /// Formats the hit as `timestamp - conversation - sender: text` in local
+    /// time.
+    pub fn format(&self) -> String {
+        let ts = Local.from_utc_datetime(&self.msg.utc_timestamp);
+        return format!(
+            "{} - {} - {}: {}",
+            ts.format("%F %T"),
+            self.conversation_name,
+            self.msg.sender,
+            self.msg.text
+        );
+    }
+}
+
+/// Recent mentions across all conversations, newest last.
+#[derive(Default)]
+pub struct MentionLog {
+    hits: VecDeque<MentionHit>,
+}
+
+impl MentionLog {
+    pub fn new() -> Self {
+        return MentionLog::default();
+    }
+
+    pub fn push(&mut self, hit: MentionHit) {
+        if self.hits.len() == MENTION_LOG_LENGTH {
+            self.hits.pop_front();
+        }
+        self.hits.push_back(hit);
+    }
+
+    pub fn hits(&self) -> impl DoubleEndedIterator<Item = &MentionHit> {
+        return self.hits.iter();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(sender: &str, text: &str) -> ChatMsg {
+        ChatMsg {
+            sender: sender.to_string(),
+            conversation_id: "c1".to_string(),
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_text_mentions() {
+        let matcher = MentionMatcher::new("me", &["deploy-bot".to_string()]);
+        assert_eq!(matcher.find(&msg("alice", "@me")), Some(Mention::User));
+        assert_eq!(
+            matcher.find(&msg("alice", "hey @Me, look")),
+            Some(Mention::User)
+        );
+        assert_eq!(matcher.find(&msg("alice", "hey @meg")), None);
+        assert_eq!(matcher.find(&msg("alice", "bob@me.com")), None);
+        assert_eq!(
+            matcher.find(&msg("alice", "@here lunch")),
+            Some(Mention::Channel)
+        );
+        assert_eq!(
+            matcher.find(&msg("alice", "ping @channel!")),
+            Some(Mention::Channel)
+        );
+        assert_eq!(
+            matcher.find(&msg("alice", "Deploy-Bot is down")),
+            Some(Mention::Keyword("deploy-bot".to_string()))
+        );
+        assert_eq!(matcher.find(&msg("alice", "mydeploy-bots")), None);
+        assert_eq!(matcher.find(&msg("me", "@here I'm back")), None);
+    }
+
+    #[test]
+    fn test_metadata_mentions() {
+        let matcher = MentionMatcher::new("me", &[]);
+        let mut m = msg("alice", "hi");
+        m.at_mentions = vec!["me".to_string()];
+        assert_eq!(matcher.find(&m), Some(Mention::User));
+
+        let mut m = msg("alice", "hi");
+        m.channel_mention = true;
+        assert_eq!(matcher.find(&m), Some(Mention::Channel));
+    }
+
+    #[test]
+    fn test_log_length() {
+        let mut log = MentionLog::new();
+        for i in 0..MENTION_LOG_LENGTH + 5 {
+            log.push(MentionHit {
+                conversation_name: "team#general".to_string(),
+                msg: msg("alice", &i.to_string()),
+                mention: Mention::User,
+            });
+        }
+        assert_eq!(log.hits().count(), MENTION_LOG_LENGTH);
+        assert_eq!(log.hits().next().unwrap().msg.text, "5");
+    }
+}
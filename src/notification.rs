@@ -1,17 +1,511 @@
+//! Desktop notifications.
+//!
+//! `from_config` picks a `Notifier` backend based on the user's
+//! configuration. `RecordingNotifier` keeps notifications in memory for tests.
+//! `Coalescer` and `chat_notification` turn incoming messages into
+//! notifications.
+
+use super::config::{NotificationConfig, NotifierBackend};
+use super::keybase::ChatMsg;
+use std::collections::HashMap;
 use std::process::Command;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+/// Messages to the same conversation within this many seconds of each other
+/// are shown as one notification.
+pub const BURST_SECS: i64 = 60;
+
+/// Maximum length of the message preview in characters.
+pub const PREVIEW_LENGTH: usize = 100;
+
+/// Buttons a notification can offer. Backends leave out what they don't
+/// support.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// Show the conversation in kbchatbox.
+    Open,
+    /// Reply from the notification itself.
+    Reply,
+}
+
+/// What the user did with a notification.
+#[derive(Clone, Debug, PartialEq)]
+pub enum NotificationEvent {
+    Open { tag: String },
+    Reply { tag: String, text: String },
+}
+
+/// A notification to show.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+    /// A shown notification with the same tag is replaced, if the backend
+    /// can do that. Chat notifications use the conversation id.
+    pub tag: String,
+    pub actions: Vec<Action>,
+}
+
+impl Notification {
+    pub fn new(title: &str, body: &str) -> Self {
+        Notification {
+            title: title.to_string(),
+            body: body.to_string(),
+            tag: String::new(),
+            actions: Vec::new(),
+        }
+    }
+}
+
+/// A way of showing desktop notifications.
+pub trait Notifier: Send {
+    fn notify(&self, notification: &Notification) -> Result<(), String>;
+
+    /// True if a notification replaces an earlier one with the same tag
+    /// instead of showing another popup.
+    fn replaces(&self) -> bool {
+        return false;
+    }
+}
+
+/// Cuts `text` to at most `max_chars` characters on a single line.
+pub fn preview(text: &str, max_chars: usize) -> String {
+    let line = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if line.chars().count() <= max_chars {
+        return line;
+    }
+    let mut cut: String = line.chars().take(max_chars.saturating_sub(1)).collect();
+    cut.push('…');
+    return cut;
+}
+
+/// Builds the notification for `msg`, the `count`th message of a burst.
+/// Without `show_preview` the message text is left out.
+pub fn chat_notification(
+    msg: &ChatMsg,
+    conversation_name: &str,
+    count: u32,
+    show_preview: bool,
+) -> Notification {
+    let title = if count > 1 {
+        format!("{} ({} new messages)", conversation_name, count)
+    } else {
+        conversation_name.to_string()
+    };
+
+    let body = if show_preview {
+        format!("{}: {}", msg.sender, preview(&msg.text, PREVIEW_LENGTH))
+    } else if count > 1 {
+        format!("Latest from {}", msg.sender)
+    } else {
+        format!("New message from {}", msg.sender)
+    };
+
+    return Notification {
+        title: title,
+        body: body,
+        tag: msg.conversation_id.clone(),
+        actions: vec![Action::Open, Action::Reply],
+    };
+}
+
+struct Burst {
+    count: u32,
+    last: i64,
+}
+
+/// Counts messages per conversation until there is a pause of
+/// `BURST_SECS`, or the conversation is read.
+#[derive(Default)]
+pub struct Coalescer {
+    bursts: HashMap<String, Burst>,
+}
+
+impl Coalescer {
+    pub fn new() -> Self {
+        return Coalescer::default();
+    }
+
+    /// Adds a message at `now` (seconds) and returns its number in the
+    /// current burst, starting from 1.
+    pub fn add(&mut self, conversation_id: &str, now: i64) -> u32 {
+        let burst = self
+            .bursts
+            .entry(conversation_id.to_string())
+            .or_insert(Burst {
+                count: 0,
+                last: now,
+            });
+        if now - burst.last >= BURST_SECS {
+            burst.count = 0;
+        }
+        burst.count += 1;
+        burst.last = now;
+        return burst.count;
+    }
+
+    /// Ends the burst, e.g. when the conversation is opened.
+    pub fn reset(&mut self, conversation_id: &str) {
+        self.bursts.remove(conversation_id);
+    }
+}
+
+/// Creates the backend selected in `config`. Falls back to `notify-send`
+/// when the D-Bus backend is not available. Actions taken on notifications
+/// are sent to `events`.
+pub fn from_config(
+    config: &NotificationConfig,
+    events: Sender<NotificationEvent>,
+) -> Box<dyn Notifier> {
+    match config.backend {
+        NotifierBackend::Dbus => match new_dbus_notifier(&config.icon, events) {
+            Ok(notifier) => notifier,
+            Err(err) => {
+                eprintln!(
+                    "D-Bus notifications unavailable ({}), using notify-send.",
+                    err
+                );
+                Box::new(NotifySendNotifier::new(&config.icon))
+            }
+        },
+        NotifierBackend::NotifySend => Box::new(NotifySendNotifier::new(&config.icon)),
+        NotifierBackend::Command => Box::new(CommandNotifier::new(&config.command)),
+        NotifierBackend::Null => Box::new(NullNotifier),
+    }
+}
+
+#[cfg(feature = "notify-dbus")]
+fn new_dbus_notifier(
+    icon: &str,
+    events: Sender<NotificationEvent>,
+) -> Result<Box<dyn Notifier>, String> {
+    return Ok(Box::new(DbusNotifier::new(icon, events)?));
+}
+
+#[cfg(not(feature = "notify-dbus"))]
+fn new_dbus_notifier(
+    _icon: &str,
+    _events: Sender<NotificationEvent>,
+) -> Result<Box<dyn Notifier>, String> {
+    return Err("built without the notify-dbus feature".to_string());
+}
+
+fn run_command(command: &mut Command) -> Result<(), String> {
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("notification command failed: {}", status)),
+        Err(err) => Err(format!("notification command failed: {}", err)),
+    }
+}
+
+/// Talks to the notification server over the session bus
+/// (`org.freedesktop.Notifications`). Notifications with the same tag
+/// replace each other, and the Reply action uses the `inline-reply`
+/// capability where the server has it.
+#[cfg(feature = "notify-dbus")]
+pub struct DbusNotifier {
+    proxy: zbus::blocking::Proxy<'static>,
+    icon: String,
+    inline_reply: bool,
+    /// Tags of the notifications that are still shown, by server id.
+    shown: Arc<Mutex<HashMap<u32, String>>>,
+}
+
+#[cfg(feature = "notify-dbus")]
+impl DbusNotifier {
+    pub fn new(icon: &str, events: Sender<NotificationEvent>) -> Result<Self, String> {
+        let proxy = zbus::blocking::Connection::session()
+            .and_then(|connection| {
+                zbus::blocking::Proxy::new(
+                    &connection,
+                    "org.freedesktop.Notifications",
+                    "/org/freedesktop/Notifications",
+                    "org.freedesktop.Notifications",
+                )
+            })
+            .map_err(|err| err.to_string())?;
+        let capabilities: Vec<String> = proxy
+            .call("GetCapabilities", &())
+            .map_err(|err| err.to_string())?;
+        let signals = proxy.receive_all_signals().map_err(|err| err.to_string())?;
+
+        let shown = Arc::new(Mutex::new(HashMap::new()));
+        std::thread::spawn({
+            let shown = Arc::clone(&shown);
+            move || DbusNotifier::listen_signals(signals, shown, events)
+        });
+
+        return Ok(DbusNotifier {
+            proxy: proxy,
+            icon: icon.to_string(),
+            inline_reply: capabilities.iter().any(|c| c == "inline-reply"),
+            shown: shown,
+        });
+    }
+
+    fn listen_signals(
+        signals: zbus::blocking::SignalIterator<'static>,
+        shown: Arc<Mutex<HashMap<u32, String>>>,
+        events: Sender<NotificationEvent>,
+    ) {
+        for signal in signals {
+            let member = match signal.member() {
+                Some(member) => member.to_string(),
+                None => continue,
+            };
+            let (id, arg) = match member.as_str() {
+                "ActionInvoked" | "NotificationReplied" => match signal.body::<(u32, String)>() {
+                    Ok(body) => body,
+                    Err(_) => continue,
+                },
+                "NotificationClosed" => {
+                    if let Ok((id, _reason)) = signal.body::<(u32, u32)>() {
+                        shown.lock().unwrap().remove(&id);
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
 
-pub fn send_desktop_notification(msg: &str) {
-    let ret_val = Command::new("notify-send")
-        .arg(msg)
-        .arg("-i")
-        .arg("mail-read")
-        .status();
-    println!(
-        "Notification sent: {}",
-        if ret_val.is_ok() && ret_val.unwrap().success() {
-            "success"
+            // Signals for other apps' notifications are not in `shown`.
+            let tag = match shown.lock().unwrap().get(&id) {
+                Some(tag) => tag.clone(),
+                None => continue,
+            };
+            let event = match (member.as_str(), arg.as_str()) {
+                ("ActionInvoked", "default") | ("ActionInvoked", "open") => {
+                    NotificationEvent::Open { tag: tag }
+                }
+                ("NotificationReplied", _) => NotificationEvent::Reply {
+                    tag: tag,
+                    text: arg,
+                },
+                _ => continue,
+            };
+            if events.send(event).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "notify-dbus")]
+impl Notifier for DbusNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), String> {
+        use zbus::zvariant::Value;
+
+        let mut actions: Vec<&str> = Vec::new();
+        for action in &notification.actions {
+            match action {
+                Action::Open => actions.extend(&["default", "Open", "open", "Open"]),
+                Action::Reply if self.inline_reply => actions.extend(&["inline-reply", "Reply"]),
+                Action::Reply => {}
+            }
+        }
+        let mut hints: HashMap<&str, Value> = HashMap::new();
+        hints.insert("category", Value::from("im.received"));
+
+        let replaces_id = if notification.tag.is_empty() {
+            0
         } else {
-            "failed"
+            let shown = self.shown.lock().unwrap();
+            match shown.iter().find(|(_, tag)| **tag == notification.tag) {
+                Some((id, _)) => *id,
+                None => 0,
+            }
+        };
+
+        let res: zbus::Result<u32> = self.proxy.call(
+            "Notify",
+            &(
+                "kbchatbox",
+                replaces_id,
+                self.icon.as_str(),
+                notification.title.as_str(),
+                notification.body.as_str(),
+                actions,
+                hints,
+                -1i32,
+            ),
+        );
+        match res {
+            Ok(id) => {
+                if !notification.tag.is_empty() {
+                    let mut shown = self.shown.lock().unwrap();
+                    shown.remove(&replaces_id);
+                    shown.insert(id, notification.tag.clone());
+                }
+                Ok(())
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    fn replaces(&self) -> bool {
+        return true;
+    }
+}
+
+/// Runs `notify-send` for every notification.
+pub struct NotifySendNotifier {
+    icon: String,
+}
+
+impl NotifySendNotifier {
+    pub fn new(icon: &str) -> Self {
+        NotifySendNotifier {
+            icon: icon.to_string(),
+        }
+    }
+}
+
+impl Notifier for NotifySendNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), String> {
+        return run_command(
+            Command::new("notify-send")
+                .arg("-i")
+                .arg(&self.icon)
+                .arg(&notification.title)
+                .arg(&notification.body),
+        );
+    }
+}
+
+/// Runs a user supplied command. The template is split into arguments on
+/// whitespace, then `{title}` and `{body}` are replaced in each argument, so
+/// the message text never goes through a shell.
+pub struct CommandNotifier {
+    template: Vec<String>,
+}
+
+impl CommandNotifier {
+    pub fn new(template: &str) -> Self {
+        CommandNotifier {
+            template: template.split_whitespace().map(String::from).collect(),
+        }
+    }
+
+    fn build_args(&self, notification: &Notification) -> Vec<String> {
+        return self
+            .template
+            .iter()
+            .map(|arg| {
+                arg.replace("{title}", &notification.title)
+                    .replace("{body}", &notification.body)
+            })
+            .collect();
+    }
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), String> {
+        let args = self.build_args(notification);
+        if args.is_empty() {
+            return Err("notification command is empty".to_string());
+        }
+        return run_command(Command::new(&args[0]).args(&args[1..]));
+    }
+}
+
+/// Drops all notifications.
+pub struct NullNotifier;
+
+impl Notifier for NullNotifier {
+    fn notify(&self, _notification: &Notification) -> Result<(), String> {
+        return Ok(());
+    }
+}
+
+/// Keeps notifications in memory. Clones share the same list.
+#[derive(Clone, Default)]
+pub struct RecordingNotifier {
+    sent: Arc<Mutex<Vec<Notification>>>,
+}
+
+impl RecordingNotifier {
+    pub fn new() -> Self {
+        return RecordingNotifier::default();
+    }
+
+    /// Returns the notifications shown so far.
+    pub fn sent(&self) -> Vec<Notification> {
+        return self.sent.lock().unwrap().clone();
+    }
+}
+
+impl Notifier for RecordingNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), String> {
+        self.sent.lock().unwrap().push(notification.clone());
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(text: &str) -> ChatMsg {
+        ChatMsg {
+            sender: "alice".to_string(),
+            conversation_id: "c1".to_string(),
+            text: text.to_string(),
+            ..Default::default()
         }
-    );
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview("short", 10), "short");
+        assert_eq!(preview("two\nlines  here", 20), "two lines here");
+        assert_eq!(preview("0123456789ab", 10), "012345678…");
+        assert_eq!(preview("äöäöäöäöäöäö", 5), "äöäö…");
+    }
+
+    #[test]
+    fn test_chat_notification() {
+        let n = chat_notification(&msg("hello"), "team#general", 1, true);
+        assert_eq!(n.title, "team#general");
+        assert_eq!(n.body, "alice: hello");
+        assert_eq!(n.tag, "c1");
+
+        let n = chat_notification(&msg("hello"), "team#general", 3, false);
+        assert_eq!(n.title, "team#general (3 new messages)");
+        assert_eq!(n.body, "Latest from alice");
+        assert!(!n.body.contains("hello"));
+    }
+
+    #[test]
+    fn test_coalescer() {
+        let mut coalescer = Coalescer::new();
+        assert_eq!(coalescer.add("c1", 100), 1);
+        assert_eq!(coalescer.add("c1", 110), 2);
+        assert_eq!(coalescer.add("c2", 110), 1);
+        assert_eq!(coalescer.add("c1", 110 + BURST_SECS - 1), 3);
+        assert_eq!(coalescer.add("c1", 110 + 2 * BURST_SECS), 1);
+        coalescer.reset("c2");
+        assert_eq!(coalescer.add("c2", 120), 1);
+    }
+
+    #[test]
+    fn test_command_template() {
+        let notifier = CommandNotifier::new("dunstify  -a kbchatbox {title} [{body}]");
+        let args = notifier.build_args(&Notification::new("From alice", "hi there"));
+        assert_eq!(
+            args,
+            vec!["dunstify", "-a", "kbchatbox", "From alice", "[hi there]"]
+        );
+    }
+
+    #[test]
+    fn test_recording() {
+        let recorder = RecordingNotifier::new();
+        let notifier: Box<dyn Notifier> = Box::new(recorder.clone());
+        notifier.notify(&Notification::new("a", "b")).unwrap();
+        notifier.notify(&Notification::new("c", "d")).unwrap();
+        assert_eq!(
+            recorder.sent(),
+            vec![Notification::new("a", "b"), Notification::new("c", "d")]
+        );
+    }
 }
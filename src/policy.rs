@@ -0,0 +1,179 @@
+//! Notification policy.
+//!
+//! Decides whether an incoming message is worth a desktop notification. The
+//! front end passes in what it knows about the UI, so the decision itself is
+//! free of any UI code.
+
+use super::config::{NotificationConfig, NotifyRule};
+use super::keybase::ChatMsg;
+use super::mentions::MentionMatcher;
+use chrono::NaiveTime;
+
+/// What the user is looking at when a message arrives.
+pub struct ViewState<'a> {
+    pub current_conversation_id: &'a str,
+    /// Name of the conversation the message belongs to.
+    pub conversation_name: &'a str,
+    pub window_focused: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Notify,
+    /// Sent by ourselves, e.g. from another device.
+    OwnMessage,
+    /// The conversation is open in a focused window.
+    ConversationVisible,
+    DoNotDisturb,
+    QuietHours,
+    /// The conversation is muted.
+    Muted,
+    /// The conversation only notifies for mentions and this is not one.
+    NotMentioned,
+}
+
+pub struct NotificationPolicy<'a> {
+    mentions: &'a MentionMatcher,
+    settings: &'a NotificationConfig,
+}
+
+impl<'a> NotificationPolicy<'a> {
+    pub fn new(mentions: &'a MentionMatcher, settings: &'a NotificationConfig) -> Self {
+        NotificationPolicy {
+            mentions: mentions,
+            settings: settings,
+        }
+    }
+
+    /// Decides about `msg`. `now` is the local time of day.
+    pub fn decide(&self, msg: &ChatMsg, view: &ViewState, now: NaiveTime) -> Decision {
+        if msg.sender == self.mentions.me() {
+            return Decision::OwnMessage;
+        }
+
+        if view.window_focused && msg.conversation_id == view.current_conversation_id {
+            return Decision::ConversationVisible;
+        }
+
+        if self.settings.do_not_disturb {
+            return Decision::DoNotDisturb;
+        }
+
+        if let Some(ref quiet_hours) = self.settings.quiet_hours {
+            if quiet_hours.contains(now) {
+                return Decision::QuietHours;
+            }
+        }
+
+        match self
+            .settings
+            .rule_for(&msg.conversation_id, view.conversation_name)
+        {
+            NotifyRule::All => Decision::Notify,
+            NotifyRule::MentionsOnly if self.mentions.find(msg).is_some() => Decision::Notify,
+            NotifyRule::MentionsOnly => Decision::NotMentioned,
+            NotifyRule::Muted => Decision::Muted,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::QuietHours;
+
+    fn msg(sender: &str, conversation_id: &str, text: &str) -> ChatMsg {
+        ChatMsg {
+            sender: sender.to_string(),
+            conversation_id: conversation_id.to_string(),
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn view<'a>(current: &'a str, name: &'a str, focused: bool) -> ViewState<'a> {
+        ViewState {
+            current_conversation_id: current,
+            conversation_name: name,
+            window_focused: focused,
+        }
+    }
+
+    #[test]
+    fn test_decide() {
+        let mut settings = NotificationConfig::default();
+        settings.set_rule("team#general", NotifyRule::MentionsOnly);
+        settings.set_rule("team#bots", NotifyRule::Muted);
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        let matcher = MentionMatcher::new("me", &["lunch".to_string()]);
+        let policy = NotificationPolicy::new(&matcher, &settings);
+
+        let m = msg("me", "c1", "hi");
+        assert_eq!(
+            policy.decide(&m, &view("c2", "alice,me", false), noon),
+            Decision::OwnMessage
+        );
+
+        let m = msg("alice", "c1", "hi");
+        assert_eq!(
+            policy.decide(&m, &view("c1", "alice,me", true), noon),
+            Decision::ConversationVisible
+        );
+        assert_eq!(
+            policy.decide(&m, &view("c1", "alice,me", false), noon),
+            Decision::Notify
+        );
+        assert_eq!(
+            policy.decide(&m, &view("c2", "alice,me", true), noon),
+            Decision::Notify
+        );
+
+        let m = msg("alice", "c2", "coffee?");
+        assert_eq!(
+            policy.decide(&m, &view("c1", "team#general", true), noon),
+            Decision::NotMentioned
+        );
+        let m = msg("alice", "c2", "coffee @Me?");
+        assert_eq!(
+            policy.decide(&m, &view("c1", "team#general", true), noon),
+            Decision::Notify
+        );
+        let m = msg("alice", "c2", "lunch?");
+        assert_eq!(
+            policy.decide(&m, &view("c1", "team#general", true), noon),
+            Decision::Notify
+        );
+        assert_eq!(
+            policy.decide(&m, &view("c1", "team#bots", true), noon),
+            Decision::Muted
+        );
+    }
+
+    #[test]
+    fn test_quiet() {
+        let mut settings = NotificationConfig {
+            quiet_hours: QuietHours::parse("22:00-07:00"),
+            ..NotificationConfig::default()
+        };
+        let m = msg("alice", "c1", "hi");
+        let v = view("c2", "alice,me", false);
+        let matcher = MentionMatcher::new("me", &[]);
+
+        let night = NaiveTime::from_hms_opt(23, 0, 0).unwrap();
+        let noon = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+        assert_eq!(
+            NotificationPolicy::new(&matcher, &settings).decide(&m, &v, night),
+            Decision::QuietHours
+        );
+        assert_eq!(
+            NotificationPolicy::new(&matcher, &settings).decide(&m, &v, noon),
+            Decision::Notify
+        );
+
+        settings.do_not_disturb = true;
+        assert_eq!(
+            NotificationPolicy::new(&matcher, &settings).decide(&m, &v, noon),
+            Decision::DoNotDisturb
+        );
+    }
+}
@@ -1,14 +1,263 @@
-extern crate linkify;
+//! Scrollback of the chat view: a bounded number of lines, wrapped and
+//! shown through a viewport that can be scrolled.
 
-use linkify::LinkFinder;
+use super::keybase::ChatMsg;
+use super::markdown::{self, Span, Style};
+use chrono::{Local, NaiveDateTime, TimeZone};
 use std::cmp;
 use std::collections::vec_deque::VecDeque;
-use std::collections::HashSet;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
+/// Returns the number of columns `grapheme` takes in a monospace font.
+fn grapheme_width(grapheme: &str) -> usize {
+    // Emoji sequences are drawn as one wide glyph.
+    return cmp::min(UnicodeWidthStr::width(grapheme), 2);
+}
+
+/// Returns the number of columns `text` takes in a monospace font. Wide
+/// characters, like most CJK and emoji, take two.
+pub fn display_width(text: &str) -> usize {
+    return text.graphemes(true).map(grapheme_width).sum();
+}
+
+/// Returns the graphemes of `text` that start at columns `first..last`.
+pub fn slice_columns(text: &str, first: usize, last: usize) -> String {
+    let mut ret = String::new();
+    let mut column = 0;
+    for grapheme in text.graphemes(true) {
+        if column >= last {
+            break;
+        }
+        if column >= first {
+            ret.push_str(grapheme);
+        }
+        column += grapheme_width(grapheme);
+    }
+    return ret;
+}
+
+/// A line of styled text.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StyledLine {
+    pub spans: Vec<Span>,
+    /// Draw the line highlighted, e.g. when it mentions us.
+    pub highlight: bool,
+    /// Columns to indent the rest of the rows with when the line is
+    /// wrapped.
+    pub indent: usize,
+}
+
+impl StyledLine {
+    pub fn plain(text: &str) -> Self {
+        StyledLine {
+            spans: vec![Span::plain(text)],
+            ..Default::default()
+        }
+    }
+
+    /// Returns the text without styles.
+    pub fn text(&self) -> String {
+        return self.spans.iter().map(|s| s.text.as_str()).collect();
+    }
+
+    /// Returns the target of the link at `column`, if there is one.
+    pub fn link_at(&self, column: usize) -> Option<&str> {
+        let mut start = 0;
+        for span in &self.spans {
+            let end = start + display_width(&span.text);
+            if column >= start && column < end {
+                return span.link.as_deref();
+            }
+            start = end;
+        }
+        return None;
+    }
+}
+
+/// A link or email address in a message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FoundLink {
+    /// The link as written.
+    pub text: String,
+    /// What to open, e.g. `mailto:` and the address for emails.
+    pub target: String,
+    pub sender: String,
+    pub utc_timestamp: NaiveDateTime,
+    /// Id of the line it is on.
+    line: usize,
+}
+
+impl FoundLink {
+    /// Returns the link as `timestamp - sender: link` spans, with the
+    /// timestamp in local time.
+    pub fn to_line(&self) -> StyledLine {
+        let ts = Local.from_utc_datetime(&self.utc_timestamp);
+        let mut link = Span::plain(&self.text);
+        link.link = Some(self.target.clone());
+        let mut line = message_prefix(&ts.format("%F %T").to_string(), &self.sender);
+        line.spans.push(link);
+        return line;
+    }
+}
+
+/// Returns a line with the `timestamp - sender: ` prefix of messages, and
+/// the rows after the first indented past it.
+fn message_prefix(timestamp: &str, sender: &str) -> StyledLine {
+    let dim = Style {
+        dim: true,
+        ..Style::default()
+    };
+    let bold = Style {
+        bold: true,
+        ..Style::default()
+    };
+    let spans = vec![
+        Span::new(timestamp, dim),
+        Span::plain(" - "),
+        Span::new(sender, bold),
+        Span::plain(": "),
+    ];
+    let indent = spans.iter().map(|s| display_width(&s.text)).sum();
+    return StyledLine {
+        spans: spans,
+        highlight: false,
+        indent: indent,
+    };
+}
+
+/// Breaks between graphemes `prev` and `next` are allowed at the ends of
+/// whitespace and around wide characters, which are not separated by spaces
+/// in CJK text.
+fn can_break(prev: &str, next: &str) -> bool {
+    let prev_space = prev.trim().is_empty();
+    let next_space = next.trim().is_empty();
+    if prev_space != next_space {
+        return true;
+    }
+    return !prev_space && (grapheme_width(prev) > 1 || grapheme_width(next) > 1);
+}
+
+/// Adds `grapheme` of `span` to the end of `spans`, merging it with the
+/// last span if it has the same style and link.
+fn push_grapheme(spans: &mut Vec<Span>, grapheme: &str, span: &Span) {
+    if let Some(last) = spans.last_mut() {
+        if last.style == span.style && last.link == span.link {
+            last.text.push_str(grapheme);
+            return;
+        }
+    }
+    let mut new_span = Span::new(grapheme, span.style);
+    new_span.link = span.link.clone();
+    spans.push(new_span);
+}
+
+/// Wraps `line` into rows of at most `width` columns, breaking between
+/// words where possible. Rows after the first are indented by
+/// `line.indent` unless that leaves less than half of the width.
+pub fn wrap(line: &StyledLine, width: usize) -> Vec<StyledLine> {
+    let width = cmp::max(width, 1);
+    let indent = if line.indent * 2 <= width {
+        line.indent
+    } else {
+        0
+    };
+
+    // Split into words: runs of graphemes between break opportunities.
+    let mut words: Vec<Vec<(&str, &Span)>> = Vec::new();
+    let mut prev: Option<&str> = None;
+    for span in &line.spans {
+        for grapheme in span.text.graphemes(true) {
+            match (prev, words.last_mut()) {
+                (Some(p), Some(word)) if !can_break(p, grapheme) => {
+                    word.push((grapheme, span));
+                }
+                _ => words.push(vec![(grapheme, span)]),
+            }
+            prev = Some(grapheme);
+        }
+    }
+
+    let new_row = |indent: usize| {
+        let mut spans = Vec::new();
+        if indent > 0 {
+            spans.push(Span::plain(&" ".repeat(indent)));
+        }
+        return (
+            StyledLine {
+                spans: spans,
+                highlight: line.highlight,
+                indent: line.indent,
+            },
+            indent,
+        );
+    };
+
+    let mut rows = Vec::new();
+    let (mut row, mut row_width) = new_row(0);
+    for word in words {
+        let word_width: usize = word.iter().map(|(g, _)| grapheme_width(g)).sum();
+        let is_space = word.iter().all(|(g, _)| g.trim().is_empty());
+        if row_width + word_width > width {
+            // Spaces at a break are dropped.
+            if is_space {
+                rows.push(row);
+                let next = new_row(indent);
+                row = next.0;
+                row_width = next.1;
+                continue;
+            }
+            // Words that fit on a row of their own start one.
+            if row_width > indent && indent + word_width <= width {
+                rows.push(row);
+                let next = new_row(indent);
+                row = next.0;
+                row_width = next.1;
+            }
+        }
+        for (grapheme, span) in word {
+            let w = grapheme_width(grapheme);
+            // Words longer than a row are split between graphemes.
+            if row_width + w > width && row_width > indent {
+                rows.push(row);
+                let next = new_row(indent);
+                row = next.0;
+                row_width = next.1;
+            }
+            push_grapheme(&mut row.spans, grapheme, span);
+            row_width += w;
+        }
+    }
+    if !row.spans.is_empty() || rows.is_empty() {
+        rows.push(row);
+    }
+    return rows;
+}
+
+/// Default number of lines kept for scrolling back.
+pub const SCROLLBACK_LENGTH: usize = 10_000;
+
+/// Identifies a row: the line it was wrapped from, counting every line ever
+/// appended, and its index within the line.
+pub type RowId = (usize, usize);
+
+/// Keeps the newest lines of a conversation and shows them wrapped to
+/// `max_x_chars` columns in a viewport of `max_y_chars` rows. The viewport
+/// follows the newest line until it is scrolled up.
 pub struct TextBuffer {
     xsize: usize,
     ysize: usize,
-    raw_lines: VecDeque<String>,
+    max_lines: usize,
+    raw_lines: VecDeque<StyledLine>,
+    /// Lines dropped from the front, so that lines keep their ids.
+    dropped: usize,
+    /// When scrolled up, the last line in the viewport and the number of
+    /// its rows shown.
+    bottom: Option<(usize, usize)>,
+    /// Messages appended below the viewport while scrolled up.
+    unseen: usize,
+    /// Links in the lines kept, oldest first.
+    links: Vec<FoundLink>,
 }
 
 impl TextBuffer {
@@ -16,85 +265,321 @@ impl TextBuffer {
         TextBuffer {
             xsize: max_x_chars,
             ysize: max_y_chars,
+            max_lines: SCROLLBACK_LENGTH,
             raw_lines: VecDeque::new(),
+            dropped: 0,
+            bottom: None,
+            unseen: 0,
+            links: Vec::new(),
+        }
+    }
+
+    /// Sets the number of lines kept for scrolling back.
+    pub fn set_scrollback(&mut self, max_lines: usize) {
+        self.max_lines = cmp::max(max_lines, 1);
+        self.drop_old_lines();
+    }
+
+    /// Sets the number of columns to wrap at. The viewport keeps showing
+    /// the same line at its bottom.
+    pub fn set_width(&mut self, max_x_chars: usize) {
+        let old_rows = self.bottom.map(|(id, _)| self.rows_of(id).len());
+        self.xsize = max_x_chars;
+        if let (Some((id, shown)), Some(old_rows)) = (self.bottom, old_rows) {
+            // A line shown in full stays in full.
+            let rows = self.rows_of(id).len();
+            let shown = if shown >= old_rows {
+                rows
+            } else {
+                cmp::min(shown, rows)
+            };
+            self.bottom = Some((id, shown));
+            self.clamp_to_top();
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        return self.xsize;
+    }
+
+    /// Sets the number of rows in the viewport.
+    pub fn set_height(&mut self, max_y_chars: usize) {
+        self.ysize = max_y_chars;
+        if self.bottom.is_some() {
+            self.clamp_to_top();
         }
     }
 
+    pub fn height(&self) -> usize {
+        return self.ysize;
+    }
+
+    /// Appends text to the end of the buffer. Text with newlines is split
+    /// into several lines.
     pub fn append(&mut self, new_line: &str) {
+        self.count_unseen();
         // Split newlines.
         for line in new_line.lines() {
-            self.raw_lines.push_back(line.to_string());
+            self.append_line(StyledLine::plain(line));
         }
+    }
+
+    /// Appends a line of styled text.
+    pub fn append_line(&mut self, line: StyledLine) {
+        self.raw_lines.push_back(line);
+        self.drop_old_lines();
+    }
+
+    fn count_unseen(&mut self) {
+        if self.bottom.is_some() {
+            self.unseen += 1;
+        }
+    }
 
-        // We only need maximum of ysize rows to fill the buffer vertically.
-        while self.raw_lines.len() > self.ysize {
+    fn drop_old_lines(&mut self) {
+        if self.raw_lines.len() <= self.max_lines {
+            return;
+        }
+        while self.raw_lines.len() > self.max_lines {
             self.raw_lines.pop_front();
+            self.dropped += 1;
+        }
+        let dropped = self.dropped;
+        self.links.retain(|l| l.line >= dropped);
+        // The viewport can't be above the oldest line.
+        if let Some((id, _)) = self.bottom {
+            if id < self.dropped {
+                self.bottom = Some((self.dropped, 1));
+            }
+            self.clamp_to_top();
+        }
+    }
+
+    /// Appends a chat message as `timestamp - sender: text` in local time,
+    /// with the markdown in the text rendered. The text is aligned after the
+    /// prefix on every row.
+    pub fn append_msg(&mut self, msg: &ChatMsg, highlight: bool) {
+        let ts = Local.from_utc_datetime(&msg.utc_timestamp);
+        let mut first = message_prefix(&ts.format("%F %T").to_string(), &msg.sender);
+        first.highlight = highlight;
+        let indent = first.indent;
+        self.count_unseen();
+
+        let mut lines = Vec::new();
+        let mut body = markdown::parse(&msg.text).into_iter();
+        first.spans.extend(body.next().unwrap_or_default());
+        lines.push(first);
+        for spans in body {
+            let mut line = vec![Span::plain(&" ".repeat(indent))];
+            line.extend(spans);
+            lines.push(StyledLine {
+                spans: line,
+                highlight: highlight,
+                indent: indent,
+            });
+        }
+
+        for line in lines {
+            let id = self.dropped + self.raw_lines.len();
+            for span in &line.spans {
+                if let Some(ref target) = span.link {
+                    self.links.push(FoundLink {
+                        text: span.text.clone(),
+                        target: target.clone(),
+                        sender: msg.sender.clone(),
+                        utc_timestamp: msg.utc_timestamp,
+                        line: id,
+                    });
+                }
+            }
+            self.append_line(line);
         }
     }
 
+    /// Returns the links in the lines kept, oldest first.
+    pub fn links(&self) -> &[FoundLink] {
+        return &self.links;
+    }
+
+    /// Removes all lines and scrolls to the bottom.
     pub fn clear(&mut self) {
+        self.dropped += self.raw_lines.len();
         self.raw_lines.clear();
+        self.links.clear();
+        self.scroll_to_bottom();
     }
 
-    pub fn get_newest_formatted(&self) -> String {
-        return self.get_newest().join("\n");
+    fn rows_of(&self, id: usize) -> Vec<StyledLine> {
+        return wrap(&self.raw_lines[id - self.dropped], self.xsize);
     }
 
-    fn get_newest(&self) -> Vec<String> {
-        let mut formatted: Vec<String> = Vec::new();
-        let mut links: HashSet<String> = HashSet::new();
-        let finder = LinkFinder::new();
+    /// Returns true if the viewport shows the newest line.
+    pub fn is_at_bottom(&self) -> bool {
+        return self.bottom.is_none();
+    }
 
-        // Iterate from newest to oldest.
-        for line in self.raw_lines.iter().rev() {
-            // Is the buffer full?
-            if formatted.len() >= self.ysize {
-                break;
+    /// Returns the number of messages appended below the viewport since it
+    /// was scrolled up.
+    pub fn unseen(&self) -> usize {
+        return self.unseen;
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.bottom = None;
+        self.unseen = 0;
+    }
+
+    /// Scrolls the viewport up by `rows`, stopping at the oldest line.
+    pub fn scroll_up(&mut self, rows: usize) {
+        if self.raw_lines.is_empty() || rows == 0 {
+            return;
+        }
+        let (mut id, mut shown) = match self.bottom {
+            Some(bottom) => bottom,
+            None => {
+                let id = self.dropped + self.raw_lines.len() - 1;
+                (id, self.rows_of(id).len())
             }
+        };
 
-            // Check if there are any URLs and print out to console if there are.
-            let found_links: Vec<_> = finder.links(line).collect();
-            for link in found_links {
-                links.insert(link.as_str().to_string());
+        let mut remaining = rows;
+        while remaining > 0 {
+            if shown > remaining {
+                shown -= remaining;
+                break;
+            }
+            if id == self.dropped {
+                shown = 1;
+                break;
             }
+            remaining -= shown;
+            id -= 1;
+            shown = self.rows_of(id).len();
+        }
+        self.bottom = Some((id, shown));
+        self.clamp_to_top();
+    }
 
-            // Does the new raw line fit as is? If not split into sub lines.
-            if line.len() >= self.xsize {
-                let new_lines = self.split_into_sublines(line, self.xsize);
-
-                // Check that the lines fit into remaining free lines.
-                let truncated_new_lines: Vec<String> = new_lines
-                    .iter()
-                    .rev()
-                    .take(cmp::min(self.ysize - formatted.len(), new_lines.len()))
-                    .cloned()
-                    .collect();
-                formatted.extend(truncated_new_lines);
-                continue;
+    /// Scrolls the viewport down by `rows`. Reaching the newest line follows
+    /// it again.
+    pub fn scroll_down(&mut self, rows: usize) {
+        let (mut id, mut shown) = match self.bottom {
+            Some(bottom) => bottom,
+            None => return,
+        };
+        let last = self.dropped + self.raw_lines.len() - 1;
+
+        let mut remaining = rows;
+        loop {
+            let total = self.rows_of(id).len();
+            let hidden = total.saturating_sub(shown);
+            if remaining < hidden || (remaining == hidden && id < last) {
+                shown += remaining;
+                break;
+            }
+            if id == last {
+                self.scroll_to_bottom();
+                return;
             }
+            remaining -= hidden;
+            id += 1;
+            shown = 0;
+        }
+        self.bottom = Some((id, shown));
+    }
+
+    pub fn page_up(&mut self) {
+        self.scroll_up(cmp::max(self.ysize, 2) - 1);
+    }
+
+    pub fn page_down(&mut self) {
+        self.scroll_down(cmp::max(self.ysize, 2) - 1);
+    }
+
+    /// Scrolls down if the viewport would not be full, which happens when
+    /// scrolled to the oldest line.
+    fn clamp_to_top(&mut self) {
+        let visible = self.get_visible_rows().len();
+        if visible < self.ysize {
+            self.scroll_down(self.ysize - visible);
+        }
+    }
 
-            formatted.push(line.to_string());
+    /// Returns the rows in the viewport with their ids, oldest first. Only
+    /// the lines in the viewport are wrapped.
+    pub fn get_visible_rows(&self) -> Vec<(RowId, StyledLine)> {
+        let mut rows = Vec::new();
+        if self.raw_lines.is_empty() {
+            return rows;
         }
+        let (mut id, mut shown) = match self.bottom {
+            Some((id, shown)) => (id, Some(shown)),
+            None => (self.dropped + self.raw_lines.len() - 1, None),
+        };
 
-        for l in &links {
-            println!("Found link: {}", l.as_str());
+        // Iterate from newest to oldest.
+        while rows.len() < self.ysize {
+            let line_rows = self.rows_of(id);
+            let count = match shown.take() {
+                Some(shown) => cmp::min(shown, line_rows.len()),
+                None => line_rows.len(),
+            };
+            // Take the newest rows of the line that fit.
+            let free = self.ysize - rows.len();
+            for (i, row) in line_rows
+                .into_iter()
+                .enumerate()
+                .take(count)
+                .rev()
+                .take(free)
+            {
+                rows.push(((id, i), row));
+            }
+            if id == self.dropped {
+                break;
+            }
+            id -= 1;
         }
 
-        formatted.reverse();
-        return formatted;
+        rows.reverse();
+        return rows;
     }
 
-    fn split_into_sublines(&self, line: &String, max_len: usize) -> Vec<String> {
-        let mut ret: Vec<String> = Vec::new();
-        let mut it = line.chars();
-        loop {
-            let new_line = it.by_ref().take(max_len).collect::<String>();
-            if new_line.is_empty() {
-                break;
+    /// Returns the ids of the first and the last row kept.
+    pub fn row_range(&self) -> Option<(RowId, RowId)> {
+        if self.raw_lines.is_empty() {
+            return None;
+        }
+        let last = self.dropped + self.raw_lines.len() - 1;
+        return Some(((self.dropped, 0), (last, self.rows_of(last).len() - 1)));
+    }
+
+    /// Returns the rows from `first` to `last`, also those outside of the
+    /// viewport.
+    pub fn rows_between(&self, first: RowId, last: RowId) -> Vec<(RowId, StyledLine)> {
+        let mut rows = Vec::new();
+        let end = self.dropped + self.raw_lines.len();
+        for id in cmp::max(first.0, self.dropped)..cmp::min(last.0 + 1, end) {
+            for (i, row) in self.rows_of(id).into_iter().enumerate() {
+                if (id, i) >= first && (id, i) <= last {
+                    rows.push(((id, i), row));
+                }
             }
-            ret.push(new_line);
         }
-        return ret;
+        return rows;
+    }
+
+    /// Returns the rows in the viewport, joined with newlines.
+    pub fn get_newest_formatted(&self) -> String {
+        return self.get_newest().join("\n");
+    }
+
+    fn get_newest(&self) -> Vec<String> {
+        return self
+            .get_visible_rows()
+            .iter()
+            .map(|(_, r)| r.text())
+            .collect();
     }
 
     #[cfg(test)]
@@ -142,12 +627,141 @@ mod tests {
         assert_eq!(None, it.next());
     }
 
+    #[test]
+    fn test_textbuffer_msg() {
+        let mut text_buf = TextBuffer::new(100, 5);
+        let msg = ChatMsg {
+            sender: "alice".to_string(),
+            text: "see *this*:\n```\nlet x = 1;\n```".to_string(),
+            ..Default::default()
+        };
+        text_buf.append_msg(&msg, true);
+
+        let rows: Vec<StyledLine> = text_buf
+            .get_visible_rows()
+            .into_iter()
+            .map(|(_, r)| r)
+            .collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(|r| r.highlight));
+        assert!(rows[0].text().ends_with(" - alice: see this:"));
+        assert!(rows[0].spans[0].style.dim);
+        assert_eq!(rows[0].spans[2].text, "alice");
+        assert!(rows[0].spans[2].style.bold);
+        let this = rows[0].spans.iter().find(|s| s.text == "this").unwrap();
+        assert!(this.style.bold);
+        // The code block is aligned after "timestamp - alice: ".
+        let indent = " ".repeat(display_width("2019-01-01 00:00:00 - alice: "));
+        assert_eq!(rows[1].text(), format!("{}let x = 1;", indent));
+        assert!(rows[1].spans.last().unwrap().style.code_block);
+    }
+
+    fn texts(rows: &[StyledLine]) -> Vec<String> {
+        return rows.iter().map(|r| r.text()).collect();
+    }
+
+    #[test]
+    fn test_wrap_words() {
+        let line = StyledLine::plain("hello world foo");
+        assert_eq!(texts(&wrap(&line, 11)), vec!["hello world", "foo"]);
+        assert_eq!(texts(&wrap(&line, 8)), vec!["hello ", "world ", "foo"]);
+        // Longer than a row.
+        let line = StyledLine::plain("abcdefghij kl");
+        assert_eq!(texts(&wrap(&line, 4)), vec!["abcd", "efgh", "ij ", "kl"]);
+        assert_eq!(texts(&wrap(&StyledLine::plain(""), 4)), vec![""]);
+    }
+
+    #[test]
+    fn test_wrap_multibyte() {
+        assert_eq!(display_width("äö"), 2);
+        assert_eq!(display_width("日本"), 4);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("👍🏽"), 2);
+
+        // CJK breaks between characters, never inside one.
+        let line = StyledLine::plain("日本語のテキスト");
+        let rows = wrap(&line, 5);
+        assert_eq!(texts(&rows), vec!["日本", "語の", "テキ", "スト"]);
+
+        // Combining marks and emoji modifiers stay with their base.
+        let line = StyledLine::plain("cafe\u{301} 👍🏽👍🏽");
+        let rows = texts(&wrap(&line, 5));
+        assert_eq!(rows, vec!["cafe\u{301} ", "👍🏽👍🏽"]);
+
+        // Multi-byte words are not split.
+        let line = StyledLine::plain("äää ööö");
+        assert_eq!(texts(&wrap(&line, 5)), vec!["äää ", "ööö"]);
+        for row in wrap(&StyledLine::plain("ääääääääää"), 3) {
+            assert!(display_width(&row.text()) <= 3);
+        }
+    }
+
+    #[test]
+    fn test_wrap_indent() {
+        let line = StyledLine {
+            spans: vec![
+                Span::plain("bob: "),
+                Span::new(
+                    "one two three",
+                    Style {
+                        bold: true,
+                        ..Style::default()
+                    },
+                ),
+            ],
+            indent: 5,
+            ..Default::default()
+        };
+        let rows = wrap(&line, 14);
+        assert_eq!(texts(&rows), vec!["bob: one two ", "     three"]);
+        assert!(!rows[1].spans[0].style.bold);
+        assert!(rows[1].spans[1].style.bold);
+
+        // No indent if it would leave less than half of the row.
+        assert_eq!(texts(&wrap(&line, 9)), vec!["bob: one ", "two three"]);
+    }
+
+    #[test]
+    fn test_links() {
+        let mut text_buf = TextBuffer::new(40, 5);
+        text_buf.set_scrollback(3);
+        let msg = ChatMsg {
+            sender: "alice".to_string(),
+            text: "docs at https://keybase.io/docs/api or ask bob@example.com".to_string(),
+            ..Default::default()
+        };
+        text_buf.append_msg(&msg, false);
+
+        let links = text_buf.links();
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].text, "https://keybase.io/docs/api");
+        assert_eq!(links[0].sender, "alice");
+        assert_eq!(links[1].target, "mailto:bob@example.com");
+
+        // Links survive wrapping and can be found by column.
+        let rows = text_buf.get_visible_rows();
+        let (_, ref row) = rows
+            .iter()
+            .find(|(_, r)| r.text().contains("https"))
+            .unwrap();
+        let column = row.text().find("https").unwrap();
+        assert_eq!(row.link_at(column), Some("https://keybase.io/docs/api"));
+        assert_eq!(rows[0].1.link_at(0), None);
+
+        // Links go with their lines.
+        for i in 0..3 {
+            text_buf.append(&i.to_string());
+        }
+        assert!(text_buf.links().is_empty());
+    }
+
     #[test]
     fn test_textbuffer_spill_over() {
         // Checks that TextBuffer does not leak memory by not
         // purging the old values that are not needed anymore.
         let h = 10;
         let mut text_buf = TextBuffer::new(100, h);
+        text_buf.set_scrollback(3 * h);
         assert_eq!(text_buf.get_raw_buffer_capacity(), 0);
         for i in 0..3 {
             text_buf.append(&i.to_string());
@@ -159,6 +773,122 @@ mod tests {
             text_buf.append(&i.to_string());
         }
 
-        assert_eq!(text_buf.get_raw_buffer_capacity(), h);
+        assert_eq!(text_buf.get_raw_buffer_capacity(), 3 * h);
+    }
+
+    fn numbers(count: usize, height: usize) -> TextBuffer {
+        let mut text_buf = TextBuffer::new(10, height);
+        for i in 0..count {
+            text_buf.append(&i.to_string());
+        }
+        return text_buf;
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut text_buf = numbers(20, 5);
+        assert!(text_buf.is_at_bottom());
+        assert_eq!(text_buf.get_newest(), vec!["15", "16", "17", "18", "19"]);
+
+        text_buf.scroll_up(2);
+        assert!(!text_buf.is_at_bottom());
+        assert_eq!(text_buf.get_newest(), vec!["13", "14", "15", "16", "17"]);
+
+        // Past the oldest line.
+        text_buf.scroll_up(100);
+        assert_eq!(text_buf.get_newest(), vec!["0", "1", "2", "3", "4"]);
+
+        text_buf.page_down();
+        assert_eq!(text_buf.get_newest(), vec!["4", "5", "6", "7", "8"]);
+        text_buf.page_up();
+        assert_eq!(text_buf.get_newest()[0], "0");
+
+        text_buf.scroll_down(100);
+        assert!(text_buf.is_at_bottom());
+        assert_eq!(text_buf.get_newest()[4], "19");
+
+        // Fewer lines than rows.
+        let mut text_buf = numbers(3, 5);
+        text_buf.scroll_up(1);
+        assert!(text_buf.is_at_bottom());
+        assert_eq!(text_buf.get_newest(), vec!["0", "1", "2"]);
+    }
+
+    #[test]
+    fn test_scroll_wrapped() {
+        // Three lines of three rows each.
+        let mut text_buf = TextBuffer::new(4, 4);
+        for word in &["a", "b", "c"] {
+            text_buf.append(&format!("{0}1 {0}2 {0}3", word));
+        }
+        assert_eq!(text_buf.get_newest(), vec!["b3", "c1 ", "c2 ", "c3"]);
+        text_buf.scroll_up(2);
+        assert_eq!(text_buf.get_newest(), vec!["b1 ", "b2 ", "b3", "c1 "]);
+        let rows = text_buf.get_visible_rows();
+        assert_eq!(rows[0].0, (1, 0));
+        assert_eq!(rows[3].0, (2, 0));
+
+        text_buf.scroll_down(1);
+        assert_eq!(text_buf.get_newest(), vec!["b2 ", "b3", "c1 ", "c2 "]);
+
+        let between = text_buf.rows_between((0, 2), (1, 1));
+        let texts: Vec<String> = between.iter().map(|(_, r)| r.text()).collect();
+        assert_eq!(texts, vec!["a3", "b1 ", "b2 "]);
+        assert_eq!(text_buf.row_range(), Some(((0, 0), (2, 2))));
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut text_buf = TextBuffer::new(20, 2);
+        text_buf.append("one two three four");
+        text_buf.append("five six");
+        assert_eq!(
+            text_buf.get_newest(),
+            vec!["one two three four", "five six"]
+        );
+
+        text_buf.set_width(10);
+        assert_eq!(text_buf.get_newest(), vec!["three four", "five six"]);
+        text_buf.set_height(4);
+        assert_eq!(
+            text_buf.get_newest(),
+            vec!["one two ", "three four", "five six"]
+        );
+
+        // Scrolled up, the same line stays at the bottom.
+        text_buf.set_height(2);
+        text_buf.scroll_up(1);
+        assert_eq!(text_buf.get_newest(), vec!["one two ", "three four"]);
+        text_buf.set_width(5);
+        assert_eq!(text_buf.get_newest(), vec!["three", "four"]);
+        // Until the viewport would not be full.
+        text_buf.set_width(20);
+        assert_eq!(
+            text_buf.get_newest(),
+            vec!["one two three four", "five six"]
+        );
+        assert!(text_buf.is_at_bottom());
+    }
+
+    #[test]
+    fn test_scroll_new_lines() {
+        let mut text_buf = numbers(20, 5);
+        text_buf.scroll_up(5);
+        text_buf.append("20");
+        text_buf.append("21");
+        // The viewport stays where it was.
+        assert_eq!(text_buf.get_newest(), vec!["10", "11", "12", "13", "14"]);
+        assert_eq!(text_buf.unseen(), 2);
+
+        text_buf.scroll_to_bottom();
+        assert_eq!(text_buf.unseen(), 0);
+        assert_eq!(text_buf.get_newest()[4], "21");
+
+        // Old lines going out of the scrollback move the viewport.
+        let mut text_buf = numbers(20, 5);
+        text_buf.set_scrollback(20);
+        text_buf.scroll_up(100);
+        text_buf.append("20");
+        assert_eq!(text_buf.get_newest(), vec!["1", "2", "3", "4", "5"]);
     }
 }
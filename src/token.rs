@@ -0,0 +1,78 @@
+//! The token that local clients of `kbchatbox daemon` and `kbchatbox irc`
+//! have to show.
+
+use kbchatbox::config;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Returns the default token file, `~/.config/kbchatbox/token`.
+pub fn default_token_file() -> PathBuf {
+    return config::config_dir().join("token");
+}
+
+/// Compares in constant time so the token can't be guessed byte by byte.
+pub fn token_matches(given: &str, token: &str) -> bool {
+    if given.len() != token.len() {
+        return false;
+    }
+    let diff = given
+        .bytes()
+        .zip(token.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    return diff == 0;
+}
+
+/// Reads the token file, creating it with a random token if it doesn't
+/// exist.
+pub fn load_or_create_token(path: &Path) -> Result<String, String> {
+    if let Ok(token) = fs::read_to_string(path) {
+        let token = token.trim().to_string();
+        if token.is_empty() {
+            return Err(format!("Token file {} is empty", path.display()));
+        }
+        return Ok(token);
+    }
+
+    let mut random = [0u8; 32];
+    let read = fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut random));
+    if let Err(err) = read {
+        return Err(format!("Can't generate a token: {}", err));
+    }
+    let token: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+
+    if let Some(dir) = path.parent() {
+        if let Err(err) = fs::create_dir_all(dir) {
+            return Err(format!("Can't create {}: {}", dir.display(), err));
+        }
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options
+        .open(path)
+        .and_then(|mut f| f.write_all(format!("{}\n", token).as_bytes()));
+    if let Err(err) = written {
+        return Err(format!("Can't write {}: {}", path.display(), err));
+    }
+
+    eprintln!("Created a new API token in {}", path.display());
+    return Ok(token);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("abc", "abc"));
+        assert!(!token_matches("abd", "abc"));
+        assert!(!token_matches("ab", "abc"));
+    }
+}